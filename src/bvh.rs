@@ -0,0 +1,512 @@
+use std::ops::Range;
+use crate::math::{Point3, Vec3};
+use crate::ray::{HitRecord, Hittable, HittableList, Ray};
+use crate::kdtree::{AABB, PARALLEL_THRESHOLD};
+
+// Relative costs for the surface area heuristic, a traversal step is
+// a lot cheaper than intersecting a shape
+static K_T: f32 = 1.; // Cost of tree traversal
+static K_I: f32 = 8.; // Cost of intersection
+
+// Number of buckets the centroids get binned into per split
+static NB_BINS: usize = 12;
+
+// Leaves bigger than this are always split, even if SAH disagrees
+static MAX_LEAF_SIZE: usize = 4;
+
+pub struct Bvh {
+    hittables: HittableList,
+    // Shapes with infinite bounds, tested for every ray
+    unbounded: HittableList,
+    tree: BvhTree,
+}
+
+/// The node hierarchy on its own, without the primitives. Those are
+/// kept by the owner in the order returned by `BvhTree::build` and
+/// referenced by index ranges, which lets meshes reuse the tree for
+/// their triangles.
+pub struct BvhTree {
+    nodes: Vec<BvhNode>,
+    depth: usize,
+}
+
+/// Nodes are stored depth first, so the first child of an interior
+/// node always follows it directly in the node array
+pub struct BvhNode {
+    bounds: AABB,
+    kind: BvhNodeKind,
+}
+
+pub enum BvhNodeKind {
+    Leaf {
+        first: usize,
+        count: usize,
+    },
+    Interior {
+        second_child: usize,
+    },
+}
+
+struct BuildShape {
+    index: usize,
+    bounds: AABB,
+    centroid: Point3,
+}
+
+#[derive(Clone)]
+struct Bin {
+    bounds: AABB,
+    count: usize,
+}
+
+impl Bvh {
+    pub fn build(shapes: HittableList) -> Self {
+        let (shapes, unbounded) = shapes.split_unbounded();
+        let bounds: Vec<AABB> =
+            shapes.objects.iter().map(|shape| shape.bound()).collect();
+        let (tree, order) = BvhTree::build(&bounds);
+
+        // Reorder the shapes so that every leaf references a
+        // contiguous range
+        let mut objects: Vec<_> =
+            shapes.objects.into_iter().map(Some).collect();
+        let mut hittables = HittableList::new();
+        for index in order {
+            hittables.add(objects[index].take().unwrap());
+        }
+
+        Self {
+            hittables,
+            unbounded,
+            tree,
+        }
+    }
+}
+
+impl Hittable for Bvh {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord> {
+        // Unbounded shapes first, a hit there makes the interval to
+        // traverse shorter
+        let mut result = self.unbounded.hit(r, t_min, t_max);
+        let closest = result.as_ref().map_or(t_max, |hit| hit.t);
+
+        self.tree.traverse(r, t_min, closest, |range, mut closest| {
+            for object in &self.hittables.objects[range] {
+                if let Some(hit) = object.hit(r, t_min, closest) {
+                    closest = hit.t;
+                    result = Some(hit);
+                }
+            }
+            closest
+        });
+
+        result
+    }
+
+    fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        if self.unbounded.shadow_hit(r, t_min, t_max) {
+            return true;
+        }
+
+        self.tree.any(r, t_min, t_max, |range| {
+            self.hittables.objects[range]
+                .iter()
+                .any(|o| o.shadow_hit(r, t_min, t_max))
+        })
+    }
+
+    fn bound(&self) -> AABB {
+        let mut bound = self.unbounded.bound();
+        bound.merge(&self.tree.bound());
+        bound
+    }
+}
+
+impl BvhTree {
+    /// Builds the tree over the given primitive bounds. Also returns the
+    /// order the primitives have to be stored in, so that every leaf
+    /// covers a contiguous range.
+    pub fn build(bounds: &[AABB]) -> (Self, Vec<usize>) {
+        let mut build_shapes: Vec<BuildShape> = bounds
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| {
+                let centroid = 0.5 * (&bounds.min + &bounds.max);
+                BuildShape {
+                    index,
+                    bounds: bounds.clone(),
+                    centroid,
+                }
+            })
+            .collect();
+
+        let mut nodes = vec![];
+        let depth = if build_shapes.is_empty() {
+            0
+        } else {
+            build_node(&mut build_shapes, 0, &mut nodes)
+        };
+
+        let order = build_shapes.iter().map(|s| s.index).collect();
+        (
+            Self {
+                nodes,
+                depth,
+            },
+            order,
+        )
+    }
+
+    /// Visits the leaves pierced by the ray, near ones first. `visit`
+    /// gets the range of primitives in the leaf and the closest hit
+    /// distance so far, and returns the (possibly) updated distance.
+    pub fn traverse<F>(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        mut visit: F,
+    ) where
+        F: FnMut(Range<usize>, f32) -> f32,
+    {
+        let mut closest = t_max;
+
+        if self.nodes.is_empty()
+            || r.aabb_interval(&self.nodes[0].bounds, t_min, closest)
+                .is_none()
+        {
+            return;
+        }
+
+        // Nodes are pushed together with their entry distance, so
+        // that they can be skipped once we found something closer
+        let mut stack = Vec::with_capacity(self.depth);
+        stack.push((0, t_min));
+
+        while let Some((index, t_enter)) = stack.pop() {
+            if t_enter > closest {
+                continue;
+            }
+
+            match self.nodes[index].kind {
+                BvhNodeKind::Leaf {
+                    first,
+                    count,
+                } => {
+                    closest = visit(first..first + count, closest);
+                }
+                BvhNodeKind::Interior {
+                    second_child,
+                } => {
+                    let first_child = index + 1;
+                    let first = r.aabb_interval(
+                        &self.nodes[first_child].bounds,
+                        t_min,
+                        closest,
+                    );
+                    let second = r.aabb_interval(
+                        &self.nodes[second_child].bounds,
+                        t_min,
+                        closest,
+                    );
+
+                    // Push the far child first, so the near one gets
+                    // visited first
+                    match (first, second) {
+                        (Some((t_first, _)), Some((t_second, _))) => {
+                            if t_first <= t_second {
+                                stack.push((second_child, t_second));
+                                stack.push((first_child, t_first));
+                            } else {
+                                stack.push((first_child, t_first));
+                                stack.push((second_child, t_second));
+                            }
+                        }
+                        (Some((t_first, _)), None) => {
+                            stack.push((first_child, t_first))
+                        }
+                        (None, Some((t_second, _))) => {
+                            stack.push((second_child, t_second))
+                        }
+                        (None, None) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns true as soon as `blocked` does for one of the leaves
+    /// pierced by the ray, in no particular order
+    pub fn any<F>(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        mut blocked: F,
+    ) -> bool
+    where
+        F: FnMut(Range<usize>) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let mut stack = Vec::with_capacity(self.depth);
+        stack.push(0);
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if r.aabb_interval(&node.bounds, t_min, t_max).is_none() {
+                continue;
+            }
+
+            match node.kind {
+                BvhNodeKind::Leaf {
+                    first,
+                    count,
+                } => {
+                    if blocked(first..first + count) {
+                        return true;
+                    }
+                }
+                BvhNodeKind::Interior {
+                    second_child,
+                } => {
+                    stack.push(second_child);
+                    stack.push(index + 1);
+                }
+            }
+        }
+
+        false
+    }
+
+    pub fn bound(&self) -> AABB {
+        self.nodes
+            .first()
+            .map_or(AABB::empty(), |node| node.bounds.clone())
+    }
+}
+
+/// Builds the subtree for `shapes` and returns its depth. `offset` is
+/// the position of the first shape in the final shape order.
+fn build_node(
+    shapes: &mut [BuildShape],
+    offset: usize,
+    nodes: &mut Vec<BvhNode>,
+) -> usize {
+    let mut bounds = AABB::empty();
+    let mut centroid_bounds = AABB::empty();
+    for shape in shapes.iter() {
+        bounds.merge(&shape.bounds);
+        centroid_bounds.min.assign_min(&shape.centroid);
+        centroid_bounds.max.assign_max(&shape.centroid);
+    }
+
+    let count = shapes.len();
+    let leaf = BvhNode {
+        bounds: bounds.clone(),
+        kind: BvhNodeKind::Leaf {
+            first: offset,
+            count,
+        },
+    };
+    if count == 1 {
+        nodes.push(leaf);
+        return 1;
+    }
+
+    let mid = match find_split(shapes, &bounds, &centroid_bounds) {
+        Some((axis, split_bin, cost)) => {
+            if count <= MAX_LEAF_SIZE && cost >= K_I * count as f32 {
+                nodes.push(leaf);
+                return 1;
+            }
+
+            let c_min = component(&centroid_bounds.min, axis);
+            let scale = NB_BINS as f32
+                / (component(&centroid_bounds.max, axis) - c_min);
+            partition(shapes, |s| {
+                bin_index(component(&s.centroid, axis), c_min, scale)
+                    < split_bin
+            })
+        }
+        // All centroids in one spot (or unbounded), SAH can't help us
+        None if count <= MAX_LEAF_SIZE => {
+            nodes.push(leaf);
+            return 1;
+        }
+        None => count / 2,
+    };
+
+    // Binning can put everything on one side when centroids are
+    // numerically very close, fall back to a median split
+    let mid = if mid == 0 || mid == count { count / 2 } else { mid };
+
+    let node_index = nodes.len();
+    nodes.push(BvhNode {
+        bounds,
+        kind: BvhNodeKind::Interior {
+            second_child: 0,
+        },
+    });
+
+    let (left, right) = shapes.split_at_mut(mid);
+    if count < PARALLEL_THRESHOLD {
+        let depth_left = build_node(left, offset, nodes);
+        set_second_child(nodes, node_index);
+        let depth_right = build_node(right, offset + mid, nodes);
+
+        return 1 + depth_left.max(depth_right);
+    }
+
+    // Both halves get their own node list and are appended afterwards,
+    // which gives the same layout as building them one after another
+    let ((left_nodes, depth_left), (right_nodes, depth_right)) =
+        rayon::join(
+            || {
+                let mut subtree = vec![];
+                let depth = build_node(left, offset, &mut subtree);
+                (subtree, depth)
+            },
+            || {
+                let mut subtree = vec![];
+                let depth = build_node(right, offset + mid, &mut subtree);
+                (subtree, depth)
+            },
+        );
+
+    append_subtree(nodes, left_nodes);
+    set_second_child(nodes, node_index);
+    append_subtree(nodes, right_nodes);
+
+    1 + depth_left.max(depth_right)
+}
+
+fn set_second_child(nodes: &mut [BvhNode], node_index: usize) {
+    let second_index = nodes.len();
+    if let BvhNodeKind::Interior {
+        ref mut second_child,
+    } = nodes[node_index].kind
+    {
+        *second_child = second_index;
+    }
+}
+
+/// Appends a separately built subtree, shifting its child indices. The
+/// leaves already index the primitives of the whole tree.
+fn append_subtree(nodes: &mut Vec<BvhNode>, subtree: Vec<BvhNode>) {
+    let offset = nodes.len();
+    nodes.extend(subtree.into_iter().map(|mut node| {
+        if let BvhNodeKind::Interior {
+            ref mut second_child,
+        } = node.kind
+        {
+            *second_child += offset;
+        }
+        node
+    }));
+}
+
+/// Binned SAH, returns the axis, the first bin of the right side and
+/// the estimated cost of the split
+fn find_split(
+    shapes: &[BuildShape],
+    bounds: &AABB,
+    centroid_bounds: &AABB,
+) -> Option<(usize, usize, f32)> {
+    let space_surface = bounds.surface();
+    let mut best_cost = f32::INFINITY;
+    let mut best = None;
+
+    for axis in 0..3 {
+        let c_min = component(&centroid_bounds.min, axis);
+        let c_max = component(&centroid_bounds.max, axis);
+        let extent = c_max - c_min;
+        if !extent.is_finite() || extent <= f32::EPSILON {
+            continue;
+        }
+
+        let mut bins = vec![
+            Bin {
+                bounds: AABB::empty(),
+                count: 0,
+            };
+            NB_BINS
+        ];
+        let scale = NB_BINS as f32 / extent;
+        for shape in shapes {
+            let centroid = component(&shape.centroid, axis);
+            let b = bin_index(centroid, c_min, scale);
+            bins[b].count += 1;
+            bins[b].bounds.merge(&shape.bounds);
+        }
+
+        // Sweep from the right to get the surfaces and counts of all
+        // right sides, then from the left to evaluate each split
+        let mut right_surfaces = vec![0.; NB_BINS];
+        let mut right_counts = vec![0; NB_BINS];
+        let mut right_bounds = AABB::empty();
+        let mut right_count = 0;
+        for i in (1..NB_BINS).rev() {
+            right_bounds.merge(&bins[i].bounds);
+            right_count += bins[i].count;
+            right_surfaces[i] = right_bounds.surface();
+            right_counts[i] = right_count;
+        }
+
+        let mut left_bounds = AABB::empty();
+        let mut left_count = 0;
+        for i in 1..NB_BINS {
+            left_bounds.merge(&bins[i - 1].bounds);
+            left_count += bins[i - 1].count;
+            if left_count == 0 || right_counts[i] == 0 {
+                continue;
+            }
+
+            let cost = K_T
+                + K_I
+                    * (left_count as f32 * left_bounds.surface()
+                        + right_counts[i] as f32 * right_surfaces[i])
+                    / space_surface;
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some((axis, i, cost));
+            }
+        }
+    }
+
+    best
+}
+
+fn bin_index(centroid: f32, c_min: f32, scale: f32) -> usize {
+    (((centroid - c_min) * scale) as usize).min(NB_BINS - 1)
+}
+
+fn component(v: &Vec3, axis: usize) -> f32 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+/// Moves all shapes matching `pred` to the front, returns their count
+fn partition<F>(shapes: &mut [BuildShape], pred: F) -> usize
+where
+    F: Fn(&BuildShape) -> bool,
+{
+    let mut mid = 0;
+    for i in 0..shapes.len() {
+        if pred(&shapes[i]) {
+            shapes.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
@@ -1,13 +1,16 @@
 use serde::Deserialize;
 use crate::utils::helpers::parse_vec3;
 use crate::math::{Point3, Vec3, Mat4};
-use crate::ray::Ray;
+use crate::ray::{Ray, RayDifferentials};
+use crate::light::LUMENS_PER_WATT;
 
 pub struct Camera {
     pub image_width: usize,
     pub image_height: usize,
     pub max_bounces: usize,
     pub position: Point3,
+    // Factor of the radiance reaching the image
+    pub exposure: f32,
     fwidth: f32,
     fheight: f32,
 
@@ -40,12 +43,26 @@ impl Camera {
             max_bounces,
 
             position,
+            exposure: 1.,
             look_at,
             fov,
         }
     }
 
     pub fn get_ray(&self, x: f32, y: f32) -> Ray {
+        let mut ray =
+            Ray::from_values(&self.position, &self.direction(x, y));
+        ray.differentials = Some(RayDifferentials {
+            rx_orig: self.position.clone(),
+            rx_dir: self.direction(x + 1., y),
+            ry_orig: self.position.clone(),
+            ry_dir: self.direction(x, y + 1.),
+        });
+
+        ray
+    }
+
+    fn direction(&self, x: f32, y: f32) -> Vec3 {
         let x_n = (x + 0.5) / self.fwidth;
         let y_n = (y + 0.5) / self.fheight;
 
@@ -53,10 +70,7 @@ impl Camera {
             * &(Vec3::from_values(2. * x_n - 1., 2. * y_n - 1., -1.)
                 * &self.fov);
 
-        Ray::from_values(
-            &self.position,
-            &(plane_point - &self.position).unit_vector(),
-        )
+        (plane_point - &self.position).unit_vector()
     }
 
     pub fn get_aperture_ray(
@@ -100,6 +114,16 @@ impl<'de> Deserialize<'de> for Camera {
             n: usize,
         }
 
+        /// Photographic exposure at ISO 100, for lights in lumens,
+        /// and a compensation in stops
+        #[derive(Deserialize)]
+        struct Exposure {
+            #[serde(rename = "@ev100")]
+            ev100: Option<f32>,
+            #[serde(rename = "@compensation")]
+            compensation: Option<f32>,
+        }
+
         #[derive(Deserialize)]
         struct BaseCamera {
             #[serde(deserialize_with = "parse_vec3")]
@@ -112,6 +136,7 @@ impl<'de> Deserialize<'de> for Camera {
             horizontal_fov: Angle,
             resolution: Resolution,
             max_bounces: MaxBounces,
+            exposure: Option<Exposure>,
         }
 
         let BaseCamera {
@@ -121,9 +146,10 @@ impl<'de> Deserialize<'de> for Camera {
             horizontal_fov,
             resolution,
             max_bounces,
+            exposure,
         } = BaseCamera::deserialize(deserializer)?;
 
-        Ok(Camera::from_values(
+        let mut camera = Camera::from_values(
             position,
             look_at,
             up,
@@ -131,6 +157,19 @@ impl<'de> Deserialize<'de> for Camera {
             resolution.width,
             resolution.height,
             max_bounces.n,
-        ))
+        );
+
+        // A pixel saturates at the luminance 1.2 * 2^ev100, luminance
+        // is radiance in lumens
+        if let Some(exposure) = exposure {
+            if let Some(ev100) = exposure.ev100 {
+                camera.exposure = LUMENS_PER_WATT / (1.2 * ev100.exp2());
+            }
+            if let Some(stops) = exposure.compensation {
+                camera.exposure *= stops.exp2();
+            }
+        }
+
+        Ok(camera)
     }
 }
@@ -0,0 +1,28 @@
+use std::path::Path;
+use std::error::Error;
+use exr::prelude::read_first_rgba_layer_from_file;
+use crate::surface::materials::Texture;
+use crate::math::Color;
+
+/// Loads the first layer of an OpenEXR file with rgb channels, at full
+/// resolution. The values are linear and not clamped, alpha is ignored.
+pub fn load_exr(
+    path: &Path,
+) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+    let image = read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| {
+            let (width, height) = (resolution.width(), resolution.height());
+            (width, vec![Color::new(); width * height])
+        },
+        |(width, pixels), position, (r, g, b, _): (f32, f32, f32, f32)| {
+            pixels[position.y() * *width + position.x()] =
+                Color::from_values(r, g, b);
+        },
+    )?;
+
+    let size = image.layer_data.size;
+    let (_, pixels) = image.layer_data.channel_data.pixels;
+
+    Ok(Texture::new(size.width() as u32, size.height() as u32, pixels))
+}
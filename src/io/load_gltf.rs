@@ -0,0 +1,518 @@
+use std::{
+    path::Path,
+    error::Error,
+    collections::HashMap,
+    sync::Arc,
+    f32::consts::PI,
+};
+use gltf::{
+    buffer,
+    camera::Projection,
+    image::{self, Format},
+    khr_lights_punctual::Kind,
+    material::AlphaMode,
+    mesh::Mode,
+    Node,
+};
+use crate::{
+    camera::Camera,
+    io::{
+        pixels::{decode_pixels, PixelFormat},
+        srgb_to_linear,
+        ColorSpace::{self, Linear, Srgb},
+    },
+    light::{
+        structs::{FallOff, Light, Lights, Parallel, Point, Spot},
+        Attenuation, LUMENS_PER_WATT,
+    },
+    math::{Color, Mat4, Point3, Vec3},
+    ray::HittableList,
+    scene::Scene,
+    surface::{
+        materials::{
+            bind_uv_set, interpolated_texture, Phong, Reflectance,
+            Refraction, Solid, Textured, Transmittance,
+        },
+        ColorLookup, Material, Texture, Transform,
+    },
+    utils::{
+        mtlparser::MeshMaterial,
+        objparser::{Corner, Index, Mesh, MeshData, MeshInstance},
+    },
+};
+
+// glTF only has an aspect ratio, so the size of the image and the
+// bounces are fixed
+const IMAGE_WIDTH: usize = 1024;
+const DEFAULT_ASPECT_RATIO: f32 = 4. / 3.;
+const MAX_BOUNCES: usize = 8;
+
+// Below this roughness the Beckmann exponent goes towards infinity
+const MIN_ROUGHNESS: f32 = 0.05;
+
+/// The data of a glTF file and what we made of the nodes visited so far
+struct GltfFile {
+    buffers: Vec<buffer::Data>,
+    images: Vec<image::Data>,
+    // Meshes are shared by all nodes that reference them
+    meshes: HashMap<usize, Arc<Mesh>>,
+    camera: Option<Camera>,
+    lights: Vec<Light>,
+    world: HittableList,
+}
+
+/// Loads a .gltf or .glb file as a whole scene. Meshes become mesh
+/// instances with metallic-roughness materials mapped to our phong
+/// parameters, punctual lights become point, spot and parallel lights.
+/// The output file is named after the glTF file.
+pub fn load_gltf(
+    filepath: &Path,
+) -> Result<Scene, Box<dyn Error + Send + Sync>> {
+    let (document, buffers, images) = gltf::import(filepath)?;
+    let mut file = GltfFile {
+        buffers,
+        images,
+        meshes: HashMap::new(),
+        camera: None,
+        lights: vec![],
+        world: HittableList::new(),
+    };
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or("glTF file has no scene")?;
+    for node in scene.nodes() {
+        file.add_node(&node, &Mat4::identity(), &Mat4::identity())?;
+    }
+
+    let stem = filepath
+        .file_stem()
+        .ok_or("glTF file without name")?
+        .to_string_lossy();
+
+    Ok(Scene {
+        output_file: format!("{}.png", stem),
+        background_color: Color::new(),
+        environment: None,
+        camera: file.camera.ok_or("glTF scene has no camera")?,
+        lights: Lights {
+            lights: file.lights,
+        },
+        world: file.world,
+    })
+}
+
+/// The image size follows the aspect ratio, the horizontal field of
+/// view is the half angle like in our scene files
+fn convert_camera(
+    projection: &Projection,
+    position: Point3,
+    look_at: Point3,
+    up: Vec3,
+) -> Result<Camera, Box<dyn Error + Send + Sync>> {
+    let perspective = match projection {
+        Projection::Perspective(perspective) => perspective,
+        Projection::Orthographic(_) => {
+            return Err("Orthographic glTF cameras are not supported".into())
+        }
+    };
+
+    let aspect_ratio = perspective
+        .aspect_ratio()
+        .unwrap_or(DEFAULT_ASPECT_RATIO);
+    let height = (IMAGE_WIDTH as f32 / aspect_ratio).round() as usize;
+    let half_fov_x = ((perspective.yfov() / 2.).tan() * aspect_ratio).atan();
+
+    Ok(Camera::from_values(
+        position,
+        look_at,
+        up,
+        half_fov_x.to_degrees(),
+        IMAGE_WIDTH,
+        height,
+        MAX_BOUNCES,
+    ))
+}
+
+impl GltfFile {
+    /// Adds the node and its children, with the transforms of the
+    /// parent nodes in both directions
+    fn add_node(
+        &mut self,
+        node: &Node,
+        parent_to_world: &Mat4,
+        world_to_parent: &Mat4,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (translation, rotation, scale) = node.transform().decomposed();
+        let translation = Vec3::from_values(
+            translation[0],
+            translation[1],
+            translation[2],
+        );
+        let scale = Vec3::from_values(scale[0], scale[1], scale[2]);
+        let rotation = Mat4::rotate_quaternion(rotation);
+
+        let local_to_parent = &(&Mat4::translate(&translation) * &rotation)
+            * &Mat4::scale(&scale);
+        let parent_to_local = &(&Mat4::scale(&(1. / &scale))
+            * &rotation.transpose())
+            * &Mat4::translate(&-&translation);
+        let object_to_world = parent_to_world * &local_to_parent;
+        let world_to_object = &parent_to_local * world_to_parent;
+
+        // Cameras and lights look down their -z axis
+        let position = &object_to_world * &Point3::new();
+        let forward = object_to_world
+            .mul_dir(&Vec3::from_values(0., 0., -1.))
+            .unit_vector();
+
+        if let Some(camera) = node.camera() {
+            if self.camera.is_some() {
+                println!("Ignoring glTF camera {}", camera.index());
+            } else {
+                let up = object_to_world
+                    .mul_dir(&Vec3::from_values(0., 1., 0.));
+                self.camera = Some(convert_camera(
+                    &camera.projection(),
+                    position.clone(),
+                    &position + &forward,
+                    up,
+                )?);
+            }
+        }
+
+        if let Some(light) = node.light() {
+            // Point and spot lights are given in candela, directional
+            // ones in lux, the light models take watts divided by PI
+            let [r, g, b] = light.color();
+            let color = light.intensity() / (LUMENS_PER_WATT * PI)
+                * Color::from_values(r, g, b);
+            self.lights.push(match light.kind() {
+                Kind::Directional => Light::Parallel(Parallel {
+                    color,
+                    direction: forward.clone(),
+                }),
+                Kind::Point => Light::Point(Point {
+                    color,
+                    position: position.clone(),
+                    attenuation: Some(Attenuation::INVERSE_SQUARE),
+                    intensity: None,
+                    ies: None,
+                }),
+                Kind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                } => Light::Spot(Spot {
+                    color,
+                    position: position.clone(),
+                    direction: forward.clone(),
+                    fall_off: FallOff {
+                        alpha1: inner_cone_angle.to_degrees(),
+                        alpha2: outer_cone_angle.to_degrees(),
+                    },
+                    attenuation: Some(Attenuation::INVERSE_SQUARE),
+                    intensity: None,
+                    ies: None,
+                }),
+            });
+        }
+
+        if let Some(mesh) = node.mesh() {
+            let mesh = match self.meshes.get(&mesh.index()) {
+                Some(mesh) => mesh.clone(),
+                None => {
+                    let loaded = Arc::new(self.convert_mesh(&mesh)?);
+                    self.meshes.insert(mesh.index(), loaded.clone());
+                    loaded
+                }
+            };
+            let transform = Transform::from_matrices(
+                object_to_world.clone(),
+                world_to_object.clone(),
+            );
+            let instance = MeshInstance::new(mesh, None, None, Some(transform));
+            if !instance.is_empty() {
+                self.world.add(Box::new(instance));
+            }
+        }
+
+        for child in node.children() {
+            self.add_node(&child, &object_to_world, &world_to_object)?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges the triangle primitives of a mesh into one mesh, with a
+    /// material per primitive
+    fn convert_mesh(
+        &self,
+        mesh: &gltf::Mesh,
+    ) -> Result<Mesh, Box<dyn Error + Send + Sync>> {
+        let mut data = MeshData::default();
+        let mut material_indices = HashMap::new();
+
+        // Only stored if some primitive has them
+        let has_attribute = |semantic: gltf::Semantic| {
+            mesh.primitives().any(|p| p.get(&semantic).is_some())
+        };
+        let mesh_has_colors = has_attribute(gltf::Semantic::Colors(0));
+        let mesh_has_second_texcoords =
+            has_attribute(gltf::Semantic::TexCoords(1));
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                println!(
+                    "Ignoring glTF primitive of mesh {}, only triangles \
+                     are supported",
+                    mesh.index()
+                );
+                continue;
+            }
+
+            let reader = primitive
+                .reader(|buffer| Some(&self.buffers[buffer.index()]));
+            let positions = reader
+                .read_positions()
+                .ok_or("glTF primitive without positions")?;
+
+            // Attributes belong to the vertices, so all of them get the
+            // same index as the vertex
+            let first = data.vertices.len();
+            for p in positions {
+                data.vertices.push(Point3::from_values(p[0], p[1], p[2]));
+            }
+            let count = data.vertices.len() - first;
+
+            // Attributes a primitive doesn't have are padded, so they
+            // stay in line with the vertices
+            let mut normals: Vec<_> = reader.read_normals().map_or(
+                vec![],
+                |n| n.map(|n| Vec3::from_values(n[0], n[1], n[2])).collect(),
+            );
+            let has_normals = normals.len() == count;
+            normals.resize(count, Vec3::new());
+            data.normals.append(&mut normals);
+
+            let read_texcoords = |set| -> Vec<Vec3> {
+                reader.read_tex_coords(set).map_or(vec![], |t| {
+                    t.into_f32()
+                        .map(|t| Vec3::from_values(t[0], t[1], 0.))
+                        .collect()
+                })
+            };
+            let mut texcoords = read_texcoords(0);
+            let has_texcoords = texcoords.len() == count;
+            texcoords.resize(count, Vec3::new());
+            data.texcoords.append(&mut texcoords);
+
+            let mut second_texcoords = read_texcoords(1);
+            let has_second_texcoords = second_texcoords.len() == count;
+            if mesh_has_second_texcoords {
+                second_texcoords.resize(count, Vec3::new());
+                data.second_texcoords.append(&mut second_texcoords);
+            }
+
+            if mesh_has_colors {
+                let mut colors: Vec<_> = reader.read_colors(0).map_or(
+                    vec![],
+                    |c| {
+                        c.into_rgb_f32()
+                            .map(|c| Color::from_values(c[0], c[1], c[2]))
+                            .collect()
+                    },
+                );
+                colors.resize(count, Color::from_values(1., 1., 1.));
+                data.colors.append(&mut colors);
+            }
+
+            // Primitives without a material get the default one, which
+            // has no index
+            let material = primitive.material();
+            let material_index = match material_indices.get(&material.index()) {
+                Some(index) => *index,
+                None => {
+                    let index = data.materials.len() as Index;
+                    data.materials.push(self.convert_material(&material)?);
+                    material_indices.insert(material.index(), index);
+                    index
+                }
+            };
+
+            let indices: Vec<usize> = match reader.read_indices() {
+                Some(indices) => {
+                    indices.into_u32().map(|i| i as usize).collect()
+                }
+                None => (0..count).collect(),
+            };
+            for triangle in indices.chunks_exact(3) {
+                let mut corners = vec![];
+                for &i in triangle {
+                    if i >= count {
+                        return Err("glTF vertex index out of range".into());
+                    }
+                    let i = first + i;
+                    corners.push(Corner {
+                        vertex: i,
+                        texcoord: has_texcoords.then_some(i),
+                        second_texcoord: has_second_texcoords.then_some(i),
+                        normal: has_normals.then_some(i),
+                    });
+                }
+                data.add_polygon(&corners, Some(material_index));
+            }
+        }
+
+        // Missing normals are flat according to the spec
+        Ok(data.into_mesh(0.))
+    }
+
+    /// Maps a metallic-roughness material to the parameters of our
+    /// lighting models. The roughness gives the exponent of the
+    /// Beckmann distribution used by Cook-Torrance, smooth metals
+    /// reflect.
+    fn convert_material(
+        &self,
+        gltf_material: &gltf::Material,
+    ) -> Result<MeshMaterial, Box<dyn Error + Send + Sync>> {
+        let pbr = gltf_material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor();
+        let base_color = Color::from_values(r, g, b);
+        let metallic = pbr.metallic_factor();
+        let roughness = pbr.roughness_factor().max(MIN_ROUGHNESS);
+
+        // glTF squares the roughness to get the alpha of the
+        // distribution, our exponent is 2 / alpha^2 + 2. Our specular
+        // highlights and reflections are white, so metals keep the
+        // base color in the diffuse part.
+        let beckmann_alpha = roughness * roughness;
+        let phong = Phong {
+            ka: 1.,
+            kd: 1.,
+            ks: 1.,
+            exponent: 2. / (beckmann_alpha * beckmann_alpha) + 2.,
+        };
+        let smoothness = 1. - roughness;
+        let reflectance = Reflectance {
+            r: metallic * smoothness * smoothness,
+        };
+        let transmittance = Transmittance {
+            t: match gltf_material.alpha_mode() {
+                AlphaMode::Blend => 1. - alpha,
+                _ => 0.,
+            },
+        };
+        let refraction = Refraction {
+            iof: gltf_material.ior().unwrap_or(1.5),
+        };
+
+        let [r, g, b] = gltf_material.emissive_factor();
+        let emission = Some(Color::from_values(r, g, b))
+            .filter(|e| e.x > 0. || e.y > 0. || e.z > 0.);
+
+        let base_texture = pbr
+            .base_color_texture()
+            .map(|info| {
+                let texture = info.texture();
+                let uv_set = info.tex_coord();
+                self.convert_texture(texture, uv_set, &base_color, Srgb)
+            })
+            .transpose()?;
+        let material: Arc<dyn Material> = match base_texture {
+            Some(texture) => Arc::new(Textured {
+                texture,
+                phong,
+                reflectance,
+                transmittance,
+                refraction,
+                emission,
+            }),
+            None => Arc::new(Solid {
+                color: base_color,
+                phong,
+                reflectance,
+                transmittance,
+                refraction,
+                emission,
+            }),
+        };
+
+        let white = Color::from_values(1., 1., 1.);
+        let normal_map = gltf_material
+            .normal_texture()
+            .map(|info| {
+                let texture = info.texture();
+                self.convert_texture(texture, info.tex_coord(), &white, Linear)
+            })
+            .transpose()?;
+
+        Ok(MeshMaterial {
+            material,
+            normal_map,
+        })
+    }
+
+    /// Looks up the image of a texture, multiplied by `factor`, with
+    /// the set of texture coords the material binds it to
+    fn convert_texture(
+        &self,
+        texture: gltf::Texture,
+        uv_set: u32,
+        factor: &Color,
+        color_space: ColorSpace,
+    ) -> Result<Box<dyn ColorLookup>, Box<dyn Error + Send + Sync>> {
+        let uv_set = if uv_set > 1 {
+            println!(
+                "Ignoring uv set {} of glTF texture {}, only two are \
+                 supported",
+                uv_set,
+                texture.index()
+            );
+            0
+        } else {
+            uv_set as usize
+        };
+
+        let image = self.convert_image(texture, factor, color_space)?;
+        Ok(bind_uv_set(interpolated_texture(image), uv_set))
+    }
+
+    /// Converts the image of a texture to linear values, multiplied by
+    /// `factor`
+    fn convert_image(
+        &self,
+        texture: gltf::Texture,
+        factor: &Color,
+        color_space: ColorSpace,
+    ) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+        let image = &self.images[texture.source().index()];
+
+        // Channels and bytes per channel
+        let (channels, size) = match image.format {
+            Format::R8 => (1, 1),
+            Format::R8G8 => (2, 1),
+            Format::R8G8B8 => (3, 1),
+            Format::R8G8B8A8 => (4, 1),
+            Format::R16 => (1, 2),
+            Format::R16G16 => (2, 2),
+            Format::R16G16B16 => (3, 2),
+            Format::R16G16B16A16 => (4, 2),
+            Format::R32G32B32FLOAT => (3, 4),
+            Format::R32G32B32A32FLOAT => (4, 4),
+        };
+        let format = PixelFormat {
+            channels,
+            size,
+            big_endian: cfg!(target_endian = "big"),
+        };
+        let decode = |value: f32| match color_space {
+            Srgb => srgb_to_linear(value),
+            Linear => value,
+        };
+        let (pixels, _) = decode_pixels(&image.pixels, format, decode);
+        let pixels = pixels.iter().map(|color| color * factor).collect();
+
+        Ok(Texture::new(image.width, image.height, pixels))
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::error::Error;
+use crate::surface::materials::Texture;
+use crate::math::Color;
+
+/// Loads a Radiance .hdr file. The values are linear and can be way
+/// above one, they are not clamped.
+pub fn load_hdr(
+    path: &Path,
+) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err("Not a Radiance hdr file".into());
+    }
+
+    // Header lines up to an empty line, only the format matters to us
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err("Hdr file ends in the header".into());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                let message = format!("Unsupported hdr format {}", format);
+                return Err(message.into());
+            }
+        }
+    }
+
+    // Like "-Y 512 +X 1024", rows go from top to bottom unless it
+    // says +Y
+    line.clear();
+    reader.read_line(&mut line)?;
+    let size: Vec<_> = line.split_whitespace().collect();
+    let (flip, height, width) = match size[..] {
+        [y @ ("-Y" | "+Y"), height, "+X", width] => {
+            (y == "+Y", height.parse::<u32>()?, width.parse::<u32>()?)
+        }
+        _ => {
+            let message = format!("Unsupported hdr size {}", line.trim());
+            return Err(message.into());
+        }
+    };
+
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    let mut data = data.as_slice();
+    // Every row takes at least the four bytes of a pixel or of the
+    // header of an encoded row, so corrupt sizes don't get allocated
+    if height as usize > data.len() / 4 {
+        return Err("Hdr file is truncated".into());
+    }
+    let mut rows = Vec::with_capacity(height as usize);
+    for _ in 0..height {
+        rows.push(read_scanline(&mut data, width as usize)?);
+    }
+    if flip {
+        rows.reverse();
+    }
+
+    let pixels = rows.concat().iter().map(rgbe_to_color).collect();
+
+    Ok(Texture::new(width, height, pixels))
+}
+
+/// Reads one row of rgbe pixels, which is either run length encoded
+/// per channel or stored as is
+fn read_scanline(
+    data: &mut &[u8],
+    width: usize,
+) -> Result<Vec<[u8; 4]>, Box<dyn Error + Send + Sync>> {
+    let truncated = || -> Box<dyn Error + Send + Sync> {
+        "Hdr file is truncated".into()
+    };
+
+    // Encoded rows start with 2 2 and the width, which is never a
+    // valid pixel
+    let is_encoded = (8..0x8000).contains(&width)
+        && data.len() >= 4
+        && data[0] == 2
+        && data[1] == 2
+        && data[2] & 0x80 == 0;
+    if !is_encoded {
+        return read_flat_scanline(data, width);
+    }
+    if ((data[2] as usize) << 8 | data[3] as usize) != width {
+        return Err("Hdr scanline with the wrong width".into());
+    }
+    *data = &data[4..];
+
+    // The channels come one after the other, as runs of one value or
+    // of literal values
+    let mut row = vec![[0u8; 4]; width];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let (&count, rest) = data.split_first().ok_or_else(truncated)?;
+            *data = rest;
+            if count > 128 {
+                let count = (count - 128) as usize;
+                let (&value, rest) =
+                    data.split_first().ok_or_else(truncated)?;
+                *data = rest;
+                if x + count > width {
+                    return Err("Hdr run exceeds the scanline".into());
+                }
+                for pixel in &mut row[x..x + count] {
+                    pixel[channel] = value;
+                }
+                x += count;
+            } else {
+                let count = count as usize;
+                if count == 0 || x + count > width {
+                    return Err("Hdr run exceeds the scanline".into());
+                }
+                if data.len() < count {
+                    return Err(truncated());
+                }
+                let literals = row[x..x + count].iter_mut().zip(*data);
+                for (pixel, &value) in literals {
+                    pixel[channel] = value;
+                }
+                *data = &data[count..];
+                x += count;
+            }
+        }
+    }
+
+    Ok(row)
+}
+
+/// Rows of plain rgbe pixels, where the old run length encoding marks
+/// repetitions of the previous pixel with 1 1 1 and a count
+fn read_flat_scanline(
+    data: &mut &[u8],
+    width: usize,
+) -> Result<Vec<[u8; 4]>, Box<dyn Error + Send + Sync>> {
+    // Runs can make the row longer than the bytes left, the capacity is
+    // only a guess then
+    let mut row: Vec<[u8; 4]> =
+        Vec::with_capacity(width.min(data.len() / 4));
+    let mut shift = 0;
+    while row.len() < width {
+        if data.len() < 4 {
+            return Err("Hdr file is truncated".into());
+        }
+        let pixel = [data[0], data[1], data[2], data[3]];
+        *data = &data[4..];
+
+        match (pixel, row.last()) {
+            ([1, 1, 1, count], Some(&previous)) => {
+                let count = (count as usize)
+                    .checked_shl(shift)
+                    .unwrap_or(usize::MAX);
+                if count > width - row.len() {
+                    return Err("Hdr run exceeds the scanline".into());
+                }
+                row.extend(std::iter::repeat_n(previous, count));
+                shift += 8;
+            }
+            _ => {
+                row.push(pixel);
+                shift = 0;
+            }
+        }
+    }
+
+    Ok(row)
+}
+
+/// The mantissas share the exponent in the fourth byte
+fn rgbe_to_color(rgbe: &[u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::new();
+    }
+    let scale = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    Color::from_values(
+        (rgbe[0] as f32 + 0.5) * scale,
+        (rgbe[1] as f32 + 0.5) * scale,
+        (rgbe[2] as f32 + 0.5) * scale,
+    )
+}
@@ -0,0 +1,87 @@
+use std::path::Path;
+use std::fs;
+use std::error::Error;
+use crate::light::IesProfile;
+
+// Photometric type of the file, we only know type C
+const TYPE_C: f32 = 1.;
+
+/// Loads an IESNA LM-63 photometric file (from the 1986 version up to
+/// 2002). The candela values are scaled by the multiplier and ballast
+/// factor of the file, lamp tilt data is skipped.
+pub fn load_ies(
+    path: &Path,
+) -> Result<IesProfile, Box<dyn Error + Send + Sync>> {
+    let data = fs::read(path)?;
+    let text = String::from_utf8_lossy(&data);
+    let mut lines = text.lines();
+
+    // Keywords like [MANUFAC] up to the TILT line
+    let tilt = loop {
+        let line = lines.next().ok_or("IES file without TILT line")?;
+        if let Some(tilt) = line.trim().strip_prefix("TILT=") {
+            break tilt.trim().to_string();
+        }
+    };
+
+    // The rest are numbers, separated by spaces, commas or new lines
+    let rest: Vec<&str> = lines.collect();
+    let mut numbers = rest
+        .iter()
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|number| !number.is_empty());
+    let mut next = || -> Result<f32, Box<dyn Error + Send + Sync>> {
+        let number = numbers.next().ok_or("IES file ends early")?;
+        Ok(number.parse::<f32>()?)
+    };
+
+    match tilt.as_str() {
+        "NONE" => {}
+        "INCLUDE" => {
+            // Lamp to luminaire geometry, then angles and factors
+            next()?;
+            let count = next()? as usize;
+            for _ in 0..2 * count {
+                next()?;
+            }
+        }
+        file => println!("Ignoring the tilt file {} of {:?}", file, path),
+    }
+
+    let _lamps = next()?;
+    let _lumens_per_lamp = next()?;
+    let multiplier = next()?;
+    let vertical_count = next()? as usize;
+    let horizontal_count = next()? as usize;
+    let photometric_type = next()?;
+    // Units and the size of the luminaire don't matter to us
+    for _ in 0..4 {
+        next()?;
+    }
+    let ballast_factor = next()?;
+    // The ballast lamp factor of LM-63-1991, reserved since then
+    let _future_use = next()?;
+    let _input_watts = next()?;
+
+    if photometric_type != TYPE_C {
+        return Err("Only type C IES photometry is supported".into());
+    }
+
+    let scale = multiplier * ballast_factor;
+    let vertical = (0..vertical_count)
+        .map(|_| next())
+        .collect::<Result<Vec<_>, _>>()?;
+    let horizontal = (0..horizontal_count)
+        .map(|_| next())
+        .collect::<Result<Vec<_>, _>>()?;
+    // The counts aren't checked yet, so nothing is allocated up front
+    let mut candela = vec![];
+    for _ in 0..horizontal_count {
+        let row = (0..vertical_count)
+            .map(|_| next().map(|c| scale * c))
+            .collect::<Result<Vec<_>, _>>()?;
+        candela.push(row);
+    }
+
+    Ok(IesProfile::new(vertical, horizontal, candela)?)
+}
@@ -1,32 +1,85 @@
 use std::path::Path;
 use std::fs::File;
+use serde::Deserialize;
+use png::{BitDepth, ColorType, Transformations};
 use crate::surface::materials::Texture;
-use crate::math::Color;
+use super::pixels::{decode_pixels, PixelFormat};
 use std::error::Error;
 
-pub fn load_texture(
+/// How the values of a texture are encoded. Color textures are usually
+/// stored as sRGB, data like normal maps is linear.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+/// Loads a png of any color type and bit depth. With `ColorSpace::Srgb`
+/// the colors get converted to linear, using the gamma of the file if
+/// it has one and the sRGB curve otherwise. The alpha channel is only
+/// kept if some pixels are transparent.
+pub fn load_png(
     path: &Path,
+    color_space: ColorSpace,
 ) -> Result<Texture, Box<dyn Error + Send + Sync>> {
     let file = File::open(path)?;
-    let decoder = png::Decoder::new(file);
-    let mut reader = decoder.read_info().unwrap();
+    let mut decoder = png::Decoder::new(file);
+
+    // Palettes and transparency chunks become rgb(a), gray images with
+    // less than 8 bits get scaled up
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
     let mut buf = vec![0; reader.output_buffer_size()];
     let info = reader.next_frame(&mut buf)?;
 
-    let width = info.width as u32;
-    let height = info.height as u32;
-    let pixels: Vec<_> = buf
-        .chunks_exact(3)
-        .map(|pixel| Color::from_values(
-            pixel[0] as f32 / 255.,
-            pixel[1] as f32 / 255.,
-            pixel[2] as f32 / 255.
-        ))
-        .collect();
-
-    Ok(Texture {
-        width,
-        height,
-        pixels
-    })
+    let channels = match info.color_type {
+        ColorType::Grayscale => 1,
+        ColorType::GrayscaleAlpha => 2,
+        ColorType::Rgb => 3,
+        ColorType::Rgba => 4,
+        ColorType::Indexed => return Err("Unexpanded png palette".into()),
+    };
+    let size = match info.bit_depth {
+        BitDepth::Eight => 1,
+        BitDepth::Sixteen => 2,
+        _ => return Err("Unexpanded png bit depth".into()),
+    };
+
+    // A gAMA chunk stores the exponent the values were encoded with,
+    // the sRGB chunk overrides it
+    let png_info = reader.info();
+    let gamma = match png_info.source_gamma {
+        Some(gamma) if png_info.srgb.is_none() => Some(gamma.into_value()),
+        _ => None,
+    };
+    let decode = |value: f32| match (color_space, gamma) {
+        (ColorSpace::Linear, _) => value,
+        (ColorSpace::Srgb, Some(gamma)) => value.powf(1. / gamma),
+        (ColorSpace::Srgb, None) => srgb_to_linear(value),
+    };
+
+    let format = PixelFormat {
+        channels,
+        size,
+        big_endian: true,
+    };
+    let data = &buf[..info.buffer_size()];
+    let (pixels, alpha) = decode_pixels(data, format, decode);
+
+    let mut texture = Texture::new(info.width, info.height, pixels);
+    if alpha.iter().any(|&a| a < 1.) {
+        texture.alpha = Some(alpha);
+    }
+
+    Ok(texture)
+}
+
+/// Inverse of the sRGB transfer function, for values in [0, 1]
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
 }
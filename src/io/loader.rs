@@ -2,13 +2,46 @@ use std::error::Error;
 use std::env;
 use std::fs::File;
 use std::io::BufReader;
+use std::path::Path;
 use crate::scene::Scene;
+use crate::surface::materials::Texture;
+use super::load_gltf::load_gltf;
+use super::load_png::{load_png, ColorSpace};
+use super::load_hdr::load_hdr;
+use super::load_exr::load_exr;
 
+/// Loads the scene given as first argument, either one of our xml
+/// files or a glTF file
 pub fn load_scene() -> Result<Scene, Box<dyn Error + Send + Sync>> {
-    let xml_path = env::args()
+    let scene_path = env::args()
         .nth(1)
         .ok_or("No XML file specified")?;
-    let xml_file = File::open(xml_path)?;
+
+    let extension = Path::new(&scene_path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    if let Some("gltf" | "glb") = extension.as_deref() {
+        return load_gltf(Path::new(&scene_path));
+    }
+
+    let xml_file = File::open(scene_path)?;
     let ref mut xml_reader = BufReader::new(xml_file);
     quick_xml::de::from_reader(xml_reader).map_err(|e| e.into())
 }
+
+/// Loads an image texture, picking the decoder by the file extension.
+/// The color space only applies to png files, hdr and exr files are
+/// linear already.
+pub fn load_texture(
+    path: &Path,
+    color_space: ColorSpace,
+) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("hdr") => load_hdr(path),
+        Some("exr") => load_exr(path),
+        _ => load_png(path, color_space),
+    }
+}
@@ -1,7 +1,13 @@
 mod png_writer;
 mod load_png;
+mod pixels;
+mod load_gltf;
+mod load_hdr;
+mod load_exr;
+mod load_ies;
 mod loader;
 
 pub use png_writer::SceneWriter;
-pub use loader::load_scene;
-pub use load_png::load_texture;
+pub use loader::{load_scene, load_texture};
+pub use load_png::{srgb_to_linear, ColorSpace};
+pub use load_ies::load_ies;
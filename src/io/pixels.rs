@@ -0,0 +1,83 @@
+use crate::math::Color;
+
+/// Layout of interleaved pixel data: 1 to 4 channels of `size` bytes,
+/// with gray in the first channel of 1 or 2 channel images and alpha in
+/// the last channel of 2 or 4 channel images. Values of 4 bytes are
+/// floats, others are unsigned integers.
+#[derive(Clone, Copy)]
+pub struct PixelFormat {
+    pub channels: usize,
+    pub size: usize,
+    pub big_endian: bool,
+}
+
+/// Decodes `data` into colors and the alpha of each pixel, 1 for images
+/// without alpha. `decode` converts the color channels of integer
+/// images to linear, floats are linear already.
+pub fn decode_pixels<F>(
+    data: &[u8],
+    format: PixelFormat,
+    decode: F,
+) -> (Vec<Color>, Vec<f32>)
+where
+    F: Fn(f32) -> f32,
+{
+    let PixelFormat {
+        channels,
+        size,
+        big_endian,
+    } = format;
+
+    let value = |bytes: &[u8]| match size {
+        1 => bytes[0] as f32 / 255.,
+        2 => {
+            let bytes = [bytes[0], bytes[1]];
+            let v = if big_endian {
+                u16::from_be_bytes(bytes)
+            } else {
+                u16::from_le_bytes(bytes)
+            };
+            v as f32 / 65535.
+        }
+        _ => {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            if big_endian {
+                f32::from_be_bytes(bytes)
+            } else {
+                f32::from_le_bytes(bytes)
+            }
+        }
+    };
+
+    // Only 8 bit values are common enough to be worth a table
+    let table: Vec<f32> = match size {
+        1 => (0..=255).map(|v| decode(v as f32 / 255.)).collect(),
+        _ => vec![],
+    };
+    let color_value = |bytes: &[u8]| match size {
+        1 => table[bytes[0] as usize],
+        2 => decode(value(bytes)),
+        _ => value(bytes),
+    };
+
+    let nb_pixels = data.len() / (channels * size);
+    let mut pixels = Vec::with_capacity(nb_pixels);
+    let mut alpha = Vec::with_capacity(nb_pixels);
+    for pixel in data.chunks_exact(channels * size) {
+        let mut c = pixel.chunks_exact(size);
+        // Gray images only have one channel for all three
+        let color = if channels < 3 {
+            let v = color_value(c.next().unwrap());
+            Color::from_values(v, v, v)
+        } else {
+            let r = color_value(c.next().unwrap());
+            let g = color_value(c.next().unwrap());
+            let b = color_value(c.next().unwrap());
+            Color::from_values(r, g, b)
+        };
+        pixels.push(color);
+        alpha.push(c.next().map_or(1., value));
+    }
+
+    (pixels, alpha)
+}
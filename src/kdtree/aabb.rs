@@ -26,6 +26,21 @@ impl AABB {
         )
     }
 
+    /// Bounds of shapes without an extent, like infinite planes
+    pub fn infinite() -> Self {
+        let empty = Self::empty();
+        Self::new(empty.max, empty.min)
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.min.x.is_finite()
+            && self.min.y.is_finite()
+            && self.min.z.is_finite()
+            && self.max.x.is_finite()
+            && self.max.y.is_finite()
+            && self.max.z.is_finite()
+    }
+
     pub fn merge(&mut self, other: &Self) {
         self.min = Point3::from_values(
             self.min.x.min(other.min.x),
@@ -0,0 +1,241 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use crate::math::Point3;
+use super::{AABB, Plane, Dimension, KDTreeNode};
+
+static MAGIC: &[u8; 4] = b"KDTC";
+
+// Bump this whenever the file layout or the tree building changes, so
+// that old caches get rebuilt instead of loaded
+static VERSION: u32 = 1;
+
+static TAG_LEAF: u8 = 0;
+static TAG_NODE: u8 = 1;
+
+// Bytes of an empty leaf and of each shape index in a leaf
+static LEAF_SIZE: usize = 5;
+static SHAPE_SIZE: usize = 4;
+
+pub struct CachedTree {
+    pub tree: Vec<KDTreeNode>,
+    pub space: AABB,
+    pub depth: usize,
+}
+
+/// The tree only depends on the bounds of the shapes (and their order),
+/// so hashing them covers both the geometry and the transforms. Uses
+/// FNV-1a, as the std hashers aren't stable between releases.
+pub fn cache_key(bounds: &[AABB]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |value: f32| {
+        for byte in value.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    };
+
+    for bb in bounds {
+        feed(bb.min.x);
+        feed(bb.min.y);
+        feed(bb.min.z);
+        feed(bb.max.x);
+        feed(bb.max.y);
+        feed(bb.max.z);
+    }
+
+    hash
+}
+
+pub fn save(
+    path: &Path,
+    key: u64,
+    nb_shapes: usize,
+    cached: &CachedTree,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut w = BufWriter::new(File::create(path)?);
+
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&key.to_le_bytes())?;
+    w.write_all(&(nb_shapes as u64).to_le_bytes())?;
+    w.write_all(&(cached.depth as u64).to_le_bytes())?;
+    write_point(&mut w, &cached.space.min)?;
+    write_point(&mut w, &cached.space.max)?;
+    w.write_all(&(cached.tree.len() as u64).to_le_bytes())?;
+
+    for node in &cached.tree {
+        match node {
+            KDTreeNode::Leaf {
+                shapes,
+            } => {
+                w.write_all(&[TAG_LEAF])?;
+                w.write_all(&(shapes.len() as u32).to_le_bytes())?;
+                for &shape in shapes {
+                    w.write_all(&(shape as u32).to_le_bytes())?;
+                }
+            }
+            KDTreeNode::Node {
+                l_child,
+                r_child,
+                plane,
+            } => {
+                let dimension = match plane.dimension {
+                    Dimension::X => 0u8,
+                    Dimension::Y => 1u8,
+                    Dimension::Z => 2u8,
+                };
+                w.write_all(&[TAG_NODE, dimension])?;
+                w.write_all(&plane.pos.to_le_bytes())?;
+                w.write_all(&(*l_child as u32).to_le_bytes())?;
+                w.write_all(&(*r_child as u32).to_le_bytes())?;
+            }
+        }
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+/// Loads the tree from `path`. Fails if the file is from another
+/// version, was built for different geometry or is corrupt.
+pub fn load(
+    path: &Path,
+    key: u64,
+    nb_shapes: usize,
+) -> Result<CachedTree, Box<dyn Error + Send + Sync>> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    let mut r = Reader {
+        data: &data,
+    };
+
+    if r.bytes(4)? != MAGIC {
+        return Err("Not a kdtree cache".into());
+    }
+    if r.u32()? != VERSION {
+        return Err("Outdated kdtree cache".into());
+    }
+    if r.u64()? != key || r.u64()? != nb_shapes as u64 {
+        return Err("Kdtree cache was built for a different scene".into());
+    }
+
+    let depth = r.u64()? as usize;
+    let space = AABB::new(r.point()?, r.point()?);
+    let nb_nodes = r.u64()? as usize;
+    // Check the counts against the file before allocating for them, a
+    // node takes at least a tag and a count of shapes
+    if nb_nodes > r.data.len() / LEAF_SIZE {
+        return Err("Truncated kdtree cache".into());
+    }
+    // The depth sizes the traversal stack
+    if depth > nb_nodes {
+        return Err("Corrupt kdtree cache".into());
+    }
+
+    let mut tree = Vec::with_capacity(nb_nodes);
+    for _ in 0..nb_nodes {
+        let tag = r.u8()?;
+        let node = if tag == TAG_LEAF {
+            let count = r.u32()? as usize;
+            if count > r.data.len() / SHAPE_SIZE {
+                return Err("Truncated kdtree cache".into());
+            }
+            let mut shapes = Vec::with_capacity(count);
+            for _ in 0..count {
+                let shape = r.u32()? as usize;
+                if shape >= nb_shapes {
+                    return Err("Corrupt kdtree cache".into());
+                }
+                shapes.push(shape);
+            }
+            KDTreeNode::Leaf {
+                shapes,
+            }
+        } else if tag == TAG_NODE {
+            let dimension = match r.u8()? {
+                0 => Dimension::X,
+                1 => Dimension::Y,
+                2 => Dimension::Z,
+                _ => return Err("Corrupt kdtree cache".into()),
+            };
+            let pos = r.f32()?;
+            let l_child = r.u32()? as usize;
+            let r_child = r.u32()? as usize;
+            // Children always come after their parent, anything else
+            // could make the traversal loop forever
+            let index = tree.len();
+            let valid = |child: usize| child > index && child < nb_nodes;
+            if !valid(l_child) || !valid(r_child) {
+                return Err("Corrupt kdtree cache".into());
+            }
+            KDTreeNode::Node {
+                l_child,
+                r_child,
+                plane: Plane::new(dimension, pos),
+            }
+        } else {
+            return Err("Corrupt kdtree cache".into());
+        };
+        tree.push(node);
+    }
+
+    if tree.is_empty() || !r.data.is_empty() {
+        return Err("Corrupt kdtree cache".into());
+    }
+
+    Ok(CachedTree {
+        tree,
+        space,
+        depth,
+    })
+}
+
+fn write_point<W: Write>(
+    w: &mut W,
+    p: &Point3,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    w.write_all(&p.x.to_le_bytes())?;
+    w.write_all(&p.y.to_le_bytes())?;
+    w.write_all(&p.z.to_le_bytes())?;
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(
+        &mut self,
+        n: usize,
+    ) -> Result<&'a [u8], Box<dyn Error + Send + Sync>> {
+        if self.data.len() < n {
+            return Err("Truncated kdtree cache".into());
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error + Send + Sync>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error + Send + Sync>> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, Box<dyn Error + Send + Sync>> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn point(&mut self) -> Result<Point3, Box<dyn Error + Send + Sync>> {
+        Ok(Point3::from_values(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
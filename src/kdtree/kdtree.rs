@@ -1,6 +1,9 @@
-use crate::ray::{hit_scan, shadow_hit_scan, HitRecord, Hittable, HittableList, Ray};
+use std::path::Path;
+use rayon::prelude::*;
+use crate::ray::{HitRecord, Hittable, HittableList, Ray};
 use super::{AABB, Plane, Dimension};
 use super::candidate::{Candidates, Candidate, Side};
+use super::cache::{self, CachedTree};
 
 // Values taken from the paper "On building fast kd-Trees for Ray
 // Tracing, and on doing that in O(N log N)"
@@ -11,9 +14,16 @@ static K_I: f32 = 20.; // Cost of intersection
 // 0. -> Cutting an empty space is never better than cutting full one
 static EMPTY_CUT_BONUS: f32 = 0.2;
 
+// Subtrees with at least this many shapes are built in parallel, below
+// that the overhead isn't worth it. The bvh uses the same threshold.
+pub static PARALLEL_THRESHOLD: usize = 4096;
+
 pub struct KDTree {
     hittables: HittableList,
+    // Shapes with infinite bounds, tested for every ray
+    unbounded: HittableList,
     tree: Vec<KDTreeNode>,
+    space: AABB,
     depth: usize,
 }
 
@@ -23,29 +33,98 @@ pub enum KDTreeNode {
     },
     Node {
         l_child: usize,
-        l_space: AABB,
         r_child: usize,
-        r_space: AABB,
+        plane: Plane,
     },
 }
 
 impl KDTree {
     pub fn build(shapes: HittableList) -> Self {
-        // Can only build tree if we have hittables
-        assert!(!shapes.objects.is_empty());
-        let nb_shapes = shapes.objects.len();
+        let (shapes, unbounded) = shapes.split_unbounded();
+        let bounds = Self::shape_bounds(&shapes);
+        let CachedTree {
+            tree,
+            space,
+            depth,
+        } = Self::build_nodes(&bounds);
+
+        Self {
+            hittables: shapes,
+            unbounded,
+            tree,
+            space,
+            depth,
+        }
+    }
+
+    /// Like `build`, but tries to load the nodes from the cache file at
+    /// `path` first. Rebuilds and overwrites the cache if it is missing,
+    /// outdated or was built for different geometry.
+    pub fn build_cached(shapes: HittableList, path: &Path) -> Self {
+        let (shapes, unbounded) = shapes.split_unbounded();
+        let bounds = Self::shape_bounds(&shapes);
+
+        // Nothing worth caching without bounded shapes
+        let cached = if bounds.is_empty() {
+            Self::build_nodes(&bounds)
+        } else {
+            Self::load_or_build(&bounds, path)
+        };
+
+        Self {
+            hittables: shapes,
+            unbounded,
+            tree: cached.tree,
+            space: cached.space,
+            depth: cached.depth,
+        }
+    }
+
+    fn load_or_build(bounds: &[AABB], path: &Path) -> CachedTree {
+        let nb_shapes = bounds.len();
+        let key = cache::cache_key(bounds);
+
+        match cache::load(path, key, nb_shapes) {
+            Ok(cached) => {
+                println!("Loaded kdtree from {}.", path.display());
+                cached
+            }
+            Err(e) => {
+                println!("Not using kdtree cache: {}", e);
+                let cached = Self::build_nodes(bounds);
+                if let Err(e) = cache::save(path, key, nb_shapes, &cached) {
+                    println!("Could not write kdtree cache: {}", e);
+                }
+                cached
+            }
+        }
+    }
+
+    fn shape_bounds(shapes: &HittableList) -> Vec<AABB> {
+        shapes.objects.par_iter().map(|shape| shape.bound()).collect()
+    }
+
+    fn build_nodes(bounds: &[AABB]) -> CachedTree {
+        let nb_shapes = bounds.len();
+        if nb_shapes == 0 {
+            return CachedTree {
+                tree: vec![],
+                space: AABB::empty(),
+                depth: 0,
+            };
+        }
 
         let mut space: AABB = Default::default();
         let mut candidates = Candidates::with_capacity(nb_shapes * 6);
 
-        for (index, shape) in shapes.objects.iter().enumerate() {
-            let bb = shape.bound();
-            candidates.extend(Candidate::gen_candidates(index, &bb));
-            space.merge(&bb);
+        for (index, bb) in bounds.iter().enumerate() {
+            candidates.extend(Candidate::gen_candidates(index, bb));
+            space.merge(bb);
         }
 
-        // Sorting, as the order is important for the algorithm
-        candidates.sort();
+        // Sorting, as the order is important for the algorithm. The sort
+        // is stable, so the tree doesn't depend on the thread count.
+        candidates.par_sort();
 
         let mut sides = vec![Side::Both; nb_shapes];
         let mut tree = vec![];
@@ -53,95 +132,139 @@ impl KDTree {
             &space, candidates, nb_shapes, &mut sides, &mut tree,
         );
 
-        Self {
-            hittables: shapes,
+        CachedTree {
             tree,
+            space,
             depth,
         }
     }
-}
 
-impl Hittable for KDTree {
-    fn hit(
-        &self,
-        r: &Ray,
-        t_min: f32,
-        t_max: f32,
-    ) -> Option<HitRecord> {
-        let mut result: Vec<usize> = vec![];
-        let mut stack = vec![0];
+    /// Visits the leaves pierced by the ray front to back, clipping the
+    /// ray interval at every split plane. `visit_leaf` returns the
+    /// closest distance found so far, traversal stops as soon as the
+    /// next cell starts behind it.
+    fn traverse<F>(&self, r: &Ray, t_min: f32, t_max: f32, mut visit_leaf: F)
+    where
+        F: FnMut(&[usize]) -> f32,
+    {
+        if self.tree.is_empty() {
+            return;
+        }
 
-        stack.reserve_exact(self.depth);
-        while !stack.is_empty() {
-            let node = &self.tree[stack.pop().unwrap()];
-            match node {
-                KDTreeNode::Leaf {
-                    shapes,
-                } => result.extend(shapes),
+        let (mut t_near, mut t_far) =
+            match r.aabb_interval(&self.space, t_min, t_max) {
+                None => return,
+                Some(v) => v,
+            };
+
+        let mut closest = t_max;
+        let mut stack = Vec::with_capacity(self.depth);
+        let mut node = 0;
+
+        loop {
+            if closest < t_near {
+                break;
+            }
+
+            match &self.tree[node] {
                 KDTreeNode::Node {
                     l_child,
-                    l_space,
                     r_child,
-                    r_space,
+                    plane,
                 } => {
-                    if r.intersect_aabb(r_space) {
-                        stack.push(*r_child);
+                    let t_plane = plane.intersect(r);
+                    let (near, far) = if plane.is_left(r) {
+                        (*l_child, *r_child)
+                    } else {
+                        (*r_child, *l_child)
+                    };
+
+                    if t_plane.is_nan() || t_plane > t_far || t_plane <= 0.
+                    {
+                        // Interval lies completely in the near cell
+                        node = near;
+                    } else if t_plane < t_near {
+                        // Interval lies completely in the far cell
+                        node = far;
+                    } else {
+                        stack.push((far, t_plane, t_far));
+                        node = near;
+                        t_far = t_plane;
                     }
-                    if r.intersect_aabb(l_space) {
-                        stack.push(*l_child);
+                }
+                KDTreeNode::Leaf {
+                    shapes,
+                } => {
+                    closest = visit_leaf(shapes);
+                    match stack.pop() {
+                        None => break,
+                        Some((next, next_near, next_far)) => {
+                            node = next;
+                            t_near = next_near;
+                            t_far = next_far;
+                        }
                     }
                 }
             }
         }
+    }
+}
 
-        result.sort();
-        result.dedup();
+impl Hittable for KDTree {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord> {
+        // Unbounded shapes first, a hit there makes the interval to
+        // traverse shorter
+        let mut result = self.unbounded.hit(r, t_min, t_max);
+        let mut closest = result.as_ref().map_or(t_max, |hit| hit.t);
+
+        // Shapes can overlap several cells, so a hit in one cell might
+        // lie behind the next one. The closest distance handles that,
+        // traversal only ends once the next cell starts behind it.
+        self.traverse(r, t_min, closest, |shapes| {
+            for &index in shapes {
+                let object = &self.hittables.objects[index];
+                if let Some(hit) = object.hit(r, t_min, closest) {
+                    closest = hit.t;
+                    result = Some(hit);
+                }
+            }
+            closest
+        });
 
-        let objects = result
-            .into_iter()
-            .map(|index| self.hittables.objects[index].as_ref());
-        hit_scan(objects, r, t_min, t_max)
+        result
     }
 
     fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
-        let mut result: Vec<usize> = vec![];
-        let mut stack = vec![0];
-
-        stack.reserve_exact(self.depth);
-        while !stack.is_empty() {
-            let node = &self.tree[stack.pop().unwrap()];
-            match node {
-                KDTreeNode::Leaf {
-                    shapes,
-                } => result.extend(shapes),
-                KDTreeNode::Node {
-                    l_child,
-                    l_space,
-                    r_child,
-                    r_space,
-                } => {
-                    if r.intersect_aabb(r_space) {
-                        stack.push(*r_child);
-                    }
-                    if r.intersect_aabb(l_space) {
-                        stack.push(*l_child);
-                    }
-                }
-            }
+        if self.unbounded.shadow_hit(r, t_min, t_max) {
+            return true;
         }
 
-        result.sort();
-        result.dedup();
+        let mut blocked = false;
 
-        let objects = result
-            .into_iter()
-            .map(|index| self.hittables.objects[index].as_ref());
-        shadow_hit_scan(objects, r, t_min, t_max)
+        self.traverse(r, t_min, t_max, |shapes| {
+            blocked = shapes.iter().any(|&index| {
+                self.hittables.objects[index].shadow_hit(r, t_min, t_max)
+            });
+
+            if blocked {
+                f32::NEG_INFINITY
+            } else {
+                t_max
+            }
+        });
+
+        blocked
     }
 
-    /// Dummy impl, shouldn't be used
     fn bound(&self) -> AABB {
-        Default::default()
+        let mut bound = self.unbounded.bound();
+        bound.merge(&self.space);
+        bound
     }
 }
 
@@ -167,22 +290,69 @@ pub fn build_tree(
         return 1;
     }
 
-    let (left_space, right_space) =
-        split_space(space, &candidates[best_index].plane);
+    let plane = candidates[best_index].plane.clone();
+    let (left_space, right_space) = split_space(space, &plane);
     let (left_candidates, right_candidates) =
         classify(candidates, best_index, sides);
 
     let node_index = tree.len();
     tree.push(KDTreeNode::Node {
         l_child: node_index + 1,
-        l_space: left_space.clone(),
         r_child: 0,
-        r_space: right_space.clone(),
+        plane,
     });
 
-    let depth_left =
-        build_tree(&left_space, left_candidates, n_l, sides, tree);
+    if nb_shapes < PARALLEL_THRESHOLD {
+        let depth_left =
+            build_tree(&left_space, left_candidates, n_l, sides, tree);
+
+        set_right_child(tree, node_index);
 
+        let depth_right =
+            build_tree(&right_space, right_candidates, n_r, sides, tree);
+
+        return 1 + depth_left.max(depth_right);
+    }
+
+    // Both halves get their own node list and are appended afterwards,
+    // which gives the same layout as building them one after another.
+    // Classifying overwrites the sides of all shapes in a node, so the
+    // right half can start from a fresh array.
+    let nb_total = sides.len();
+    let ((left_tree, depth_left), (right_tree, depth_right)) = rayon::join(
+        || {
+            let mut subtree = vec![];
+            let depth = build_tree(
+                &left_space,
+                left_candidates,
+                n_l,
+                sides,
+                &mut subtree,
+            );
+            (subtree, depth)
+        },
+        || {
+            let mut subtree = vec![];
+            let mut sides = vec![Side::Both; nb_total];
+            let depth = build_tree(
+                &right_space,
+                right_candidates,
+                n_r,
+                &mut sides,
+                &mut subtree,
+            );
+            (subtree, depth)
+        },
+    );
+
+    append_subtree(tree, left_tree);
+    set_right_child(tree, node_index);
+    append_subtree(tree, right_tree);
+
+    1 + depth_left.max(depth_right)
+}
+
+fn set_right_child(tree: &mut [KDTreeNode], node_index: usize) {
     let r_child_index = tree.len();
     if let KDTreeNode::Node {
         ref mut r_child,
@@ -191,11 +361,23 @@ pub fn build_tree(
     {
         *r_child = r_child_index;
     }
+}
 
-    let depth_right =
-        build_tree(&right_space, right_candidates, n_r, sides, tree);
-
-    1 + depth_left.max(depth_right)
+/// Appends a separately built subtree, shifting its child indices
+fn append_subtree(tree: &mut Vec<KDTreeNode>, subtree: Vec<KDTreeNode>) {
+    let offset = tree.len();
+    tree.extend(subtree.into_iter().map(|mut node| {
+        if let KDTreeNode::Node {
+            ref mut l_child,
+            ref mut r_child,
+            ..
+        } = node
+        {
+            *l_child += offset;
+            *r_child += offset;
+        }
+        node
+    }));
 }
 
 fn split_space(space: &AABB, plane: &Plane) -> (AABB, AABB) {
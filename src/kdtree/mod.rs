@@ -2,6 +2,7 @@ mod aabb;
 mod kdtree;
 mod plane;
 mod candidate;
+mod cache;
 
 pub use aabb::AABB;
 pub use plane::{Plane, Dimension};
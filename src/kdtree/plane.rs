@@ -1,4 +1,5 @@
 use super::AABB;
+use crate::ray::Ray;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Dimension {
@@ -40,4 +41,24 @@ impl Plane {
                 self.pos > space.min.z && self.pos < space.max.z
         }
     }
+    /// Distance along the ray to the plane, NaN if the ray lies in it
+    pub fn intersect(&self, r: &Ray) -> f32 {
+        match self.dimension {
+            Dimension::X => (self.pos - r.orig.x) / r.dir.x,
+            Dimension::Y => (self.pos - r.orig.y) / r.dir.y,
+            Dimension::Z => (self.pos - r.orig.z) / r.dir.z,
+        }
+    }
+
+    /// Whether the ray starts on the left side of the plane, rays
+    /// starting on the plane count by their direction
+    pub fn is_left(&self, r: &Ray) -> bool {
+        let (orig, dir) = match self.dimension {
+            Dimension::X => (r.orig.x, r.dir.x),
+            Dimension::Y => (r.orig.y, r.dir.y),
+            Dimension::Z => (r.orig.z, r.dir.z),
+        };
+
+        orig < self.pos || (orig == self.pos && dir <= 0.)
+    }
 }
@@ -0,0 +1,70 @@
+use crate::math::Color;
+use std::f32::consts::PI;
+
+/// Luminous efficacy of light at 555 nm, the peak of human vision, in
+/// lumens per watt
+pub const LUMENS_PER_WATT: f32 = 683.;
+
+/// How the light of point and spot lights fades over the distance `d`:
+/// by 1 / (constant + linear * d + quadratic * d²)
+#[derive(Clone)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+
+impl Attenuation {
+    /// What lights without an attenuation do, the color everywhere
+    pub const NONE: Attenuation = Attenuation {
+        constant: 1.,
+        linear: 0.,
+        quadratic: 0.,
+    };
+
+    /// Physically correct falloff
+    pub const INVERSE_SQUARE: Attenuation = Attenuation {
+        constant: 0.,
+        linear: 0.,
+        quadratic: 1.,
+    };
+
+    pub fn factor(&self, distance: f32) -> f32 {
+        let d = distance.max(f32::EPSILON);
+        1. / (self.constant + d * (self.linear + d * self.quadratic))
+    }
+}
+
+/// The color and attenuation of a light sending `watts` evenly into
+/// `solid_angle`, with `color` only tinting it. The light models take
+/// the irradiance divided by PI as color. Lights given in watts fall
+/// off physically unless told otherwise.
+pub fn radiometric(
+    color: &Color,
+    watts: Option<f32>,
+    solid_angle: f32,
+    attenuation: &Option<Attenuation>,
+) -> (Color, Attenuation) {
+    match watts {
+        None => (
+            color.clone(),
+            attenuation.clone().unwrap_or(Attenuation::NONE),
+        ),
+        Some(watts) => (
+            watts / (solid_angle * PI) * color,
+            attenuation.clone().unwrap_or(Attenuation::INVERSE_SQUARE),
+        ),
+    }
+}
+
+/// Like radiometric, for lights whose profile gives the intensity in
+/// watts per steradian
+pub fn profiled(
+    color: &Color,
+    attenuation: &Option<Attenuation>,
+) -> (Color, Attenuation) {
+    (
+        color / PI,
+        attenuation.clone().unwrap_or(Attenuation::INVERSE_SQUARE),
+    )
+}
@@ -0,0 +1,100 @@
+use super::light::*;
+use crate::math::{Color, Point3, Vec3};
+use crate::ray::{Ray, HitRecord, Hittable};
+use rand::{distributions::Uniform, prelude::Distribution, Rng};
+use std::f32::consts::{PI, TAU};
+
+/// A flat round lamp glowing with radiance `color` on the side its
+/// normal faces. Points are picked uniformly by area.
+pub struct Disk {
+    pub color: Color,
+    pub position: Point3,
+    pub normal: Vec3,
+    pub radius: f32,
+    pub num_samples: usize,
+}
+
+impl Disk {
+    fn sample_from<R: Rng>(
+        &self,
+        p: &Point3,
+        rng: &mut R,
+    ) -> Option<LightSample> {
+        let frand = Uniform::new(0.0, 1.0);
+        let u1: f32 = frand.sample(rng);
+        let r = self.radius * u1.sqrt();
+        let phi = TAU * frand.sample(rng);
+        let position = &self.position
+            + to_world(&self.normal, r * phi.cos(), r * phi.sin(), 0.);
+
+        let l_not_norm = &position - p;
+        let distance = l_not_norm.length();
+        let direction = l_not_norm / distance;
+        let pdf = self.area_pdf(distance, -self.normal.dot(&direction))?;
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.color.clone(),
+            pdf,
+            is_delta: false,
+        })
+    }
+
+    /// Solid angle pdf of a point at `distance`, None for the dark side
+    fn area_pdf(&self, distance: f32, cos_l: f32) -> Option<f32> {
+        if cos_l <= f32::EPSILON || distance <= 0. {
+            return None;
+        }
+        let area = PI * self.radius * self.radius;
+        Some(distance * distance / (cos_l * area))
+    }
+}
+
+impl<M, H, R> LightSource<M, H, R> for Disk
+where
+    M: LightModel,
+    H: Hittable,
+    R: Rng,
+{
+    fn intensity(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        hittables: &H,
+        rng: &mut R,
+    ) -> Color {
+        sampled_intensity::<M, H, R, _>(
+            r,
+            hit_record,
+            hittables,
+            rng,
+            self.num_samples,
+            |p, rng| self.sample_from(p, rng),
+        )
+    }
+
+    fn sample(&self, p: &Point3, rng: &mut R) -> Option<LightSample> {
+        self.sample_from(p, rng)
+    }
+
+    fn emitted(&self, r: &Ray, t_max: f32) -> Option<(Color, f32)> {
+        let ndir = self.normal.dot(&r.dir);
+        if ndir >= -f32::EPSILON {
+            return None;
+        }
+
+        let t = self.normal.dot(&(&self.position - &r.orig)) / ndir;
+        if t <= 0. || t >= t_max {
+            return None;
+        }
+        let d = r.at(t) - &self.position;
+        if d.length_squared() > self.radius * self.radius {
+            return None;
+        }
+
+        let len = r.dir.length();
+        let pdf = self.area_pdf(t * len, -ndir / len)?;
+        Some((self.color.clone(), pdf))
+    }
+}
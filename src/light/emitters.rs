@@ -0,0 +1,308 @@
+use std::sync::Arc;
+use rand::{distributions::Uniform, prelude::Distribution, Rng};
+use std::f32::consts::{PI, TAU};
+use crate::{
+    math::{Color, Point3, Vec3},
+    ray::{Hittable, HitRecord, Ray},
+    surface::{Material, Transform},
+};
+use super::light::{
+    sampled_intensity, LightModel, LightSample, LightSource,
+};
+
+// Points the Whitted integrator samples per hit
+const NUM_SAMPLES: usize = 16;
+
+// Sampled points lie on surfaces of the scene, so shadow rays stop
+// this fraction of the distance short of them
+const SHADOW_SHRINK: f32 = 1e-3;
+
+// Steps in latitude for integrating the area of transformed spheres,
+// and how many points sampling them may reject in a row
+const AREA_STEPS: usize = 64;
+const MAX_REJECTIONS: usize = 32;
+
+struct EmissiveTriangle {
+    vertices: [Point3; 3],
+    emission: Color,
+}
+
+impl EmissiveTriangle {
+    fn area(&self) -> f32 {
+        let [v0, v1, v2] = &self.vertices;
+        0.5 * (v1 - v0).cross(&(v2 - v0)).length()
+    }
+
+    /// A uniform point and the normal there
+    fn sample(&self, u1: f32, u2: f32) -> (Point3, Vec3) {
+        let [v0, v1, v2] = &self.vertices;
+        let su = u1.sqrt();
+        let (b0, b1) = (1. - su, u2 * su);
+        let p = b0 * v0 + b1 * v1 + (1. - b0 - b1) * v2;
+        (p, (v1 - v0).cross(&(v2 - v0)).unit_vector())
+    }
+}
+
+/// A sphere surface with the transform it has in the scene. Uneven
+/// scaling stretches some parts of the surface more than others, so
+/// sampling rejects points by their stretch to stay uniform by area.
+pub struct EmissiveSphere {
+    center: Point3,
+    radius: f32,
+    transform: Option<Transform>,
+    emission: Color,
+    // Upper bound of the stretch and the area in world space
+    max_stretch: f32,
+    area: f32,
+}
+
+impl EmissiveSphere {
+    pub fn new(
+        center: Point3,
+        radius: f32,
+        transform: Option<Transform>,
+        emission: Color,
+    ) -> Self {
+        let mut sphere = EmissiveSphere {
+            center,
+            radius,
+            transform,
+            emission,
+            max_stretch: 1.,
+            area: 4. * PI * radius * radius,
+        };
+
+        // The stretch of a normal is at most the Frobenius norm of the
+        // normal matrix, the area is the mean stretch over the sphere
+        if let Some(t) = &sphere.transform {
+            let e = &t.normal_matrix.e;
+            let frobenius = [0, 1, 2, 4, 5, 6, 8, 9, 10]
+                .iter()
+                .map(|&i| e[i] * e[i])
+                .sum::<f32>()
+                .sqrt();
+            sphere.max_stretch = determinant(t) * frobenius;
+
+            let mut stretch = 0.;
+            for i in 0..AREA_STEPS {
+                for j in 0..2 * AREA_STEPS {
+                    let z = (i as f32 + 0.5) / AREA_STEPS as f32;
+                    let phi = (j as f32 + 0.5) / (2 * AREA_STEPS) as f32;
+                    stretch += sphere.stretch(&sphere_normal(z, phi));
+                }
+            }
+            sphere.area *= stretch / (2 * AREA_STEPS * AREA_STEPS) as f32;
+        }
+
+        sphere
+    }
+
+    /// How much the transform scales the area around the object space
+    /// normal `n`
+    fn stretch(&self, n: &Vec3) -> f32 {
+        self.transform.as_ref().map_or(1., |t| {
+            determinant(t) * t.normal_matrix.mul_dir(n).length()
+        })
+    }
+
+    /// A uniform point and the normal there, both in world space
+    fn sample<R: Rng>(&self, rng: &mut R) -> (Point3, Vec3) {
+        let frand = Uniform::new(0.0, 1.0);
+        let mut n = sphere_normal(frand.sample(rng), frand.sample(rng));
+        if self.transform.is_some() {
+            for _ in 0..MAX_REJECTIONS {
+                if frand.sample(rng) * self.max_stretch <= self.stretch(&n) {
+                    break;
+                }
+                n = sphere_normal(frand.sample(rng), frand.sample(rng));
+            }
+        }
+
+        let p = &self.center + self.radius * &n;
+        match &self.transform {
+            None => (p, n),
+            Some(t) => (
+                &t.object_to_world * &p,
+                t.normal_matrix.mul_dir(&n).unit_vector(),
+            ),
+        }
+    }
+}
+
+/// Absolute determinant of the linear part of the transform
+fn determinant(transform: &Transform) -> f32 {
+    let e = &transform.object_to_world.e;
+    (e[0] * (e[5] * e[10] - e[6] * e[9])
+        - e[1] * (e[4] * e[10] - e[6] * e[8])
+        + e[2] * (e[4] * e[9] - e[5] * e[8]))
+        .abs()
+}
+
+/// Point on the unit sphere, uniform for uniform `u1` and `u2`
+pub(super) fn sphere_normal(u1: f32, u2: f32) -> Vec3 {
+    let z = 1. - 2. * u1;
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = TAU * u2;
+    Vec3::from_values(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// The glowing spheres and mesh triangles of the scene as one light.
+/// Points are picked uniformly by area, so their pdf is the same
+/// everywhere on the light. Both sides glow.
+pub struct Emitters {
+    triangles: Vec<EmissiveTriangle>,
+    spheres: Vec<EmissiveSphere>,
+    // Hits with these materials are on the light
+    materials: Vec<Arc<dyn Material>>,
+    // Over the triangles, then the spheres
+    cdf: Vec<f32>,
+    area: f32,
+}
+
+impl Emitters {
+    /// Collects the glowing parts of the surfaces, None if there are
+    /// none
+    pub fn from_surfaces(surfaces: &[Box<dyn Hittable>]) -> Option<Self> {
+        let mut emitters = Emitters {
+            triangles: vec![],
+            spheres: vec![],
+            materials: vec![],
+            cdf: vec![],
+            area: 0.,
+        };
+        for surface in surfaces {
+            surface.collect_emitters(&mut emitters);
+        }
+
+        let areas = emitters
+            .triangles
+            .iter()
+            .map(EmissiveTriangle::area)
+            .chain(emitters.spheres.iter().map(|s| s.area));
+        let mut area = 0.;
+        for a in areas {
+            area += a;
+            emitters.cdf.push(area);
+        }
+        if area <= 0. {
+            return None;
+        }
+        emitters.cdf.iter_mut().for_each(|c| *c /= area);
+        emitters.area = area;
+
+        Some(emitters)
+    }
+
+    /// Adds a triangle in world space
+    pub fn add_triangle(
+        &mut self,
+        vertices: [Point3; 3],
+        emission: &Color,
+        material: &Arc<dyn Material>,
+    ) {
+        self.add_material(material);
+        self.triangles.push(EmissiveTriangle {
+            vertices,
+            emission: emission.clone(),
+        });
+    }
+
+    pub fn add_sphere(
+        &mut self,
+        sphere: EmissiveSphere,
+        material: &Arc<dyn Material>,
+    ) {
+        self.add_material(material);
+        self.spheres.push(sphere);
+    }
+
+    fn add_material(&mut self, material: &Arc<dyn Material>) {
+        if !self.materials.iter().any(|m| Arc::ptr_eq(m, material)) {
+            self.materials.push(material.clone());
+        }
+    }
+
+    /// A point picked by area as seen from `p`, with its solid angle
+    /// pdf
+    fn sample_from<R: Rng>(
+        &self,
+        p: &Point3,
+        rng: &mut R,
+    ) -> Option<LightSample> {
+        let frand = Uniform::new(0.0, 1.0);
+        let u = frand.sample(rng);
+        let i = self.cdf.partition_point(|&c| c <= u);
+        let (position, normal, emission) = match self.triangles.get(i) {
+            Some(triangle) => {
+                let (u1, u2) = (frand.sample(rng), frand.sample(rng));
+                let (position, normal) = triangle.sample(u1, u2);
+                (position, normal, &triangle.emission)
+            }
+            None => {
+                let index = (i - self.triangles.len())
+                    .min(self.spheres.len() - 1);
+                let sphere = &self.spheres[index];
+                let (position, normal) = sphere.sample(rng);
+                (position, normal, &sphere.emission)
+            }
+        };
+
+        let l_not_norm = &position - p;
+        let distance = l_not_norm.length();
+        let direction = l_not_norm / distance;
+        let cos_l = normal.dot(&direction).abs();
+        if cos_l <= f32::EPSILON || distance <= 0. {
+            return None;
+        }
+
+        let pdf = distance * distance / (cos_l * self.area);
+        Some(LightSample {
+            direction,
+            distance: distance * (1. - SHADOW_SHRINK),
+            radiance: emission.clone(),
+            pdf,
+            is_delta: false,
+        })
+    }
+}
+
+impl<M, H, R> LightSource<M, H, R> for Emitters
+where
+    M: LightModel,
+    H: Hittable,
+    R: Rng,
+{
+    fn intensity(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        hittables: &H,
+        rng: &mut R,
+    ) -> Color {
+        sampled_intensity::<M, H, R, _>(
+            r,
+            hit_record,
+            hittables,
+            rng,
+            NUM_SAMPLES,
+            |p, rng| self.sample_from(p, rng),
+        )
+    }
+
+    fn sample(&self, p: &Point3, rng: &mut R) -> Option<LightSample> {
+        self.sample_from(p, rng)
+    }
+
+    fn surface_pdf(&self, r: &Ray, hit: &HitRecord) -> Option<f32> {
+        if !self.materials.iter().any(|m| Arc::ptr_eq(m, &hit.material)) {
+            return None;
+        }
+
+        let distance = hit.t * r.dir.length();
+        let cos_l = hit.normal.dot(&r.dir).abs() / r.dir.length();
+        if cos_l <= f32::EPSILON {
+            return None;
+        }
+        Some(distance * distance / (cos_l * self.area))
+    }
+}
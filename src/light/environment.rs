@@ -0,0 +1,369 @@
+use std::{error::Error, path::PathBuf, sync::Arc};
+use serde::{Deserialize, Deserializer, de};
+use rand::{distributions::Uniform, prelude::Distribution, Rng};
+use std::f32::consts::{PI, TAU};
+use crate::{
+    io::{load_texture, ColorSpace},
+    math::{Color, Point3, Vec3},
+    ray::{Hittable, HitRecord, Ray},
+    surface::{
+        materials::Texture,
+        mipmap::{bilinear, bilinear_clamped},
+    },
+};
+use super::light::{
+    sampled_intensity, LightModel, LightSample, LightSource,
+};
+
+// Directions the Whitted integrator samples per hit, unless the scene
+// says otherwise
+const DEFAULT_NUM_SAMPLES: usize = 16;
+
+/// Light from infinitely far away in every direction, like the sky.
+/// Stored as an equirectangular image, cube maps get resampled into one
+/// when they are loaded. Directions are looked up like the texture
+/// coords of spheres, the top row is straight up.
+pub struct Environment {
+    width: usize,
+    height: usize,
+    // Already scaled by the intensity
+    pixels: Vec<Color>,
+    // Around the y axis, in radians
+    rotation: f32,
+    num_samples: usize,
+    // Importance sampling by luminance: a cdf over the rows and one
+    // over the pixels of each row, all rows after each other
+    row_cdf: Vec<f32>,
+    pixel_cdf: Vec<f32>,
+}
+
+impl Environment {
+    fn new(
+        texture: Texture,
+        intensity: f32,
+        rotation: f32,
+        num_samples: usize,
+    ) -> Self {
+        let width = texture.width as usize;
+        let height = texture.height as usize;
+        let pixels: Vec<Color> = texture
+            .pixels
+            .into_iter()
+            .map(|c| c * intensity)
+            .collect();
+
+        // Rows near the poles cover less of the sphere
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut pixel_cdf = Vec::with_capacity(width * height);
+        let mut total = 0.;
+        for (y, row) in pixels.chunks_exact(width).enumerate() {
+            let latitude = (0.5 - (y as f32 + 0.5) / height as f32) * PI;
+            let mut sum = 0.;
+            let start = pixel_cdf.len();
+            for pixel in row {
+                sum += luminance(pixel) * latitude.cos();
+                pixel_cdf.push(sum);
+            }
+            for (x, value) in pixel_cdf[start..].iter_mut().enumerate() {
+                *value = if sum > 0. {
+                    *value / sum
+                } else {
+                    (x + 1) as f32 / width as f32
+                };
+            }
+            total += sum;
+            row_cdf.push(total);
+        }
+        for value in &mut row_cdf {
+            *value = if total > 0. { *value / total } else { 0. };
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+            rotation,
+            num_samples,
+            row_cdf,
+            pixel_cdf,
+        }
+    }
+
+    /// The radiance seen in direction `dir`, interpolated between the
+    /// pixels, for rays that miss everything
+    pub fn radiance(&self, dir: &Vec3) -> Color {
+        let (u, v) = self.uv(dir);
+        bilinear(self.width as u32, self.height as u32, &self.pixels, u, v)
+    }
+
+    fn uv(&self, dir: &Vec3) -> (f32, f32) {
+        let d = dir.unit_vector();
+        let (sin, cos) = self.rotation.sin_cos();
+        let x = d.x * cos - d.z * sin;
+        let z = d.x * sin + d.z * cos;
+        let u = 0.5 + x.atan2(z) / TAU;
+        let v = 0.5 - d.y.clamp(-1., 1.).asin() / PI;
+        (u, v)
+    }
+
+    fn direction(&self, u: f32, v: f32) -> Vec3 {
+        let latitude = (0.5 - v) * PI;
+        let longitude = (u - 0.5) * TAU + self.rotation;
+        Vec3::from_values(
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+            latitude.cos() * longitude.cos(),
+        )
+    }
+
+    /// The pixel at image coords (u, v)
+    fn pixel(&self, u: f32, v: f32) -> (usize, usize) {
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        (x, y)
+    }
+
+    /// Solid angle pdf of sampling the direction at image coords
+    /// (u, v), the pixels are sampled proportional to their luminance
+    /// and size
+    fn pdf(&self, u: f32, v: f32) -> f32 {
+        let (x, y) = self.pixel(u, v);
+        let row = &self.pixel_cdf[y * self.width..(y + 1) * self.width];
+        let previous = |cdf: &[f32], i: usize| match i {
+            0 => 0.,
+            _ => cdf[i - 1],
+        };
+        let p_row = self.row_cdf[y] - previous(&self.row_cdf, y);
+        let p_pixel = row[x] - previous(row, x);
+
+        // Each pixel covers the same area of the image
+        let latitude = (0.5 - v) * PI;
+        let pixels = (self.width * self.height) as f32;
+        p_row * p_pixel * pixels / (2. * PI * PI * latitude.cos())
+    }
+
+    /// Picks a direction by the luminance of the pixels
+    fn sample_direction<R: Rng>(&self, rng: &mut R) -> Option<LightSample> {
+        // The cdf is all zeros for black images
+        if self.row_cdf.last().copied().unwrap_or(0.) <= 0. {
+            return None;
+        }
+
+        let frand = Uniform::new(0.0, 1.0);
+        let (u1, u2) = (frand.sample(rng), frand.sample(rng));
+        let y = self
+            .row_cdf
+            .partition_point(|&c| c <= u1)
+            .min(self.height - 1);
+        let row = &self.pixel_cdf[y * self.width..(y + 1) * self.width];
+        let x = row.partition_point(|&c| c <= u2).min(self.width - 1);
+
+        // Anywhere in the pixel
+        let u = (x as f32 + frand.sample(rng)) / self.width as f32;
+        let v = (y as f32 + frand.sample(rng)) / self.height as f32;
+        let pdf = self.pdf(u, v);
+        if pdf <= 0. || !pdf.is_finite() {
+            return None;
+        }
+
+        let (x, y) = self.pixel(u, v);
+        Some(LightSample {
+            direction: self.direction(u, v),
+            distance: f32::INFINITY,
+            radiance: self.pixels[y * self.width + x].clone(),
+            pdf,
+            is_delta: false,
+        })
+    }
+}
+
+impl<M, H, R> LightSource<M, H, R> for Environment
+where
+    M: LightModel,
+    H: Hittable,
+    R: Rng,
+{
+    fn intensity(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        hittables: &H,
+        rng: &mut R,
+    ) -> Color {
+        // Directions below the surface can't light it
+        let n = &hit_record.normal;
+        sampled_intensity::<M, H, R, _>(
+            r,
+            hit_record,
+            hittables,
+            rng,
+            self.num_samples,
+            |_, rng| {
+                self.sample_direction(rng)
+                    .filter(|sample| sample.direction.dot(n) > 0.)
+            },
+        )
+    }
+
+    fn sample(&self, _p: &Point3, rng: &mut R) -> Option<LightSample> {
+        self.sample_direction(rng)
+    }
+
+    fn emitted(&self, r: &Ray, t_max: f32) -> Option<(Color, f32)> {
+        // Only rays that escape the scene see the environment
+        if t_max.is_finite() {
+            return None;
+        }
+
+        let (u, v) = self.uv(&r.dir);
+        let (x, y) = self.pixel(u, v);
+        let radiance = self.pixels[y * self.width + x].clone();
+        Some((radiance, self.pdf(u, v)))
+    }
+}
+
+fn luminance(c: &Color) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/// Images are in the scenes folder, pngs are decoded from sRGB
+fn load_image(name: &str) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+    let mut path = PathBuf::new();
+    path.push(r"../scenes");
+    path.push(name);
+    load_texture(&path, ColorSpace::Srgb)
+}
+
+fn load_cube_map(
+    names: [&str; 6],
+) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+    let [px, nx, py, ny, pz, nz] = names.map(load_image);
+    cube_map_to_equirect([px?, nx?, py?, ny?, pz?, nz?])
+}
+
+/// Resamples the six faces of a cube map into an equirectangular image
+/// with four times the width of a face. The faces are laid out like
+/// OpenGL cube maps, looking from the inside.
+fn cube_map_to_equirect(
+    faces: [Texture; 6],
+) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+    let size = faces[0].width;
+    if faces.iter().any(|f| f.width != size || f.height != size) {
+        return Err("Cube map faces must be squares of one size".into());
+    }
+
+    let width = 4 * size;
+    let height = 2 * size;
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        let latitude = (0.5 - (y as f32 + 0.5) / height as f32) * PI;
+        for x in 0..width {
+            let longitude = ((x as f32 + 0.5) / width as f32 - 0.5) * TAU;
+            let d = Vec3::from_values(
+                latitude.cos() * longitude.sin(),
+                latitude.sin(),
+                latitude.cos() * longitude.cos(),
+            );
+
+            // Face and the coords on it, with t going down
+            let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+            let (face, major, s, t) = match () {
+                _ if ax >= ay && ax >= az => {
+                    if d.x > 0. {
+                        (0, ax, -d.z, -d.y)
+                    } else {
+                        (1, ax, d.z, -d.y)
+                    }
+                }
+                _ if ay >= az => {
+                    if d.y > 0. {
+                        (2, ay, d.x, d.z)
+                    } else {
+                        (3, ay, d.x, -d.z)
+                    }
+                }
+                _ => {
+                    if d.z > 0. {
+                        (4, az, d.x, -d.y)
+                    } else {
+                        (5, az, -d.x, -d.y)
+                    }
+                }
+            };
+
+            let s = (s / major + 1.) * 0.5;
+            let t = (t / major + 1.) * 0.5;
+            let face = &faces[face];
+            pixels.push(bilinear_clamped(size, size, &face.pixels, s, t));
+        }
+    }
+
+    Ok(Texture::new(width, height, pixels))
+}
+
+pub fn parse_environment<'de, D>(
+    deserializer: D,
+) -> Result<Option<Arc<Environment>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // Either an equirectangular image or the six faces of a cube map,
+    // named after the axis they look at
+    #[derive(Deserialize)]
+    enum BaseSource {
+        #[serde(rename = "image")]
+        Image {
+            #[serde(rename = "@name")]
+            name: String,
+        },
+        #[serde(rename = "cube_map")]
+        CubeMap {
+            #[serde(rename = "@px")]
+            px: String,
+            #[serde(rename = "@nx")]
+            nx: String,
+            #[serde(rename = "@py")]
+            py: String,
+            #[serde(rename = "@ny")]
+            ny: String,
+            #[serde(rename = "@pz")]
+            pz: String,
+            #[serde(rename = "@nz")]
+            nz: String,
+        },
+    }
+
+    // The rotation around the y axis is in degrees
+    #[derive(Deserialize)]
+    struct BaseEnvironment {
+        #[serde(rename = "@intensity")]
+        intensity: Option<f32>,
+        #[serde(rename = "@rotation")]
+        #[serde(default)]
+        rotation: f32,
+        #[serde(rename = "@num_samples")]
+        num_samples: Option<usize>,
+        #[serde(rename = "$value")]
+        source: BaseSource,
+    }
+
+    let e = match Option::<BaseEnvironment>::deserialize(deserializer)? {
+        Some(e) => e,
+        None => return Ok(None),
+    };
+
+    let texture = match e.source {
+        BaseSource::Image { name } => load_image(&name),
+        BaseSource::CubeMap { px, nx, py, ny, pz, nz } => {
+            load_cube_map([&px, &nx, &py, &ny, &pz, &nz])
+        }
+    }
+    .map_err(|e| de::Error::custom(e.to_string()))?;
+
+    Ok(Some(Arc::new(Environment::new(
+        texture,
+        e.intensity.unwrap_or(1.),
+        e.rotation.to_radians(),
+        e.num_samples.unwrap_or(DEFAULT_NUM_SAMPLES),
+    ))))
+}
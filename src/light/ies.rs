@@ -0,0 +1,197 @@
+use crate::math::Vec3;
+use super::attenuation::LUMENS_PER_WATT;
+use super::light::to_world;
+use std::f32::consts::{PI, TAU};
+use std::sync::Arc;
+
+// Steps in the vertical angle for integrating the flux, the horizontal
+// angle takes twice as many
+const FLUX_STEPS: usize = 180;
+
+/// Candela values measured around a luminaire (type C photometry): the
+/// vertical angle goes from the nadir (0) to straight up (180), the
+/// horizontal one around it. Both are in degrees and ascending.
+pub struct IesProfile {
+    vertical: Vec<f32>,
+    horizontal: Vec<f32>,
+    // One row of vertical angles per horizontal angle
+    candela: Vec<Vec<f32>>,
+}
+
+impl IesProfile {
+    pub fn new(
+        vertical: Vec<f32>,
+        horizontal: Vec<f32>,
+        candela: Vec<Vec<f32>>,
+    ) -> Result<Self, String> {
+        let ascending = |angles: &[f32]| {
+            !angles.is_empty() && angles.windows(2).all(|w| w[0] < w[1])
+        };
+        if !ascending(&vertical) || !ascending(&horizontal) {
+            return Err("IES angles have to be ascending".to_string());
+        }
+        if candela.len() != horizontal.len()
+            || candela.iter().any(|row| row.len() != vertical.len())
+        {
+            return Err("IES candela values don't match the angles".into());
+        }
+
+        Ok(IesProfile {
+            vertical,
+            horizontal,
+            candela,
+        })
+    }
+
+    /// Candela at the vertical angle `theta` and the horizontal angle
+    /// `phi`, interpolated between the measured angles. Directions
+    /// outside of the vertical angles get no light.
+    pub fn candela(&self, theta: f32, phi: f32) -> f32 {
+        let first = self.vertical[0];
+        let last = self.vertical[self.vertical.len() - 1];
+        if theta < first || theta > last {
+            return 0.;
+        }
+
+        let (v0, v1, tv) = bracket(&self.vertical, theta);
+        let (h0, h1, th) = self.bracket_horizontal(self.fold(phi));
+        let row = |h: usize| {
+            let row = &self.candela[h];
+            row[v0] + tv * (row[v1] - row[v0])
+        };
+        row(h0) + th * (row(h1) - row(h0))
+    }
+
+    /// Maps `phi` into the horizontal angles, files leave out what
+    /// follows from the symmetry of the luminaire
+    fn fold(&self, phi: f32) -> f32 {
+        let phi = phi.rem_euclid(360.);
+        let first = self.horizontal[0];
+        let last = self.horizontal[self.horizontal.len() - 1];
+        if last == 90. {
+            // Symmetric in each quadrant
+            let phi = phi % 180.;
+            if phi > 90. {
+                180. - phi
+            } else {
+                phi
+            }
+        } else if last == 180. {
+            // Symmetric about the plane of 0 and 180 degrees
+            if phi > 180. {
+                360. - phi
+            } else {
+                phi
+            }
+        } else if first == 90. && last == 270. {
+            // Symmetric about the plane of 90 and 270 degrees
+            if phi < 90. {
+                180. - phi
+            } else if phi > 270. {
+                540. - phi
+            } else {
+                phi
+            }
+        } else {
+            phi
+        }
+    }
+
+    /// Like bracket, but a full profile wraps around from its last
+    /// horizontal angle to the first one at 360 degrees
+    fn bracket_horizontal(&self, phi: f32) -> (usize, usize, f32) {
+        let last = self.horizontal.len() - 1;
+        let end = self.horizontal[last];
+        if self.horizontal[0] == 0. && phi > end && end < 360. {
+            return (last, 0, (phi - end) / (360. - end));
+        }
+        bracket(&self.horizontal, phi)
+    }
+
+    /// Luminous flux of the profile in lumens
+    pub fn lumens(&self) -> f32 {
+        let d_theta = PI / FLUX_STEPS as f32;
+        let d_phi = TAU / (2 * FLUX_STEPS) as f32;
+        let mut lumens = 0.;
+
+        for i in 0..FLUX_STEPS {
+            let theta = (i as f32 + 0.5) * d_theta;
+            let mut ring = 0.;
+            for j in 0..2 * FLUX_STEPS {
+                let phi = (j as f32 + 0.5) * d_phi;
+                ring += self.candela(theta.to_degrees(), phi.to_degrees());
+            }
+            lumens += ring * theta.sin() * d_theta * d_phi;
+        }
+
+        lumens
+    }
+}
+
+/// The angles around `a` and how far it is from the first to the
+/// second, clamped to the ends
+fn bracket(angles: &[f32], a: f32) -> (usize, usize, f32) {
+    let i = angles.partition_point(|&x| x < a);
+    if i == 0 {
+        return (0, 0, 0.);
+    }
+    if i == angles.len() {
+        return (i - 1, i - 1, 0.);
+    }
+    (i - 1, i, (a - angles[i - 1]) / (angles[i] - angles[i - 1]))
+}
+
+/// A profile as a light sends it out, the nadir pointing along `nadir`.
+/// Seen from above, horizontal angles go counterclockwise.
+pub struct AimedProfile {
+    profile: Arc<IesProfile>,
+    nadir: Vec3,
+    x: Vec3,
+    y: Vec3,
+    // Watts per steradian for one candela of the profile
+    scale: f32,
+}
+
+impl AimedProfile {
+    /// Without `watts` the candela values are absolute, otherwise the
+    /// profile is scaled to send out that power
+    pub fn new(
+        profile: Arc<IesProfile>,
+        nadir: &Vec3,
+        watts: Option<f32>,
+    ) -> Self {
+        let scale = match watts {
+            None => 1. / LUMENS_PER_WATT,
+            Some(watts) => {
+                let lumens = profile.lumens();
+                if lumens > 0. {
+                    watts / lumens
+                } else {
+                    0.
+                }
+            }
+        };
+        let nadir = nadir.unit_vector();
+        let x = to_world(&nadir, 1., 0., 0.);
+        let y = x.cross(&nadir);
+
+        AimedProfile {
+            profile,
+            nadir,
+            x,
+            y,
+            scale,
+        }
+    }
+
+    /// Radiant intensity towards the unit vector `direction`, which
+    /// points away from the light
+    pub fn intensity(&self, direction: &Vec3) -> f32 {
+        let cos_theta = self.nadir.dot(direction).clamp(-1., 1.);
+        let phi = direction.dot(&self.y).atan2(direction.dot(&self.x));
+        self.scale
+            * self
+                .profile
+                .candela(cos_theta.acos().to_degrees(), phi.to_degrees())
+    }
+}
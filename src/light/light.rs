@@ -2,16 +2,25 @@ use crate::{
     math::{Color, Point3, Vec3},
     light::structs::Light as SceneLight,
     surface::materials::Phong as LightParameters,
-    ray::{Ray, Hittable, HitRecord},
+    ray::{Ray, Hittable, HittableList, HitRecord},
 };
 use super::{
     ambient::Ambient,
     point::Point,
     parallel::Parallel,
-    spot::Spot
+    spot::Spot,
+    attenuation::{profiled, radiometric},
+    ies::AimedProfile,
+    projector::Projector,
+    sphere::Sphere,
+    disk::Disk,
+    environment::Environment,
+    emitters::Emitters,
 };
 
 use rand::{distributions::Uniform, prelude::Distribution, Rng};
+use std::f32::consts::{PI, TAU};
+use std::sync::Arc;
 
 pub struct Lights<M, H, R>
 where
@@ -28,8 +37,14 @@ where
     H: Hittable,
     R: Rng,
 {
-    pub fn from_scene(scene_lights: &[SceneLight]) -> Self {
-        let mut lights = Vec::with_capacity(scene_lights.len());
+    /// The environment of the scene and its glowing surfaces light it
+    /// like any other light
+    pub fn from_scene(
+        scene_lights: &[SceneLight],
+        environment: Option<&Arc<Environment>>,
+        hittables: &HittableList,
+    ) -> Self {
+        let mut lights = Vec::with_capacity(scene_lights.len() + 2);
 
         for light in scene_lights {
             let b: Box<dyn LightSource<M, H, R> + Send + Sync> =
@@ -48,9 +63,24 @@ where
                         Box::new(l)
                     }
                     SceneLight::Point(p) => {
+                        let down = Vec3::from_values(0., -1., 0.);
+                        let profile = p.ies.as_ref().map(|ies| {
+                            AimedProfile::new(ies.clone(), &down, p.intensity)
+                        });
+                        let (color, attenuation) = match profile {
+                            Some(_) => profiled(&p.color, &p.attenuation),
+                            None => radiometric(
+                                &p.color,
+                                p.intensity,
+                                4. * PI,
+                                &p.attenuation,
+                            ),
+                        };
                         let l = Point {
-                            color: p.color.clone(),
+                            color,
                             position: p.position.clone(),
+                            attenuation,
+                            profile,
                         };
                         Box::new(l)
                     }
@@ -58,13 +88,34 @@ where
                         let alpha1 = s.fall_off.alpha1.to_radians();
                         let alpha2 = s.fall_off.alpha2.to_radians();
 
+                        // The cone reaching halfway through the falloff
+                        // holds as much light as the spot sends out
+                        let half = 0.5 * (alpha1 + alpha2);
+                        let profile = s.ies.as_ref().map(|ies| {
+                            AimedProfile::new(
+                                ies.clone(),
+                                &s.direction,
+                                s.intensity,
+                            )
+                        });
+                        let (color, attenuation) = match profile {
+                            Some(_) => profiled(&s.color, &s.attenuation),
+                            None => radiometric(
+                                &s.color,
+                                s.intensity,
+                                TAU * (1. - half.cos()),
+                                &s.attenuation,
+                            ),
+                        };
                         let l = Spot {
-                            color: s.color.clone(),
+                            color,
                             position: s.position.clone(),
                             direction: s.direction.unit_vector(),
                             alpha1,
                             alpha2,
                             alpha_range: alpha2 - alpha1,
+                            attenuation,
+                            profile,
                         };
                         Box::new(l)
                     }
@@ -88,6 +139,66 @@ where
                             num_steps: r.num_steps,
                         };
 
+                        Box::new(l)
+                    }
+                    SceneLight::Projector(p) => {
+                        let forward = p.direction.unit_vector();
+                        let mut right = forward.cross(&p.up);
+                        right = if right.length_squared() > 0. {
+                            right.unit_vector()
+                        } else {
+                            // Any up will do if it's along the direction
+                            to_world(&forward, 1., 0., 0.)
+                        };
+                        let up = right.cross(&forward);
+
+                        let image = &p.image;
+                        let tan_x = p.horizontal_fov.degrees.to_radians().tan();
+                        let tan_y = tan_x * image.height as f32
+                            / image.width as f32;
+                        // Of the pyramid the image is projected into
+                        let solid_angle = 4.
+                            * (tan_x.atan().sin() * tan_y.atan().sin()).asin();
+                        let (color, attenuation) = radiometric(
+                            &p.color,
+                            p.intensity,
+                            solid_angle,
+                            &p.attenuation,
+                        );
+
+                        let l = Projector {
+                            color,
+                            position: p.position.clone(),
+                            forward,
+                            right,
+                            up,
+                            tan_x,
+                            tan_y,
+                            image: image.clone(),
+                            attenuation,
+                        };
+
+                        Box::new(l)
+                    }
+                    SceneLight::Sphere(s) => {
+                        let l = Sphere {
+                            color: s.color.clone(),
+                            position: s.position.clone(),
+                            radius: s.radius,
+                            num_samples: s.num_samples,
+                        };
+
+                        Box::new(l)
+                    }
+                    SceneLight::Disk(d) => {
+                        let l = Disk {
+                            color: d.color.clone(),
+                            position: d.position.clone(),
+                            normal: d.direction.unit_vector(),
+                            radius: d.radius,
+                            num_samples: d.num_samples,
+                        };
+
                         Box::new(l)
                     }
                 };
@@ -95,6 +206,13 @@ where
             lights.push(b);
         }
 
+        if let Some(environment) = environment {
+            lights.push(Box::new(environment.clone()));
+        }
+        if let Some(emitters) = Emitters::from_surfaces(&hittables.objects) {
+            lights.push(Box::new(emitters));
+        }
+
         Self {
             lights,
         }
@@ -118,6 +236,107 @@ where
 
         color
     }
+
+    /// Next-event estimation for the path integrator: takes one
+    /// sample from every light and weights it against BRDF sampling
+    /// with the power heuristic. `normal` has to face the viewer.
+    pub fn direct(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        normal: &Vec3,
+        hittables: &H,
+        rng: &mut R,
+    ) -> Color {
+        let p = &hit_record.p;
+        let v = &-r.dir.unit_vector();
+        let m_c = &hit_record.material.color(r, hit_record);
+        let l_p = hit_record.material.phong();
+        let ior = hit_record.material.refraction();
+        let white = &Color::from_values(1., 1., 1.);
+        let mut color = Color::new();
+
+        for light in &self.lights {
+            let sample = match light.sample(p, rng) {
+                None => continue,
+                Some(s) => s,
+            };
+
+            let l = &sample.direction;
+            if l.dot(normal) <= 0. {
+                continue;
+            }
+
+            let s_ray = Ray::from_values(p, l);
+            if hittables.shadow_hit(&s_ray, 0.01, sample.distance) {
+                continue;
+            }
+
+            let weight = if sample.is_delta {
+                1.
+            } else {
+                power_heuristic(sample.pdf, M::pdf(l, v, normal, l_p))
+            };
+
+            // The light models return BRDF * cos scaled by PI
+            let f_cos = M::intensity(l, v, normal, white, m_c, l_p, ior)
+                / PI;
+            color += f_cos * &sample.radiance * (weight / sample.pdf);
+        }
+
+        color
+    }
+
+    /// Radiance of the glowing surface a ray hit. BRDF-sampled rays
+    /// weight it against next-event estimation if a light samples the
+    /// surface.
+    pub fn surface_emission(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        bsdf_pdf: Option<f32>,
+    ) -> Color {
+        let emission = match hit_record.material.emission() {
+            None => return Color::new(),
+            Some(e) => e,
+        };
+
+        let light_pdf = bsdf_pdf.and_then(|_| {
+            self.lights
+                .iter()
+                .find_map(|light| light.surface_pdf(r, hit_record))
+        });
+        match (bsdf_pdf, light_pdf) {
+            (Some(bsdf_pdf), Some(light_pdf)) => {
+                emission * power_heuristic(bsdf_pdf, light_pdf)
+            }
+            _ => emission.clone(),
+        }
+    }
+
+    /// Radiance picked up by a BRDF-sampled ray hitting a light
+    /// before `t_max`, weighted against next-event estimation
+    pub fn emitted(&self, r: &Ray, t_max: f32, bsdf_pdf: f32) -> Color {
+        let mut color = Color::new();
+
+        for light in &self.lights {
+            if let Some((radiance, pdf)) = light.emitted(r, t_max) {
+                color += radiance * power_heuristic(bsdf_pdf, pdf);
+            }
+        }
+
+        color
+    }
+}
+
+/// A direction towards a light, as used for next-event estimation.
+/// `pdf` is with respect to solid angle, delta lights use 1.
+pub struct LightSample {
+    pub direction: Vec3,
+    pub distance: f32,
+    pub radiance: Color,
+    pub pdf: f32,
+    pub is_delta: bool,
 }
 
 pub trait LightSource<M, H, R>
@@ -133,6 +352,58 @@ where
         hittables: &H,
         rng: &mut R,
     ) -> Color;
+
+    /// Samples the light as seen from `p`. Radiance is chosen so
+    /// that the path integrator ends up with the same direct light
+    /// as `intensity`. Lights without a direction (ambient) return
+    /// `None`.
+    fn sample(&self, _p: &Point3, _rng: &mut R) -> Option<LightSample> {
+        None
+    }
+
+    /// Radiance and solid angle pdf if `r` hits the light before
+    /// `t_max`, only possible for lights with an area
+    fn emitted(&self, _r: &Ray, _t_max: f32) -> Option<(Color, f32)> {
+        None
+    }
+
+    /// Solid angle pdf of `sample` picking the point where `r` hit a
+    /// glowing surface, if this light samples that surface
+    fn surface_pdf(&self, _r: &Ray, _hit: &HitRecord) -> Option<f32> {
+        None
+    }
+}
+
+/// Lights shared with the rest of the scene, like the environment that
+/// rays missing everything see as well
+impl<L, M, H, R> LightSource<M, H, R> for Arc<L>
+where
+    L: LightSource<M, H, R>,
+    M: LightModel,
+    H: Hittable,
+    R: Rng,
+{
+    fn intensity(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        hittables: &H,
+        rng: &mut R,
+    ) -> Color {
+        self.as_ref().intensity(r, hit_record, hittables, rng)
+    }
+
+    fn sample(&self, p: &Point3, rng: &mut R) -> Option<LightSample> {
+        self.as_ref().sample(p, rng)
+    }
+
+    fn emitted(&self, r: &Ray, t_max: f32) -> Option<(Color, f32)> {
+        self.as_ref().emitted(r, t_max)
+    }
+
+    fn surface_pdf(&self, r: &Ray, hit: &HitRecord) -> Option<f32> {
+        self.as_ref().surface_pdf(r, hit)
+    }
 }
 
 pub trait LightModel {
@@ -145,6 +416,23 @@ pub trait LightModel {
         light_parameters: &LightParameters,
         index_of_refraction: f32,
     ) -> Color;
+
+    /// Importance samples an incoming light direction for the path
+    /// integrator
+    fn sample<R: Rng>(
+        view_direction: &Vec3,
+        normal: &Vec3,
+        light_parameters: &LightParameters,
+        rng: &mut R,
+    ) -> Vec3;
+
+    /// Solid angle pdf of `sample` returning `light_direction`
+    fn pdf(
+        light_direction: &Vec3,
+        view_direction: &Vec3,
+        normal: &Vec3,
+        light_parameters: &LightParameters,
+    ) -> f32;
 }
 
 pub struct Phong;
@@ -165,6 +453,45 @@ impl LightModel for Phong {
         let specular = l_c * spec;
         diffuse * l_p.kd + specular * l_p.ks
     }
+
+    fn sample<R: Rng>(
+        v: &Vec3,
+        n: &Vec3,
+        l_p: &LightParameters,
+        rng: &mut R,
+    ) -> Vec3 {
+        let frand = Uniform::new(0.0, 1.0);
+        let u1 = frand.sample(rng);
+        let u2 = frand.sample(rng);
+
+        if frand.sample(rng) < diffuse_probability(l_p) {
+            return cosine_hemisphere(n, u1, u2);
+        }
+
+        // Sample the cos^n lobe around the mirror direction
+        let r = (-v).reflect(n);
+        let cos_theta = u1.powf(1. / (l_p.exponent + 1.));
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = TAU * u2;
+        to_world(
+            &r,
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        )
+    }
+
+    fn pdf(l: &Vec3, v: &Vec3, n: &Vec3, l_p: &LightParameters) -> f32 {
+        let p_d = diffuse_probability(l_p);
+        let diffuse = l.dot(n).max(0.) / PI;
+
+        let r = (-v).reflect(n);
+        let cos_alpha = l.dot(&r).max(0.);
+        let specular = (l_p.exponent + 1.) / TAU
+            * cos_alpha.powf(l_p.exponent);
+
+        p_d * diffuse + (1. - p_d) * specular
+    }
 }
 
 pub struct CookTorrance;
@@ -226,6 +553,215 @@ impl LightModel for CookTorrance {
 
         specular_diffuse
     }
+
+    fn sample<R: Rng>(
+        v: &Vec3,
+        n: &Vec3,
+        l_p: &LightParameters,
+        rng: &mut R,
+    ) -> Vec3 {
+        let frand = Uniform::new(0.0, 1.0);
+        let u1: f32 = frand.sample(rng);
+        let u2 = frand.sample(rng);
+
+        if frand.sample(rng) < diffuse_probability(l_p) {
+            return cosine_hemisphere(n, u1, u2);
+        }
+
+        // Sample a half vector from the Beckmann distribution and
+        // reflect the view direction on it
+        let alpha = (2. / (l_p.exponent - 2.)).sqrt();
+        let tan2h = -alpha * alpha * (1. - u1).ln();
+        let cos_theta = 1. / (1. + tan2h).sqrt();
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = TAU * u2;
+        let h = to_world(
+            n,
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        );
+
+        (-v).reflect(&h)
+    }
+
+    fn pdf(l: &Vec3, v: &Vec3, n: &Vec3, l_p: &LightParameters) -> f32 {
+        let p_d = diffuse_probability(l_p);
+        let diffuse = l.dot(n).max(0.) / PI;
+
+        let h = (l + v).unit_vector();
+        let dot_n_h = n.dot(&h).max(0.);
+        let dot_h_v = h.dot(v).abs().max(0.0001);
+
+        let alpha = (2. / (l_p.exponent - 2.)).sqrt();
+        let cos2h = dot_n_h * dot_n_h;
+        let tan2h = (1. - cos2h).max(0.) / cos2h;
+        let s_d = if tan2h.is_infinite() {
+            0f32
+        } else {
+            (-tan2h / (alpha * alpha)).exp()
+                / (PI * alpha * alpha * cos2h * cos2h)
+        };
+        let specular = s_d * dot_n_h / (4. * dot_h_v);
+
+        p_d * diffuse + (1. - p_d) * specular
+    }
+}
+
+/// Probability of sampling the diffuse lobe instead of the specular
+/// one, proportional to the material coefficients
+fn diffuse_probability(l_p: &LightParameters) -> f32 {
+    let total = l_p.kd + l_p.ks;
+    if total <= 0. {
+        1.
+    } else {
+        l_p.kd / total
+    }
+}
+
+fn power_heuristic(f_pdf: f32, g_pdf: f32) -> f32 {
+    let f2 = f_pdf * f_pdf;
+    let g2 = g_pdf * g_pdf;
+    if f2 + g2 <= 0. {
+        0.
+    } else {
+        f2 / (f2 + g2)
+    }
+}
+
+/// Transforms a direction given relative to `n` (as z axis) into world
+/// space, using the branchless basis by Duff et al.
+pub(super) fn to_world(n: &Vec3, x: f32, y: f32, z: f32) -> Vec3 {
+    let sign = 1f32.copysign(n.z);
+    let a = -1. / (sign + n.z);
+    let b = n.x * n.y * a;
+    let t = Vec3::from_values(
+        1. + sign * n.x * n.x * a,
+        sign * b,
+        -sign * n.x,
+    );
+    let bt = Vec3::from_values(b, sign + n.y * n.y * a, -n.y);
+
+    x * t + y * bt + z * n
+}
+
+fn cosine_hemisphere(n: &Vec3, u1: f32, u2: f32) -> Vec3 {
+    let r = u1.sqrt();
+    let phi = TAU * u2;
+    to_world(n, r * phi.cos(), r * phi.sin(), (1. - u1).max(0.).sqrt())
+}
+
+/// Whitted intensity of a light with an area, averaged over
+/// `num_samples` points picked by `sample`
+pub(super) fn sampled_intensity<M, H, R, F>(
+    r: &Ray,
+    hit_record: &HitRecord,
+    hittables: &H,
+    rng: &mut R,
+    num_samples: usize,
+    mut sample: F,
+) -> Color
+where
+    M: LightModel,
+    H: Hittable,
+    R: Rng,
+    F: FnMut(&Point3, &mut R) -> Option<LightSample>,
+{
+    let p = &hit_record.p;
+    let v = &-r.dir.unit_vector();
+    let n = &hit_record.normal;
+    let m_c = &hit_record.material.color(r, hit_record);
+    let l_p = hit_record.material.phong();
+    let ior = hit_record.material.refraction();
+    let mut color = Color::new();
+
+    for _ in 0..num_samples {
+        let sample = match sample(p, rng) {
+            Some(sample) => sample,
+            None => continue,
+        };
+
+        let l = &sample.direction;
+        let s_ray = Ray::from_values(p, l);
+        if hittables.shadow_hit(&s_ray, 0.01, sample.distance) {
+            continue;
+        }
+
+        // The light models expect the color of the light scaled by PI,
+        // see LightSample
+        let l_c = &(sample.radiance / (PI * sample.pdf));
+        color += M::intensity(l, v, n, l_c, m_c, l_p, ior);
+    }
+
+    color / num_samples as f32
+}
+
+/// Samples a uniform point on the parallelogram spanned by `v1` and
+/// `v2`. Area light radiance drops the usual distance falloff, just
+/// like the Whitted integrator does.
+fn parallelogram_sample(
+    color: &Color,
+    corner: &Point3,
+    v1: &Vec3,
+    v2: &Vec3,
+    p: &Point3,
+    u: f32,
+    v: f32,
+) -> Option<LightSample> {
+    let position = corner + v1 * u + v2 * v;
+    let l_not_norm = &position - p;
+    let distance = l_not_norm.length();
+    let direction = l_not_norm / distance;
+
+    let n = v1.cross(v2);
+    let area = n.length();
+    let cos_l = (n.dot(&direction) / area).abs();
+    if cos_l <= f32::EPSILON {
+        return None;
+    }
+
+    let pdf = distance * distance / (area * cos_l);
+    Some(LightSample {
+        direction,
+        distance,
+        radiance: PI * pdf * color,
+        pdf,
+        is_delta: false,
+    })
+}
+
+fn parallelogram_emitted(
+    color: &Color,
+    corner: &Point3,
+    v1: &Vec3,
+    v2: &Vec3,
+    r: &Ray,
+    t_max: f32,
+) -> Option<(Color, f32)> {
+    let n = v1.cross(v2);
+    let ndir = n.dot(&r.dir);
+    if ndir.abs() <= f32::EPSILON {
+        return None;
+    }
+
+    let t = n.dot(&(corner - &r.orig)) / ndir;
+    if t <= 0. || t >= t_max {
+        return None;
+    }
+
+    // Solve for the parallelogram coordinates of the hit point
+    let n2 = n.length_squared();
+    let d = r.at(t) - corner;
+    let u = d.cross(v2).dot(&n) / n2;
+    let v = v1.cross(&d).dot(&n) / n2;
+    if !(0. ..=1.).contains(&u) || !(0. ..=1.).contains(&v) {
+        return None;
+    }
+
+    let distance = t * r.dir.length();
+    let cos_l = ndir.abs() / (n2.sqrt() * r.dir.length());
+    let pdf = distance * distance / (n2.sqrt() * cos_l);
+    Some((PI * pdf * color, pdf))
 }
 
 pub struct RectangularAreaRandom {
@@ -277,6 +813,32 @@ where
 
         color / self.num_samples as f32
     }
+
+    fn sample(&self, p: &Point3, rng: &mut R) -> Option<LightSample> {
+        let frand = Uniform::new(0.0, 1.0);
+        let u = frand.sample(rng);
+        let v = frand.sample(rng);
+        parallelogram_sample(
+            &self.color,
+            &self.corner,
+            &self.v1,
+            &self.v2,
+            p,
+            u,
+            v,
+        )
+    }
+
+    fn emitted(&self, r: &Ray, t_max: f32) -> Option<(Color, f32)> {
+        parallelogram_emitted(
+            &self.color,
+            &self.corner,
+            &self.v1,
+            &self.v2,
+            r,
+            t_max,
+        )
+    }
 }
 
 pub struct RectangularArea {
@@ -331,4 +893,32 @@ where
 
         color / (self.num_steps * self.num_steps) as f32
     }
+
+    fn sample(&self, p: &Point3, rng: &mut R) -> Option<LightSample> {
+        // No stratification here, the path integrator already takes
+        // many samples per pixel
+        let frand = Uniform::new(0.0, 1.0);
+        let u = frand.sample(rng);
+        let v = frand.sample(rng);
+        parallelogram_sample(
+            &self.color,
+            &self.corner,
+            &self.v1,
+            &self.v2,
+            p,
+            u,
+            v,
+        )
+    }
+
+    fn emitted(&self, r: &Ray, t_max: f32) -> Option<(Color, f32)> {
+        parallelogram_emitted(
+            &self.color,
+            &self.corner,
+            &self.v1,
+            &self.v2,
+            r,
+            t_max,
+        )
+    }
 }
@@ -3,6 +3,13 @@ mod ambient;
 mod parallel;
 mod point;
 mod spot;
+mod sphere;
+mod disk;
+mod attenuation;
+mod ies;
+mod projector;
+mod environment;
+mod emitters;
 pub mod structs;
 
 pub use light::{LightModel, Lights, Phong, CookTorrance};
@@ -11,3 +18,7 @@ pub use ambient::Ambient;
 pub use parallel::Parallel;
 pub use point::Point;
 pub use spot::Spot;
+pub use environment::{parse_environment, Environment};
+pub use emitters::{EmissiveSphere, Emitters};
+pub use attenuation::{Attenuation, LUMENS_PER_WATT};
+pub use ies::IesProfile;
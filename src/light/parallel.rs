@@ -1,7 +1,8 @@
 use super::light::*;
-use crate::math::{Color, Vec3};
+use crate::math::{Color, Point3, Vec3};
 use crate::ray::{Ray, HitRecord, Hittable};
 use rand::Rng;
+use std::f32::consts::PI;
 
 pub struct Parallel {
     pub color: Color,
@@ -36,4 +37,14 @@ where
 
         M::intensity(l, v, n, l_c, m_c, l_p, ior)
     }
+
+    fn sample(&self, _p: &Point3, _rng: &mut R) -> Option<LightSample> {
+        Some(LightSample {
+            direction: -&self.direction,
+            distance: f32::INFINITY,
+            radiance: PI * &self.color,
+            pdf: 1.,
+            is_delta: true,
+        })
+    }
 }
@@ -1,11 +1,24 @@
 use super::light::*;
-use crate::math::{Color, Point3};
+use super::attenuation::Attenuation;
+use super::ies::AimedProfile;
+use crate::math::{Color, Point3, Vec3};
 use crate::ray::{Ray, HitRecord, Hittable};
 use rand::Rng;
+use std::f32::consts::PI;
 
 pub struct Point {
     pub color: Color,
     pub position: Point3,
+    pub attenuation: Attenuation,
+    pub profile: Option<AimedProfile>,
+}
+
+impl Point {
+    /// How much of the intensity the profile sends along `direction`,
+    /// which points away from the light
+    fn profile_factor(&self, direction: &Vec3) -> f32 {
+        self.profile.as_ref().map_or(1., |p| p.intensity(direction))
+    }
 }
 
 impl<M, H, R> LightSource<M, H, R> for Point
@@ -32,11 +45,28 @@ where
 
         let v = &-r.dir.unit_vector();
         let n = &hit_record.normal;
-        let l_c = &self.color;
+        let falloff = self.attenuation.factor(len) * self.profile_factor(&-l);
+        let l_c = &(falloff * &self.color);
         let m_c = &hit_record.material.color(r, hit_record);
         let l_p = hit_record.material.phong();
         let ior = hit_record.material.refraction();
 
         M::intensity(l, v, n, l_c, m_c, l_p, ior)
     }
+
+    fn sample(&self, p: &Point3, _rng: &mut R) -> Option<LightSample> {
+        let l_not_norm = &self.position - p;
+        let distance = l_not_norm.length();
+        let direction = l_not_norm / distance;
+        let attenuation = self.attenuation.factor(distance)
+            * self.profile_factor(&-&direction);
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: PI * attenuation * &self.color,
+            pdf: 1.,
+            is_delta: true,
+        })
+    }
 }
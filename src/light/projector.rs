@@ -0,0 +1,104 @@
+use super::light::*;
+use super::attenuation::Attenuation;
+use crate::math::{Color, Point3, Vec3};
+use crate::ray::{Ray, HitRecord, Hittable};
+use crate::surface::{materials::Texture, mipmap::bilinear_clamped};
+use rand::Rng;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Projects an image from `position`, like a slide projector or a
+/// stage light with a gobo. Nothing is lit outside of the image.
+pub struct Projector {
+    pub color: Color,
+    pub position: Point3,
+    // Unit vectors along the center of the image, to its right and top
+    pub forward: Vec3,
+    pub right: Vec3,
+    pub up: Vec3,
+    // Tangents of half the angles across and up the image
+    pub tan_x: f32,
+    pub tan_y: f32,
+    pub image: Arc<Texture>,
+    pub attenuation: Attenuation,
+}
+
+impl Projector {
+    /// Color of the light sent along `direction`, which points away
+    /// from the light, None outside of the image
+    fn projected(&self, direction: &Vec3) -> Option<Color> {
+        let z = direction.dot(&self.forward);
+        if z <= 0. {
+            return None;
+        }
+
+        let x = direction.dot(&self.right) / (z * self.tan_x);
+        let y = direction.dot(&self.up) / (z * self.tan_y);
+        if x.abs() > 1. || y.abs() > 1. {
+            return None;
+        }
+
+        // The first row of the image is its top
+        let u = 0.5 + 0.5 * x;
+        let v = 0.5 - 0.5 * y;
+        let image = &self.image;
+        let texel =
+            bilinear_clamped(image.width, image.height, &image.pixels, u, v);
+        Some(texel * &self.color)
+    }
+}
+
+impl<M, H, R> LightSource<M, H, R> for Projector
+where
+    M: LightModel,
+    H: Hittable,
+    R: Rng,
+{
+    fn intensity(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        hittables: &H,
+        _rng: &mut R,
+    ) -> Color {
+        let p = &hit_record.p;
+        let l_not_norm = &self.position - p;
+        let len = l_not_norm.length();
+        let l = &(l_not_norm / len);
+
+        let color = match self.projected(&-l) {
+            Some(color) => color,
+            None => return Color::new(),
+        };
+
+        let s_ray = Ray::from_values(p, l);
+        if hittables.shadow_hit(&s_ray, 0.01, len) {
+            return Color::new();
+        }
+
+        let v = &-r.dir.unit_vector();
+        let n = &hit_record.normal;
+        let l_c = &(self.attenuation.factor(len) * &color);
+        let m_c = &hit_record.material.color(r, hit_record);
+        let l_p = hit_record.material.phong();
+        let ior = hit_record.material.refraction();
+
+        M::intensity(l, v, n, l_c, m_c, l_p, ior)
+    }
+
+    fn sample(&self, p: &Point3, _rng: &mut R) -> Option<LightSample> {
+        let l_not_norm = &self.position - p;
+        let distance = l_not_norm.length();
+        let direction = l_not_norm / distance;
+        let color = self.projected(&-&direction)?;
+        let attenuation = self.attenuation.factor(distance);
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: PI * attenuation * color,
+            pdf: 1.,
+            is_delta: true,
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use super::light::*;
+use super::emitters::sphere_normal;
+use crate::math::{Color, Point3};
+use crate::ray::{Ray, HitRecord, Hittable};
+use rand::{distributions::Uniform, prelude::Distribution, Rng};
+use std::f32::consts::{PI, TAU};
+
+/// A ball glowing with radiance `color`. Points outside of it sample
+/// the cone of directions it covers, so no sample is wasted on the far
+/// side, and the light fades with the solid angle of that cone.
+pub struct Sphere {
+    pub color: Color,
+    pub position: Point3,
+    pub radius: f32,
+    pub num_samples: usize,
+}
+
+impl Sphere {
+    fn sample_from<R: Rng>(
+        &self,
+        p: &Point3,
+        rng: &mut R,
+    ) -> Option<LightSample> {
+        let frand = Uniform::new(0.0, 1.0);
+        let u1 = frand.sample(rng);
+        let u2 = frand.sample(rng);
+
+        let to_center = &self.position - p;
+        let d2 = to_center.length_squared();
+        let r2 = self.radius * self.radius;
+        if d2 <= r2 {
+            // Inside the light, pick a point by area instead
+            let n = sphere_normal(u1, u2);
+            let position = &self.position + self.radius * &n;
+            let l_not_norm = &position - p;
+            let distance = l_not_norm.length();
+            let direction = l_not_norm / distance;
+            let pdf = self.area_pdf(distance, n.dot(&direction).abs())?;
+            return Some(LightSample {
+                direction,
+                distance,
+                radiance: self.color.clone(),
+                pdf,
+                is_delta: false,
+            });
+        }
+
+        let d = d2.sqrt();
+        let one_minus_cos_max = cone_one_minus_cos(r2 / d2);
+        let cos_theta = 1. - u1 * one_minus_cos_max;
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = TAU * u2;
+        let direction = to_world(
+            &(to_center / d),
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        );
+
+        // Distance to the near side of the sphere along the direction
+        let b = d * cos_theta;
+        let distance = b - (r2 - d2 + b * b).max(0.).sqrt();
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.color.clone(),
+            pdf: 1. / (TAU * one_minus_cos_max),
+            is_delta: false,
+        })
+    }
+
+    fn area_pdf(&self, distance: f32, cos_l: f32) -> Option<f32> {
+        if cos_l <= f32::EPSILON || distance <= 0. {
+            return None;
+        }
+        let area = 4. * PI * self.radius * self.radius;
+        Some(distance * distance / (cos_l * area))
+    }
+}
+
+/// One minus the cosine of the half angle of a cone whose sine squared
+/// is `sin2`, without cancellation for small cones
+fn cone_one_minus_cos(sin2: f32) -> f32 {
+    sin2 / (1. + (1. - sin2).max(0.).sqrt())
+}
+
+impl<M, H, R> LightSource<M, H, R> for Sphere
+where
+    M: LightModel,
+    H: Hittable,
+    R: Rng,
+{
+    fn intensity(
+        &self,
+        r: &Ray,
+        hit_record: &HitRecord,
+        hittables: &H,
+        rng: &mut R,
+    ) -> Color {
+        sampled_intensity::<M, H, R, _>(
+            r,
+            hit_record,
+            hittables,
+            rng,
+            self.num_samples,
+            |p, rng| self.sample_from(p, rng),
+        )
+    }
+
+    fn sample(&self, p: &Point3, rng: &mut R) -> Option<LightSample> {
+        self.sample_from(p, rng)
+    }
+
+    fn emitted(&self, r: &Ray, t_max: f32) -> Option<(Color, f32)> {
+        let oc = &r.orig - &self.position;
+        let a = r.dir.length_squared();
+        let half_b = oc.dot(&r.dir);
+        let c = oc.length_squared() - self.radius * self.radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0. {
+            return None;
+        }
+
+        let sqrtd = discriminant.sqrt();
+        let mut t = (-half_b - sqrtd) / a;
+        if t <= 0. {
+            t = (-half_b + sqrtd) / a;
+        }
+        if t <= 0. || t >= t_max {
+            return None;
+        }
+
+        let pdf = if c > 0. {
+            let sin2 = self.radius * self.radius / oc.length_squared();
+            1. / (TAU * cone_one_minus_cos(sin2))
+        } else {
+            let n = (r.at(t) - &self.position) / self.radius;
+            let distance = t * a.sqrt();
+            self.area_pdf(distance, n.dot(&r.dir).abs() / a.sqrt())?
+        };
+        Some((self.color.clone(), pdf))
+    }
+}
@@ -1,7 +1,10 @@
 use super::light::*;
+use super::attenuation::Attenuation;
+use super::ies::AimedProfile;
 use crate::math::{Color, Point3, Vec3};
 use crate::ray::{Ray, HitRecord, Hittable};
 use rand::Rng;
+use std::f32::consts::PI;
 
 pub struct Spot {
     pub color: Color,
@@ -10,6 +13,16 @@ pub struct Spot {
     pub alpha1: f32,
     pub alpha2: f32,
     pub alpha_range: f32,
+    pub attenuation: Attenuation,
+    pub profile: Option<AimedProfile>,
+}
+
+impl Spot {
+    /// How much of the intensity the profile sends along `direction`,
+    /// which points away from the light
+    fn profile_factor(&self, direction: &Vec3) -> f32 {
+        self.profile.as_ref().map_or(1., |p| p.intensity(direction))
+    }
 }
 
 impl<M, H, R> LightSource<M, H, R> for Spot
@@ -42,7 +55,8 @@ where
 
         let v = &-r.dir.unit_vector();
         let n = &hit_record.normal;
-        let l_c = &self.color;
+        let falloff = self.attenuation.factor(len) * self.profile_factor(&-l);
+        let l_c = &(falloff * &self.color);
         let m_c = &hit_record.material.color(r, hit_record);
         let mut l_p = hit_record.material.phong().clone();
         let ior = hit_record.material.refraction();
@@ -57,4 +71,28 @@ where
 
         M::intensity(l, v, n, l_c, m_c, &l_p, ior)
     }
+
+    fn sample(&self, p: &Point3, _rng: &mut R) -> Option<LightSample> {
+        let l_not_norm = &self.position - p;
+        let distance = l_not_norm.length();
+        let direction = l_not_norm / distance;
+
+        let angle = self.direction.dot(&-&direction).acos();
+        if angle > self.alpha2 {
+            return None;
+        }
+
+        let interpolation = (angle - self.alpha1) / self.alpha_range;
+        let spotfactor = 1. - interpolation.clamp(0., 1.);
+        let attenuation = self.attenuation.factor(distance)
+            * self.profile_factor(&-&direction);
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: PI * spotfactor * attenuation * &self.color,
+            pdf: 1.,
+            is_delta: true,
+        })
+    }
 }
@@ -1,6 +1,11 @@
 use crate::utils::helpers::{parse_color, parse_vec3};
 use crate::math::{Color, Point3, Vec3};
-use serde::Deserialize;
+use crate::io::{load_ies, load_texture, ColorSpace};
+use crate::surface::materials::Texture;
+use super::attenuation::{Attenuation, LUMENS_PER_WATT};
+use super::ies::IesProfile;
+use serde::{de, Deserialize, Deserializer};
+use std::{path::PathBuf, sync::Arc};
 
 #[derive(Deserialize)]
 pub struct Lights {
@@ -23,6 +28,12 @@ pub enum Light {
     RectangularAreaRandom(RectangularAreaRandom),
     #[serde(rename = "rectangular_area")]
     RectangularArea(RectangularArea),
+    #[serde(rename = "sphere_light")]
+    Sphere(Sphere),
+    #[serde(rename = "disk_light")]
+    Disk(Disk),
+    #[serde(rename = "projector_light")]
+    Projector(Projector),
 }
 
 #[derive(Deserialize)]
@@ -31,12 +42,27 @@ pub struct Ambient {
     pub color: Color,
 }
 
+/// `intensity` is the power of the light in watts, which `color` then
+/// only tints. Such lights fall off with the square of the distance
+/// unless an `attenuation` says otherwise, as do lights with an `ies`
+/// profile. The profile points down and spreads the intensity, or
+/// gives it in candela if there is none.
 #[derive(Deserialize)]
 pub struct Point {
     #[serde(deserialize_with = "parse_color")]
     pub color: Color,
     #[serde(deserialize_with = "parse_vec3")]
     pub position: Point3,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_attenuation")]
+    pub attenuation: Option<Attenuation>,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_intensity")]
+    pub intensity: Option<f32>,
+    #[serde(rename = "@ies")]
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_ies")]
+    pub ies: Option<Arc<IesProfile>>,
 }
 
 #[derive(Deserialize)]
@@ -47,6 +73,8 @@ pub struct Parallel {
     pub direction: Vec3,
 }
 
+/// Like for point lights, but the `ies` profile points along
+/// `direction` and the cone still cuts it off
 #[derive(Deserialize)]
 pub struct Spot {
     #[serde(rename = "color")]
@@ -60,6 +88,16 @@ pub struct Spot {
     pub direction: Vec3,
     #[serde(rename = "falloff")]
     pub fall_off: FallOff,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_attenuation")]
+    pub attenuation: Option<Attenuation>,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_intensity")]
+    pub intensity: Option<f32>,
+    #[serde(rename = "@ies")]
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_ies")]
+    pub ies: Option<Arc<IesProfile>>,
 }
 
 /// The angles in FallOff are the angle from the center of cone to the
@@ -99,3 +137,201 @@ pub struct RectangularArea {
     #[serde(rename = "@num_steps")]
     pub num_steps: usize,
 }
+
+/// `color` is the radiance of the surface, so unlike point lights these
+/// grow dimmer with distance
+#[derive(Deserialize)]
+pub struct Sphere {
+    #[serde(deserialize_with = "parse_color")]
+    pub color: Color,
+    #[serde(deserialize_with = "parse_vec3")]
+    pub position: Point3,
+    #[serde(rename = "@radius")]
+    pub radius: f32,
+    #[serde(rename = "@num_samples")]
+    pub num_samples: usize,
+}
+
+/// Glows on the side `direction` points to
+#[derive(Deserialize)]
+pub struct Disk {
+    #[serde(deserialize_with = "parse_color")]
+    pub color: Color,
+    #[serde(deserialize_with = "parse_vec3")]
+    pub position: Point3,
+    #[serde(deserialize_with = "parse_vec3")]
+    pub direction: Vec3,
+    #[serde(rename = "@radius")]
+    pub radius: f32,
+    #[serde(rename = "@num_samples")]
+    pub num_samples: usize,
+}
+
+/// Projects `image` along `direction`, with its top towards `up`.
+/// `horizontal_fov` is half the angle across the image like for the
+/// camera, the image gives the angle up. An `intensity` is what a white
+/// image would send out.
+#[derive(Deserialize)]
+pub struct Projector {
+    #[serde(rename = "@image")]
+    #[serde(deserialize_with = "parse_projector_image")]
+    pub image: Arc<Texture>,
+    #[serde(deserialize_with = "parse_color")]
+    pub color: Color,
+    #[serde(deserialize_with = "parse_vec3")]
+    pub position: Point3,
+    #[serde(deserialize_with = "parse_vec3")]
+    pub direction: Vec3,
+    #[serde(deserialize_with = "parse_vec3")]
+    pub up: Vec3,
+    pub horizontal_fov: Angle,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_attenuation")]
+    pub attenuation: Option<Attenuation>,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_intensity")]
+    pub intensity: Option<f32>,
+}
+
+#[derive(Deserialize)]
+pub struct Angle {
+    #[serde(rename = "@angle")]
+    pub degrees: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AttenuationKind {
+    Polynomial,
+    InverseSquare,
+}
+
+/// An optional `<attenuation/>`, either `kind="inverse_square"` or
+/// polynomial with the `constant` (1 per default), `linear` and
+/// `quadratic` coefficients
+pub fn parse_attenuation<'de, D>(
+    deserializer: D,
+) -> Result<Option<Attenuation>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct BaseAttenuation {
+        #[serde(rename = "@kind")]
+        kind: Option<AttenuationKind>,
+        #[serde(rename = "@constant")]
+        constant: Option<f32>,
+        #[serde(rename = "@linear")]
+        linear: Option<f32>,
+        #[serde(rename = "@quadratic")]
+        quadratic: Option<f32>,
+    }
+
+    let a = match Option::<BaseAttenuation>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(a) => a,
+    };
+    let has_coefficients =
+        a.constant.is_some() || a.linear.is_some() || a.quadratic.is_some();
+
+    match a.kind {
+        Some(AttenuationKind::InverseSquare) if has_coefficients => {
+            Err(de::Error::custom(
+                "inverse_square attenuation takes no coefficients",
+            ))
+        }
+        Some(AttenuationKind::InverseSquare) => {
+            Ok(Some(Attenuation::INVERSE_SQUARE))
+        }
+        _ => {
+            let attenuation = Attenuation {
+                constant: a.constant.unwrap_or(1.),
+                linear: a.linear.unwrap_or(0.),
+                quadratic: a.quadratic.unwrap_or(0.),
+            };
+            let coefficients = [
+                attenuation.constant,
+                attenuation.linear,
+                attenuation.quadratic,
+            ];
+            if coefficients.iter().any(|&c| c < 0.)
+                || coefficients.iter().all(|&c| c == 0.)
+            {
+                return Err(de::Error::custom(
+                    "Attenuation coefficients must not be negative or all zero",
+                ));
+            }
+            Ok(Some(attenuation))
+        }
+    }
+}
+
+/// An optional `<intensity watts="..."/>` or `<intensity lumens="..."/>`,
+/// in watts
+pub fn parse_intensity<'de, D>(
+    deserializer: D,
+) -> Result<Option<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct BaseIntensity {
+        #[serde(rename = "@watts")]
+        watts: Option<f32>,
+        #[serde(rename = "@lumens")]
+        lumens: Option<f32>,
+    }
+
+    match Option::<BaseIntensity>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(BaseIntensity {
+            watts: Some(watts),
+            lumens: None,
+        }) => Ok(Some(watts)),
+        Some(BaseIntensity {
+            watts: None,
+            lumens: Some(lumens),
+        }) => Ok(Some(lumens / LUMENS_PER_WATT)),
+        Some(_) => Err(de::Error::custom(
+            "intensity needs either watts or lumens",
+        )),
+    }
+}
+
+/// The optional `ies` attribute, a photometric file in the scenes folder
+pub fn parse_ies<'de, D>(
+    deserializer: D,
+) -> Result<Option<Arc<IesProfile>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = match Option::<String>::deserialize(deserializer)? {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let mut path = PathBuf::new();
+    path.push(r"../scenes");
+    path.push(&name);
+    let profile = load_ies(&path).map_err(|e| {
+        de::Error::custom(format!("Can't load {}: {}", name, e))
+    })?;
+    Ok(Some(Arc::new(profile)))
+}
+
+/// Projected images are in the scenes folder, pngs are decoded from sRGB
+pub fn parse_projector_image<'de, D>(
+    deserializer: D,
+) -> Result<Arc<Texture>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    let mut path = PathBuf::new();
+    path.push(r"../scenes");
+    path.push(&name);
+    let image = load_texture(&path, ColorSpace::Srgb).map_err(|e| {
+        de::Error::custom(format!("Can't load {}: {}", name, e))
+    })?;
+    Ok(Arc::new(image))
+}
@@ -1,4 +1,4 @@
-use std::{error::Error, f32::consts, sync::OnceLock};
+use std::{error::Error, f32::consts, path::Path, sync::OnceLock};
 use rand::prelude::*;
 use rand_xoshiro::Xoshiro256StarStar;
 use rayon::prelude::*;
@@ -7,22 +7,26 @@ mod camera;
 mod light;
 mod surface;
 mod kdtree;
+mod bvh;
 mod scene;
 mod math;
 mod utils;
 mod ray;
 mod io;
 mod raytracer;
+mod pathtracer;
 
 use math::{Color, Vec3};
 use ray::{Hittable, HittableList, Ray};
 use scene::Scene;
 use indicatif::ParallelProgressIterator;
 use kdtree::KDTree;
+use bvh::Bvh;
 use light::{LightModel, Lights, Phong, CookTorrance};
-use utils::config::Config;
+use utils::config::{Accelerator, Config, Integrator};
 use crate::io::SceneWriter;
 use crate::raytracer::*;
+use crate::pathtracer::path_color;
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
@@ -40,11 +44,18 @@ where
     M: LightModel,
     R: Rng,
 {
+    if config.integrator == Integrator::Path {
+        return path_color(r, scene, config, hittables, lights, rng);
+    }
+
     hittables
         .hit(r, 0., f32::INFINITY)
         .map(|hit| {
             let unit_normal = hit.normal.unit_vector();
-            let color = lights.intensity(r, &hit, hittables, rng);
+            let mut color = lights.intensity(r, &hit, hittables, rng);
+            if let Some(emission) = hit.material.emission() {
+                color += emission;
+            }
 
             // Reached max bounces, return the color
             if bounce > scene.camera.max_bounces {
@@ -79,7 +90,7 @@ where
                 )
             }
         })
-        .unwrap_or(scene.background_color.clone())
+        .unwrap_or_else(|| scene.background(r))
 }
 
 fn render<H, M, R>(
@@ -116,8 +127,13 @@ fn render<H, M, R>(
                 &mut chunk_rng,
             );
 
+            let color = color * scene.camera.exposure;
             let mut int_color = [0u8; 3];
-            utils::get_int_color(&mut int_color, &color);
+            utils::get_int_color(
+                &mut int_color,
+                &color,
+                config.srgb_output,
+            );
             slice.copy_from_slice(&int_color);
         });
 }
@@ -176,65 +192,65 @@ where
 fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     CONFIG.set(utils::config::get_config()?).unwrap();
 
-    let mut scene = io::load_scene()?;
     let config = CONFIG.get().unwrap();
+    // Before loading the scene, meshes build their hierarchies in parallel
     rayon::ThreadPoolBuilder::new()
         .num_threads(config.num_threads)
         .build_global()
         .unwrap();
+    let mut scene = io::load_scene()?;
 
     let seed = config.random_seed;
     let rng = Xoshiro256StarStar::seed_from_u64(seed);
 
+    let mut hittables = HittableList::new();
+    hittables.extend(scene.world.objects.drain(..).collect());
+
     if config.cook_torrance {
-        if config.kdtree {
-            let lights: Lights<
-                CookTorrance,
-                KDTree,
-                Xoshiro256StarStar,
-            >;
-            lights = Lights::from_scene(&scene.lights.lights);
+        render_accelerated::<CookTorrance>(scene, config, hittables, rng)
+    } else {
+        render_accelerated::<Phong>(scene, config, hittables, rng)
+    }
+}
 
-            println!("Building kdtree...");
-            let mut hittables = HittableList::new();
-            hittables.extend(scene.world.objects.drain(..).collect());
-            let kdtree = KDTree::build(hittables);
-            println!("Done building kdtree.");
-            render_main(scene, config, &kdtree, &lights, rng)
-        } else {
-            let lights: Lights<
-                CookTorrance,
-                HittableList,
-                Xoshiro256StarStar,
-            >;
-            lights = Lights::from_scene(&scene.lights.lights);
-
-            let mut hittables = HittableList::new();
-            hittables.extend(scene.world.objects.drain(..).collect());
+fn render_accelerated<M>(
+    scene: Scene,
+    config: &Config,
+    hittables: HittableList,
+    rng: Xoshiro256StarStar,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    M: LightModel,
+{
+    let env = scene.environment.as_ref();
+    match config.accelerator() {
+        Accelerator::List => {
+            let lights: Lights<M, HittableList, Xoshiro256StarStar> =
+                Lights::from_scene(&scene.lights.lights, env, &hittables);
             render_main(scene, config, &hittables, &lights, rng)
         }
-    } else {
-        if config.kdtree {
-            let lights: Lights<Phong, KDTree, Xoshiro256StarStar>;
-            lights = Lights::from_scene(&scene.lights.lights);
+        Accelerator::KDTree => {
+            let lights: Lights<M, KDTree, Xoshiro256StarStar> =
+                Lights::from_scene(&scene.lights.lights, env, &hittables);
 
             println!("Building kdtree...");
-            let mut hittables = HittableList::new();
-            hittables.extend(scene.world.objects.drain(..).collect());
-            let kdtree = KDTree::build(hittables);
+            let kdtree = match config.kdtree_cache {
+                Some(ref path) => {
+                    KDTree::build_cached(hittables, Path::new(path))
+                }
+                None => KDTree::build(hittables),
+            };
             println!("Done building kdtree.");
             render_main(scene, config, &kdtree, &lights, rng)
-        } else {
-            let lights: Lights<
-                Phong,
-                HittableList,
-                Xoshiro256StarStar,
-            >;
-            lights = Lights::from_scene(&scene.lights.lights);
-
-            let mut hittables = HittableList::new();
-            hittables.extend(scene.world.objects.drain(..).collect());
-            render_main(scene, config, &hittables, &lights, rng)
+        }
+        Accelerator::Bvh => {
+            let lights: Lights<M, Bvh, Xoshiro256StarStar> =
+                Lights::from_scene(&scene.lights.lights, env, &hittables);
+
+            println!("Building bvh...");
+            let bvh = Bvh::build(hittables);
+            println!("Done building bvh.");
+            render_main(scene, config, &bvh, &lights, rng)
         }
     }
 }
@@ -98,6 +98,22 @@ impl Mat4 {
         }
     }
 
+    /// Rotation by the unit quaternion x y z w
+    pub fn rotate_quaternion(q: [f32; 4]) -> Self {
+        let [x, y, z, w] = q;
+        let (xx, yy, zz) = (x * x, y * y, z * z);
+        let (xy, xz, yz) = (x * y, x * z, y * z);
+        let (wx, wy, wz) = (w * x, w * y, w * z);
+        Self {
+            e: [
+                1. - 2. * (yy + zz), 2. * (xy - wz), 2. * (xz + wy), 0.,
+                2. * (xy + wz), 1. - 2. * (xx + zz), 2. * (yz - wx), 0.,
+                2. * (xz - wy), 2. * (yz + wx), 1. - 2. * (xx + yy), 0.,
+                0., 0., 0., 1.
+            ]
+        }
+    }
+
     pub fn transpose(&self) -> Self {
         let e = &self.e;
         Self {
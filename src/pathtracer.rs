@@ -0,0 +1,147 @@
+use crate::{
+    math::Color,
+    ray::{Hittable, Ray},
+    scene::Scene,
+    light::{LightModel, Lights},
+    utils::config::Config,
+    raytracer::{fresnel, reflected_ray, refracted_ray},
+};
+
+use rand::distributions::Uniform;
+use rand::prelude::*;
+use std::f32::consts::PI;
+
+// Number of bounces before russian roulette may terminate a path
+static MIN_BOUNCES: usize = 3;
+
+// Upper bound for the survival probability, so that paths with a
+// throughput above one still terminate eventually
+static MAX_SURVIVAL: f32 = 0.95;
+
+/// Unidirectional path tracer. Every bounce picks one lobe of the
+/// material: perfect reflection and refraction with probability of
+/// their coefficients, otherwise the light model's BRDF, which is
+/// importance sampled and combined with next-event estimation through
+/// multiple importance sampling. Paths are terminated by russian
+/// roulette instead of `max_bounces`.
+///
+/// Ambient lights are ignored, indirect light takes their place. Area
+/// lights stay invisible to camera rays and perfect specular paths
+/// like in the Whitted integrator, glowing surfaces are seen by all
+/// rays. An environment map is sampled as a light, it is only seen
+/// directly by camera and specular rays.
+pub fn path_color<H, M, R>(
+    r: &Ray,
+    scene: &Scene,
+    config: &Config,
+    hittables: &H,
+    lights: &Lights<M, H, R>,
+    rng: &mut R,
+) -> Color
+where
+    H: Hittable,
+    M: LightModel,
+    R: Rng,
+{
+    let frand = Uniform::new(0.0, 1.0);
+    let white = Color::from_values(1., 1., 1.);
+    let mut color = Color::new();
+    let mut throughput = white.clone();
+    let mut ray = r.clone();
+
+    // Pdf of the last BRDF sample, None for camera rays and perfect
+    // specular bounces
+    let mut bsdf_pdf = None;
+
+    for bounce in 0.. {
+        let hit = hittables.hit(&ray, 0., f32::INFINITY);
+
+        if let Some(pdf) = bsdf_pdf {
+            let t_max = hit.as_ref().map_or(f32::INFINITY, |h| h.t);
+            color += &throughput * lights.emitted(&ray, t_max, pdf);
+        }
+
+        let hit = match hit {
+            // Without an environment the background color lights the
+            // scene, the environment is a light that already counted
+            // itself for BRDF samples
+            None => {
+                if scene.environment.is_none() || bsdf_pdf.is_none() {
+                    color += &throughput * scene.background(&ray);
+                }
+                break;
+            }
+            Some(hit) => hit,
+        };
+        color += &throughput * lights.surface_emission(&ray, &hit, bsdf_pdf);
+
+        // The outward normal decides between entering and leaving for
+        // refraction, the BRDF wants the side the ray came from
+        let outward_normal = hit.normal.unit_vector();
+        let normal = if hit.front_face {
+            outward_normal.clone()
+        } else {
+            -&outward_normal
+        };
+
+        let reflectance = hit.material.reflectance();
+        let transmittance = hit.material.transmittance();
+        let (p_reflect, p_refract) = if config.fresnel
+            && reflectance > f32::EPSILON
+            && transmittance > f32::EPSILON
+        {
+            let fr = fresnel(
+                hit.material.refraction(),
+                &outward_normal,
+                &ray.dir.unit_vector(),
+            );
+            (reflectance + transmittance * fr, transmittance * (1. - fr))
+        } else {
+            (reflectance, transmittance)
+        };
+
+        let lobe = frand.sample(rng);
+        if lobe < p_reflect {
+            ray = reflected_ray(&ray, &hit, &outward_normal);
+            bsdf_pdf = None;
+        } else if lobe < p_reflect + p_refract {
+            ray = refracted_ray(&ray, &hit, &outward_normal);
+            bsdf_pdf = None;
+        } else {
+            color += &throughput
+                * lights.direct(&ray, &hit, &normal, hittables, rng);
+
+            let v = -ray.dir.unit_vector();
+            let l_p = hit.material.phong();
+            let l = M::sample(&v, &normal, l_p, rng).unit_vector();
+            let pdf = M::pdf(&l, &v, &normal, l_p);
+            if l.dot(&normal) <= 0. || pdf <= 0. {
+                break;
+            }
+
+            let m_c = hit.material.color(&ray, &hit);
+            let ior = hit.material.refraction();
+            let f_cos =
+                M::intensity(&l, &v, &normal, &white, &m_c, l_p, ior) / PI;
+            throughput = throughput * f_cos / pdf;
+
+            let origin = &hit.p + &l * 0.01;
+            ray = Ray::from_values(&origin, &l);
+            bsdf_pdf = Some(pdf);
+        }
+
+        if bounce >= MIN_BOUNCES {
+            let survival = throughput
+                .x
+                .max(throughput.y)
+                .max(throughput.z)
+                .min(MAX_SURVIVAL);
+            if frand.sample(rng) >= survival {
+                break;
+            }
+            throughput /= survival;
+        }
+    }
+
+    color
+}
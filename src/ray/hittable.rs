@@ -1,11 +1,18 @@
 use std::sync::Arc;
 use super::Ray;
 use crate::{
-    math::{Vec3, Point3},
+    math::{Color, Vec3, Point3},
     surface::Material,
     kdtree::AABB,
+    light::Emitters,
 };
 
+// Distance `hits` moves past an intersection before looking for the
+// next one, and an upper bound for the number of intersections, in case
+// a shape keeps reporting the same one
+const HITS_STEP: f32 = 1e-4;
+const MAX_HITS: usize = 64;
+
 pub trait Hittable: Send + Sync {
     fn hit(
         &self,
@@ -17,6 +24,30 @@ pub trait Hittable: Send + Sync {
     fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool;
 
     fn bound(&self) -> AABB;
+
+    /// Adds the glowing parts of the surface to the lights of the
+    /// scene, only spheres and meshes can be sampled like that
+    fn collect_emitters(&self, _emitters: &mut Emitters) {}
+
+    /// All intersections in range, ordered by t. For closed surfaces
+    /// the front face flag tells entries and exits apart, which is what
+    /// CSG needs. The default just keeps asking `hit` for the next one.
+    fn hits(&self, r: &Ray, t_min: f32, t_max: f32) -> Vec<HitRecord> {
+        let mut hits = vec![];
+        let mut t_start = t_min;
+
+        while hits.len() < MAX_HITS {
+            match self.hit(r, t_start, t_max) {
+                None => break,
+                Some(hit) => {
+                    t_start = hit.t + HITS_STEP;
+                    hits.push(hit);
+                }
+            }
+        }
+
+        hits
+    }
 }
 
 #[derive(Clone)]
@@ -26,6 +57,17 @@ pub struct HitRecord {
     pub t: f32,
     pub front_face: bool,
     pub tex_coords: Vec3,
+    // How much the texture coords change to the neighbouring pixels in
+    // x and y direction, only known for camera rays
+    pub tex_derivatives: Option<(Vec3, Vec3)>,
+    // Only set for meshes with a second set of texture coords
+    pub second_tex_coords: Option<Vec3>,
+    pub second_tex_derivatives: Option<(Vec3, Vec3)>,
+    // Only set for meshes with vertex colors
+    pub vertex_color: Option<Color>,
+    // Only set for transformed objects, the hit point before the
+    // transform
+    pub object_p: Option<Point3>,
     pub material: Arc<dyn Material>,
 }
 
@@ -45,7 +87,66 @@ impl HitRecord {
             t,
             front_face,
             tex_coords,
+            tex_derivatives: None,
+            second_tex_coords: None,
+            second_tex_derivatives: None,
+            vertex_color: None,
+            object_p: None,
             material,
         }
     }
+
+    /// Sets the texture derivatives of surfaces whose point moves by
+    /// `dp_du` and `dp_dv` (in world space) with the texture coords. The
+    /// rays of the neighbouring pixels are cut with the tangent plane of
+    /// the hit, their offsets from it are then split up along those two
+    /// directions.
+    pub fn set_tex_derivatives(
+        &mut self,
+        r: &Ray,
+        dp_du: &Vec3,
+        dp_dv: &Vec3,
+    ) {
+        let d = match &r.differentials {
+            Some(d) => d,
+            None => return,
+        };
+
+        let n = &self.normal;
+        let offset = |orig: &Point3, dir: &Vec3| {
+            let ndir = n.dot(dir);
+            if ndir.abs() < f32::EPSILON {
+                return None;
+            }
+            let t = n.dot(&(&self.p - orig)) / ndir;
+            Some(orig + t * dir - &self.p)
+        };
+        let (dp_dx, dp_dy) = match (
+            offset(&d.rx_orig, &d.rx_dir),
+            offset(&d.ry_orig, &d.ry_dir),
+        ) {
+            (Some(dp_dx), Some(dp_dy)) => (dp_dx, dp_dy),
+            _ => return,
+        };
+
+        // Least squares solution of dp = du dp_du + dv dp_dv
+        let uu = dp_du.dot(dp_du);
+        let uv = dp_du.dot(dp_dv);
+        let vv = dp_dv.dot(dp_dv);
+        // Zero when the two directions are parallel
+        let det = uu * vv - uv * uv;
+        if det <= f32::EPSILON * uu * vv {
+            return;
+        }
+        let solve = |dp: &Vec3| {
+            let pu = dp_du.dot(dp);
+            let pv = dp_dv.dot(dp);
+            Vec3::from_values(
+                (vv * pu - uv * pv) / det,
+                (uu * pv - uv * pu) / det,
+                0.,
+            )
+        };
+        self.tex_derivatives = Some((solve(&dp_dx), solve(&dp_dy)));
+    }
 }
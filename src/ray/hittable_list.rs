@@ -20,6 +20,25 @@ impl HittableList {
     pub fn extend(&mut self, mut objects: Vec<Box<dyn Hittable>>) {
         self.objects.append(&mut objects)
     }
+
+    /// Splits off the objects with infinite bounds (or none at all).
+    /// Acceleration structures can't partition those, so they test
+    /// them separately.
+    pub fn split_unbounded(self) -> (Self, Self) {
+        let (bounded, unbounded) = self
+            .objects
+            .into_iter()
+            .partition(|object| object.bound().is_finite());
+
+        (
+            Self {
+                objects: bounded,
+            },
+            Self {
+                objects: unbounded,
+            },
+        )
+    }
 }
 
 impl Default for HittableList {
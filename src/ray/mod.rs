@@ -2,6 +2,6 @@ mod ray;
 mod hittable;
 mod hittable_list;
 
-pub use ray::Ray;
+pub use ray::{Ray, RayDifferentials};
 pub use hittable::{HitRecord, Hittable};
-pub use hittable_list::{hit_scan, shadow_hit_scan, HittableList};
+pub use hittable_list::HittableList;
@@ -5,26 +5,32 @@ use crate::kdtree::AABB;
 pub struct Ray {
     pub orig: Point3,
     pub dir: Vec3,
+    // Only camera rays have them
+    pub differentials: Option<RayDifferentials>,
 
     // For faster AABB testing
     inv_dir: Vec3,
-    sign: [bool; 3]
+}
+
+/// The rays through the neighbouring pixels in x and y direction, they
+/// tell how large a pixel is where the ray hits a surface
+#[derive(Clone)]
+pub struct RayDifferentials {
+    pub rx_orig: Point3,
+    pub rx_dir: Vec3,
+    pub ry_orig: Point3,
+    pub ry_dir: Vec3,
 }
 
 impl Ray {
     pub fn from_values(origin: &Point3, direction: &Vec3) -> Self {
         let inv_dir = 1. / direction;
-        let sign = [
-            direction.x < 0., 
-            direction.y < 0.,
-            direction.z < 0.
-        ];
 
         Self {
             orig: origin.clone(),
             dir: direction.clone(),
+            differentials: None,
             inv_dir,
-            sign
         }
     }
 
@@ -32,72 +38,37 @@ impl Ray {
         &self.orig + t * &self.dir 
     }
 
-    fn get_aabb_sign(aabb: &AABB, sign: bool) -> &Point3 {
-        if sign {
-            &aabb.max
-        } else {
-            &aabb.min
-        }
-    }
-
-    pub fn intersect_aabb(&self, aabb: &AABB) -> bool {
-        let mut ray_min =
-            (Self::get_aabb_sign(
-                aabb, 
-                self.sign[0]
-            ).x - self.orig.x)
-            * self.inv_dir.x;
-        let mut ray_max =
-            (Self::get_aabb_sign(
-                aabb, 
-                !self.sign[0]
-            ).x - self.orig.x)
-            * self.inv_dir.x;
-        let y_min =
-            (Self::get_aabb_sign(
-                aabb, 
-                self.sign[1]
-            ).y - self.orig.y)
-            * self.inv_dir.y;
-        let y_max =
-            (Self::get_aabb_sign(
-                aabb, 
-                !self.sign[1]
-            ).y - self.orig.y)
-            * self.inv_dir.y;
-
-        if ray_min > y_max || y_min > ray_max {
-            return false;
-        }
+    /// Clips `[t_min, t_max]` against the slabs of the box, returns the
+    /// entry and exit distance if anything is left
+    pub fn aabb_interval(
+        &self,
+        aabb: &AABB,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, f32)> {
+        let (x_min, x_max) =
+            Self::slab(aabb.min.x, aabb.max.x, self.orig.x, self.inv_dir.x);
+        let (y_min, y_max) =
+            Self::slab(aabb.min.y, aabb.max.y, self.orig.y, self.inv_dir.y);
+        let (z_min, z_max) =
+            Self::slab(aabb.min.z, aabb.max.z, self.orig.z, self.inv_dir.z);
 
-        if y_min > ray_min {
-            ray_min = y_min;
-        }
-        if y_max < ray_max {
-            ray_max = y_max;
+        // f32::max and f32::min ignore the NaN produced by rays lying
+        // exactly in a slab plane
+        let t0 = t_min.max(x_min).max(y_min).max(z_min);
+        let t1 = t_max.min(x_max).min(y_max).min(z_max);
+        if t0 > t1 {
+            return None;
         }
 
-        let z_min =
-            (Self::get_aabb_sign(
-                aabb, 
-                self.sign[2]
-            ).z - self.orig.z)
-            * self.inv_dir.z;
-        let z_max =
-            (Self::get_aabb_sign(
-                aabb, 
-                !self.sign[2]
-            ).z - self.orig.z)
-            * self.inv_dir.z;
-
-        if (ray_min > z_max) || (z_min > ray_max) {
-            return false;
-        }
-
-        if z_max < ray_max {
-            ray_max = z_max;
-        }
+        Some((t0, t1))
+    }
 
-        ray_max > 0.0
+    fn slab(min: f32, max: f32, orig: f32, inv_dir: f32) -> (f32, f32) {
+        // Ordering by value instead of by the sign of the direction also
+        // works for negative zero, where the inverse is -inf
+        let t0 = (min - orig) * inv_dir;
+        let t1 = (max - orig) * inv_dir;
+        (t0.min(t1), t0.max(t1))
     }
 }
@@ -14,6 +14,40 @@ use rand::distributions::Uniform;
 use rand::prelude::*;
 use rayon::prelude::*;
 
+pub fn reflected_ray(r: &Ray, hit: &HitRecord, normal: &Vec3) -> Ray {
+    let direction = r.dir.reflect(normal).unit_vector();
+    let origin = &hit.p + &direction * 0.01;
+    Ray::from_values(&origin, &direction)
+}
+
+pub fn refracted_ray(r: &Ray, hit: &HitRecord, normal: &Vec3) -> Ray {
+    let icd = r.dir.unit_vector();
+    let mut n = normal.clone();
+    let mut cosi = icd.dot(&n).clamp(-1., 1.);
+    let eta = if cosi < 0. {
+        // We are outside the surface, we want cos(theta)
+        // to be positive
+        cosi = -cosi;
+        1. / hit.material.refraction()
+    } else {
+        // We are inside the surface, cos(theta) is
+        // already positive but reverse normal direction
+        n = -normal;
+        hit.material.refraction() / 1.
+    };
+
+    let k = 1. - eta * eta * (1. - cosi * cosi);
+    let direction = if k < 0. {
+        r.dir.reflect(normal).unit_vector()
+    } else {
+        let v = eta * &icd + (eta * cosi - k.sqrt()) * n;
+        v.unit_vector()
+    };
+
+    let origin = &hit.p + &direction * 0.01;
+    Ray::from_values(&origin, &direction)
+}
+
 pub fn reflection<H, M, R>(
     r: &Ray,
     scene: &Scene,
@@ -30,9 +64,7 @@ where
     M: LightModel,
     R: Rng,
 {
-    let direction = r.dir.reflect(&normal).unit_vector();
-    let origin = &hit.p + &direction * 0.01;
-    let reflect_ray = Ray::from_values(&origin, &direction);
+    let reflect_ray = reflected_ray(r, hit, normal);
     ray_color(
         &reflect_ray,
         scene,
@@ -60,31 +92,7 @@ where
     M: LightModel,
     R: Rng,
 {
-    let icd = r.dir.unit_vector();
-    let mut n = normal.clone();
-    let mut cosi = icd.dot(&n).clamp(-1., 1.);
-    let eta = if cosi < 0. {
-        // We are outside the surface, we want cos(theta)
-        // to be positive
-        cosi = -cosi;
-        1. / hit.material.refraction()
-    } else {
-        // We are inside the surface, cos(theta) is
-        // already positive but reverse normal direction
-        n = -normal;
-        hit.material.refraction() / 1.
-    };
-
-    let k = 1. - eta * eta * (1. - cosi * cosi);
-    let direction = if k < 0. {
-        r.dir.reflect(&normal).unit_vector()
-    } else {
-        let v = eta * &icd + (eta * cosi - k.sqrt()) * n;
-        v.unit_vector()
-    };
-
-    let origin = &hit.p + &direction * 0.01;
-    let refract_ray = Ray::from_values(&origin, &direction);
+    let refract_ray = refracted_ray(r, hit, normal);
     ray_color(
         &refract_ray,
         scene,
@@ -142,7 +150,7 @@ where
         + refracted_color
 }
 
-fn fresnel(ior: f32, normal: &Vec3, icd: &Vec3) -> f32 {
+pub fn fresnel(ior: f32, normal: &Vec3, icd: &Vec3) -> f32 {
     let eta_i;
     let eta_t;
 
@@ -410,8 +418,13 @@ pub fn render_supersampled<H, M, R>(
             }
 
             // Convert color to image color
+            let color = color * scene.camera.exposure;
             let mut int_color = [0u8; 3];
-            get_int_color(&mut int_color, &color);
+            get_int_color(
+                &mut int_color,
+                &color,
+                config.srgb_output,
+            );
             slice.copy_from_slice(&int_color);
         });
 }
@@ -1,12 +1,13 @@
+use std::sync::Arc;
 use serde::Deserialize;
 
 use crate::{
     utils::helpers::parse_color,
     camera::Camera,
-    light::structs::Lights,
+    light::{structs::Lights, parse_environment, Environment},
     surface::surfaces::parse_world,
     math::Color,
-    ray::HittableList
+    ray::{HittableList, Ray},
 };
 
 #[derive(Deserialize)]
@@ -15,6 +16,10 @@ pub struct Scene {
     pub output_file: String,
     #[serde(deserialize_with = "parse_color")]
     pub background_color: Color,
+    // Replaces the background color and lights the scene
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_environment")]
+    pub environment: Option<Arc<Environment>>,
     pub camera: Camera,
     pub lights: Lights,
     #[serde(default)]
@@ -22,3 +27,13 @@ pub struct Scene {
     #[serde(deserialize_with = "parse_world")]
     pub world: HittableList,
 }
+
+impl Scene {
+    /// What rays see that miss everything
+    pub fn background(&self, r: &Ray) -> Color {
+        match &self.environment {
+            Some(environment) => environment.radiance(&r.dir),
+            None => self.background_color.clone(),
+        }
+    }
+}
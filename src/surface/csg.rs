@@ -0,0 +1,155 @@
+use serde::{Deserialize, Deserializer, de};
+use crate::{
+    ray::{Hittable, HitRecord, Ray},
+    kdtree::AABB,
+};
+use super::surfaces::Surface;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// Boolean combination of closed surfaces. For a difference, all other
+/// children get cut out of the first one.
+pub struct Csg {
+    operation: CsgOperation,
+    children: Vec<Box<dyn Hittable>>,
+}
+
+impl Csg {
+    fn is_inside(&self, inside: &[bool]) -> bool {
+        use CsgOperation::*;
+
+        match self.operation {
+            Union => inside.iter().any(|&i| i),
+            Intersection => inside.iter().all(|&i| i),
+            Difference => inside[0] && !inside[1..].iter().any(|&i| i),
+        }
+    }
+
+    /// The points along the ray where it enters or leaves the combined
+    /// solid, ordered by t
+    fn boundaries(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Vec<HitRecord> {
+        // Collect the entries and exits of all children. Whether we
+        // start inside a child follows from its first crossing, so we
+        // can't stop looking at t_max here.
+        let mut inside = vec![false; self.children.len()];
+        let mut events = vec![];
+        for (index, child) in self.children.iter().enumerate() {
+            let hits = child.hits(r, t_min, f32::INFINITY);
+            inside[index] = hits.first().is_some_and(|hit| !hit.front_face);
+            events.extend(hits.into_iter().map(|hit| (index, hit)));
+        }
+        events.sort_by(|(_, a), (_, b)| a.t.total_cmp(&b.t));
+
+        // Only crossings that change the combined state are part of
+        // the surface
+        let mut boundaries = vec![];
+        let mut was_inside = self.is_inside(&inside);
+        for (index, mut hit) in events {
+            if hit.t > t_max {
+                break;
+            }
+
+            inside[index] = hit.front_face;
+            let is_inside = self.is_inside(&inside);
+            if is_inside == was_inside {
+                continue;
+            }
+            was_inside = is_inside;
+
+            // Cut out children bound the result from their inside
+            if self.operation == CsgOperation::Difference && index > 0 {
+                hit.normal = -&hit.normal;
+                hit.front_face = !hit.front_face;
+            }
+            boundaries.push(hit);
+        }
+
+        boundaries
+    }
+}
+
+impl Hittable for Csg {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord> {
+        self.boundaries(r, t_min, t_max).into_iter().next()
+    }
+
+    fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.hit(r, t_min, t_max).is_some()
+    }
+
+    fn bound(&self) -> AABB {
+        use CsgOperation::*;
+
+        match self.operation {
+            Union => {
+                let mut aabb = AABB::empty();
+                for child in &self.children {
+                    aabb.merge(&child.bound());
+                }
+                aabb
+            }
+            Intersection => {
+                let mut aabb = AABB::infinite();
+                for child in &self.children {
+                    let bound = child.bound();
+                    aabb.min.assign_max(&bound.min);
+                    aabb.max.assign_min(&bound.max);
+                }
+                aabb
+            }
+            Difference => self.children[0].bound(),
+        }
+    }
+
+    fn hits(&self, r: &Ray, t_min: f32, t_max: f32) -> Vec<HitRecord> {
+        self.boundaries(r, t_min, t_max)
+    }
+}
+
+pub fn parse_csg<'de, D>(deserializer: D) -> Result<Csg, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseCsg {
+        #[serde(rename = "@op")]
+        pub op: CsgOperation,
+        #[serde(default)]
+        #[serde(rename = "$value")]
+        pub children: Vec<Surface>,
+    }
+
+    let BaseCsg {
+        op,
+        children,
+    } = BaseCsg::deserialize(deserializer)?;
+
+    let children: Vec<_> = children
+        .into_iter()
+        .filter_map(Surface::into_hittable)
+        .collect();
+    if children.len() < 2 {
+        return Err(de::Error::custom("csg needs at least two surfaces"));
+    }
+
+    Ok(Csg {
+        operation: op,
+        children,
+    })
+}
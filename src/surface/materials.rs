@@ -1,8 +1,11 @@
 use std::{sync::Arc, path::PathBuf};
 use serde::{Deserialize, Deserializer, de};
 use crate::utils::helpers::parse_color;
+use crate::io::ColorSpace;
 use crate::math::Color;
 use crate::ray::{Ray, HitRecord};
+use super::mipmap::{bilinear, build_pyramid, MipLevel, TextureMipmapped};
+use super::procedural::{BaseChecker, BaseGradient, BaseNoise, Pattern};
 
 pub trait MaterialParameters {
     fn phong(&self) -> &Phong;
@@ -12,10 +15,32 @@ pub trait MaterialParameters {
     fn transmittance(&self) -> f32;
 
     fn refraction(&self) -> f32;
+
+    /// Radiance the surface gives off by itself, on both sides
+    fn emission(&self) -> Option<&Color> {
+        None
+    }
 }
 
 pub trait ColorLookup: Send + Sync {
     fn color(&self, ray: &Ray, hit: &HitRecord) -> Color;
+
+    /// The set of texture coords the lookup reads, normal maps need
+    /// the tangents of that set
+    fn uv_set(&self) -> usize {
+        0
+    }
+
+    /// Opacity at the hit, meshes are cut out where it's below one half
+    fn alpha(&self, _ray: &Ray, _hit: &HitRecord) -> f32 {
+        1.
+    }
+
+    /// Whether the alpha can be anything but one, so opaque materials
+    /// don't need the lookups
+    fn has_alpha(&self) -> bool {
+        false
+    }
 }
 
 pub trait Material:
@@ -32,6 +57,9 @@ pub struct Solid {
     pub reflectance: Reflectance,
     pub transmittance: Transmittance,
     pub refraction: Refraction,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_emission")]
+    pub emission: Option<Color>,
 }
 
 impl MaterialParameters for Solid {
@@ -50,11 +78,18 @@ impl MaterialParameters for Solid {
     fn refraction(&self) -> f32 {
         self.refraction.iof
     }
+
+    fn emission(&self) -> Option<&Color> {
+        self.emission.as_ref()
+    }
 }
 
 impl ColorLookup for Solid {
-    fn color(&self, _ray: &Ray, _hit: &HitRecord) -> Color {
-        self.color.clone()
+    fn color(&self, _ray: &Ray, hit: &HitRecord) -> Color {
+        match &hit.vertex_color {
+            Some(vertex_color) => &self.color * vertex_color,
+            None => self.color.clone(),
+        }
     }
 }
 
@@ -64,6 +99,7 @@ pub struct Textured {
     pub reflectance: Reflectance,
     pub transmittance: Transmittance,
     pub refraction: Refraction,
+    pub emission: Option<Color>,
 }
 
 impl MaterialParameters for Textured {
@@ -82,12 +118,90 @@ impl MaterialParameters for Textured {
     fn refraction(&self) -> f32 {
         self.refraction.iof
     }
+
+    fn emission(&self) -> Option<&Color> {
+        self.emission.as_ref()
+    }
 }
 
 impl ColorLookup for Textured {
+    fn color(&self, ray: &Ray, hit: &HitRecord) -> Color {
+        let color = self.texture.color(ray, hit);
+        match &hit.vertex_color {
+            Some(vertex_color) => color * vertex_color,
+            None => color,
+        }
+    }
+
+    fn alpha(&self, ray: &Ray, hit: &HitRecord) -> f32 {
+        self.texture.alpha(ray, hit)
+    }
+
+    fn has_alpha(&self) -> bool {
+        self.texture.has_alpha()
+    }
+}
+
+/// Reads a texture with the second set of texture coords of a mesh,
+/// falling back to the first set for surfaces that don't have one
+pub struct SecondUvSet {
+    pub texture: Box<dyn ColorLookup>,
+}
+
+impl SecondUvSet {
+    /// The hit as the texture sees it
+    fn second_hit(&self, hit: &HitRecord) -> Option<HitRecord> {
+        let tex_coords = hit.second_tex_coords.as_ref()?;
+        let mut hit = hit.clone();
+        hit.tex_coords = tex_coords.clone();
+        hit.tex_derivatives = hit.second_tex_derivatives.take();
+        Some(hit)
+    }
+}
+
+impl ColorLookup for SecondUvSet {
+    fn color(&self, ray: &Ray, hit: &HitRecord) -> Color {
+        match self.second_hit(hit) {
+            Some(hit) => self.texture.color(ray, &hit),
+            None => self.texture.color(ray, hit),
+        }
+    }
+
+    fn uv_set(&self) -> usize {
+        1
+    }
+
+    fn alpha(&self, ray: &Ray, hit: &HitRecord) -> f32 {
+        match self.second_hit(hit) {
+            Some(hit) => self.texture.alpha(ray, &hit),
+            None => self.texture.alpha(ray, hit),
+        }
+    }
+
+    fn has_alpha(&self) -> bool {
+        self.texture.has_alpha()
+    }
+}
+
+/// A texture with an alpha channel, which is looked up like a texture
+/// of its own
+pub struct TextureAlpha {
+    pub texture: Box<dyn ColorLookup>,
+    pub alpha: Box<dyn ColorLookup>,
+}
+
+impl ColorLookup for TextureAlpha {
     fn color(&self, ray: &Ray, hit: &HitRecord) -> Color {
         self.texture.color(ray, hit)
     }
+
+    fn alpha(&self, ray: &Ray, hit: &HitRecord) -> f32 {
+        self.alpha.color(ray, hit).x
+    }
+
+    fn has_alpha(&self) -> bool {
+        true
+    }
 }
 
 pub struct TextureNearest {
@@ -121,38 +235,8 @@ pub struct TextureLinear {
 impl ColorLookup for TextureLinear {
     fn color(&self, _ray: &Ray, hit: &HitRecord) -> Color {
         let tex_coords = &hit.tex_coords;
-        let w_int = self.width as isize;
-        let h_int = self.height as isize;
-
-        // Subtracting half a pixel from the coordinates, so that the
-        // textures line up with the output produced by nearest
-        // neighbour, otherwise it's one pixel off when compared to
-        // nearest
-        let u = tex_coords.x * w_int as f32 - 0.5;
-        let v = tex_coords.y * h_int as f32 - 0.5;
-        let s = u.fract();
-        let t = v.fract();
-
-        let c0_u = (u.floor() as isize).rem_euclid(w_int);
-        let c0_v = (v.floor() as isize).rem_euclid(h_int);
-        let c0 = &self.pixels[(c0_v * w_int + c0_u) as usize];
-
-        let c1_u = (u.ceil() as isize).rem_euclid(w_int);
-        let c1_v = (v.floor() as isize).rem_euclid(h_int);
-        let c1 = &self.pixels[(c1_v * w_int + c1_u) as usize];
-
-        let c2_u = (u.floor() as isize).rem_euclid(w_int);
-        let c2_v = (v.ceil() as isize).rem_euclid(h_int);
-        let c2 = &self.pixels[(c2_v * w_int + c2_u) as usize];
-
-        let c3_u = (u.ceil() as isize).rem_euclid(w_int);
-        let c3_v = (v.ceil() as isize).rem_euclid(h_int);
-        let c3 = &self.pixels[(c3_v * w_int + c3_u) as usize];
-
-        let i_0 = (1. - s) * c0 + s * c1;
-        let i_1 = (1. - s) * c2 + s * c3;
-
-        (1. - t) * i_0 + t * i_1
+        bilinear(self.width, self.height, &self.pixels, tex_coords.x,
+            tex_coords.y)
     }
 }
 
@@ -242,11 +326,53 @@ pub struct Texture {
     pub width: u32,
     pub height: u32,
     pub pixels: Vec<Color>,
+    // Downsampled copies of the image, from half the size down to one
+    // pixel
+    pub mip_levels: Vec<MipLevel>,
+    // Only kept for images with transparent pixels
+    pub alpha: Option<Vec<f32>>,
+}
+
+impl Texture {
+    pub fn new(width: u32, height: u32, pixels: Vec<Color>) -> Self {
+        let mip_levels = build_pyramid(width, height, &pixels);
+        Texture {
+            width,
+            height,
+            pixels,
+            mip_levels,
+            alpha: None,
+        }
+    }
 }
 
 pub fn parse_material<'de, D>(
     deserializer: D,
 ) -> Result<Arc<dyn Material>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_material_option(deserializer)?
+        .ok_or_else(|| de::Error::custom("missing material"))
+}
+
+/// An optional `<emission r g b/>`, the radiance of glowing surfaces
+pub fn parse_emission<'de, D>(
+    deserializer: D,
+) -> Result<Option<Color>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Emission(#[serde(deserialize_with = "parse_color")] Color);
+
+    Ok(Option::<Emission>::deserialize(deserializer)?.map(|e| e.0))
+}
+
+/// Like parse_material, for elements where the material is optional
+pub fn parse_material_option<'de, D>(
+    deserializer: D,
+) -> Result<Option<Arc<dyn Material>>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -263,12 +389,14 @@ where
     }
     use BaseMaterial::*;
 
-    let m = BaseMaterial::deserialize(deserializer)?;
-    Ok(match m {
-        BaseSolid(m) => Arc::new(m),
-        BaseTextured(m) => Arc::new(m),
-        BaseSphereMap(m) => Arc::new(m),
-    })
+    let m = Option::<BaseMaterial>::deserialize(deserializer)?;
+    Ok(m.map(|m| -> Arc<dyn Material> {
+        match m {
+            BaseSolid(m) => Arc::new(m),
+            BaseTextured(m) => Arc::new(m),
+            BaseSphereMap(m) => Arc::new(m),
+        }
+    }))
 }
 
 pub fn parse_material_textured<'de, D>(
@@ -277,107 +405,201 @@ pub fn parse_material_textured<'de, D>(
 where
     D: Deserializer<'de>,
 {
+    // An image or one of the procedural textures
     #[derive(Deserialize)]
     pub struct BaseTextured {
-        #[serde(deserialize_with = "parse_texture")]
-        pub texture: Texture,
+        #[serde(default)]
+        #[serde(deserialize_with = "parse_texture_object")]
+        pub texture: Option<Box<dyn ColorLookup>>,
+        pub checker: Option<BaseChecker>,
+        pub noise: Option<BaseNoise>,
+        pub marble: Option<BaseNoise>,
+        pub wood: Option<BaseNoise>,
+        pub gradient: Option<BaseGradient>,
         pub phong: Phong,
         pub reflectance: Reflectance,
         pub transmittance: Transmittance,
         pub refraction: Refraction,
+        #[serde(default)]
+        #[serde(deserialize_with = "parse_emission")]
+        pub emission: Option<Color>,
     }
 
     let BaseTextured {
         texture,
+        checker,
+        noise,
+        marble,
+        wood,
+        gradient,
         phong,
         reflectance,
         transmittance,
         refraction,
+        emission,
     } = BaseTextured::deserialize(deserializer)?;
 
-    use crate::utils::config::TextureInterpolation::*;
-    let config = crate::CONFIG.get().unwrap();
-    let t: Box<dyn ColorLookup> = match config.texture_interpolation {
-        Nearest => {
-            let Texture {
-                width,
-                height,
-                pixels,
-            } = texture;
-            Box::new(TextureNearest {
-                width,
-                height,
-                pixels,
-            })
-        }
-        Linear => {
-            let Texture {
-                width,
-                height,
-                pixels,
-            } = texture;
-            Box::new(TextureLinear {
-                width,
-                height,
-                pixels,
-            })
-        }
-    };
+    let patterns = [
+        checker.map(Pattern::Checker),
+        noise.map(Pattern::Noise),
+        marble.map(Pattern::Marble),
+        wood.map(Pattern::Wood),
+        gradient.map(Pattern::Gradient),
+    ];
+    let mut textures = texture.into_iter().map(Ok).chain(
+        patterns.into_iter().flatten().map(Pattern::into_lookup),
+    );
+    let texture = textures
+        .next()
+        .ok_or_else(|| de::Error::missing_field("texture"))?
+        .map_err(de::Error::custom)?;
+    if textures.next().is_some() {
+        return Err(de::Error::custom("More than one texture"));
+    }
 
     Ok(Textured {
-        texture: t,
+        texture,
         phong,
         reflectance,
         transmittance,
         refraction,
+        emission,
     })
 }
 
+/// A color texture, decoded from sRGB unless it says otherwise
 pub fn parse_texture_object<'de, D>(
     deserializer: D,
 ) -> Result<Option<Box<dyn ColorLookup>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_texture_lookup(deserializer, ColorSpace::Srgb)
+}
+
+/// A normal map, its values are directions, so they are linear unless
+/// it says otherwise
+pub fn parse_normal_map<'de, D>(
+    deserializer: D,
+) -> Result<Option<Box<dyn ColorLookup>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_texture_lookup(deserializer, ColorSpace::Linear)
+}
+
+fn parse_texture_lookup<'de, D>(
+    deserializer: D,
+    default_color_space: ColorSpace,
+) -> Result<Option<Box<dyn ColorLookup>>, D::Error>
 where
     D: Deserializer<'de>,
 {
     use crate::io::load_texture;
 
+    // `uv` picks the set of texture coords of meshes, `color_space`
+    // is either "srgb" or "linear"
     #[derive(Deserialize)]
     pub struct BaseTexture {
         #[serde(rename = "@name")]
         pub name: String,
+        #[serde(rename = "@uv")]
+        #[serde(default)]
+        pub uv: usize,
+        #[serde(rename = "@color_space")]
+        pub color_space: Option<ColorSpace>,
     }
 
-    let name;
-    let t = Option::<BaseTexture>::deserialize(deserializer)?;
-    match t {
-        Some(t) => name = t.name,
+    let t = match Option::<BaseTexture>::deserialize(deserializer)? {
+        Some(t) => t,
         None => return Ok(None),
+    };
+    if t.uv > 1 {
+        return Err(de::Error::custom(format!("Unknown uv set {}", t.uv)));
     }
 
     let mut path = PathBuf::new();
     path.push(r"../scenes");
-    path.push(&name);
+    path.push(&t.name);
+
+    let color_space = t.color_space.unwrap_or(default_color_space);
+    let texture = load_texture(&path, color_space)
+        .map_err(|e| de::Error::custom(e.to_string()))?;
+
+    Ok(Some(bind_uv_set(interpolated_texture(texture), t.uv)))
+}
 
+/// Makes the texture read the given set of texture coords
+pub fn bind_uv_set(
+    texture: Box<dyn ColorLookup>,
+    uv_set: usize,
+) -> Box<dyn ColorLookup> {
+    match uv_set {
+        0 => texture,
+        _ => Box::new(SecondUvSet {
+            texture,
+        }),
+    }
+}
+
+/// Looks up the texture with the interpolation set in the config
+pub fn interpolated_texture(texture: Texture) -> Box<dyn ColorLookup> {
     let Texture {
         width,
         height,
         pixels,
-    } = load_texture(&path)
-        .map_err(|e| de::Error::custom(e.to_string()))?;
+        mip_levels,
+        alpha,
+    } = texture;
+
+    // The alpha channel gets filtered the same way as the colors
+    if let Some(alpha) = alpha {
+        let alpha = alpha
+            .iter()
+            .map(|&a| Color::from_values(a, a, a))
+            .collect();
+        let texture = Texture {
+            width,
+            height,
+            pixels,
+            mip_levels,
+            alpha: None,
+        };
+        return Box::new(TextureAlpha {
+            texture: interpolated_texture(texture),
+            alpha: interpolated_texture(Texture::new(width, height, alpha)),
+        });
+    }
 
     use crate::utils::config::TextureInterpolation::*;
     let config = crate::CONFIG.get().unwrap();
     match config.texture_interpolation {
-        Nearest => Ok(Some(Box::new(TextureNearest {
+        Nearest => Box::new(TextureNearest {
             width,
             height,
             pixels,
-        }))),
-        Linear => Ok(Some(Box::new(TextureLinear {
+        }),
+        Linear => Box::new(TextureLinear {
             width,
             height,
             pixels,
-        }))),
+        }),
+        Trilinear | Anisotropic => {
+            let full = MipLevel {
+                width,
+                height,
+                pixels,
+            };
+            let mut levels = vec![full];
+            levels.extend(mip_levels);
+            Box::new(TextureMipmapped {
+                levels,
+                anisotropic: matches!(
+                    config.texture_interpolation,
+                    Anisotropic
+                ),
+            })
+        }
     }
 }
 
@@ -395,6 +617,9 @@ where
         pub reflectance: Reflectance,
         pub transmittance: Transmittance,
         pub refraction: Refraction,
+        #[serde(default)]
+        #[serde(deserialize_with = "parse_emission")]
+        pub emission: Option<Color>,
     }
 
     let BaseTextured {
@@ -403,11 +628,13 @@ where
         reflectance,
         transmittance,
         refraction,
+        emission,
     } = BaseTextured::deserialize(deserializer)?;
     let Texture {
         width,
         height,
         pixels,
+        ..
     } = texture;
     let t: Box<dyn ColorLookup> = Box::new(TextureSphere {
         width,
@@ -420,6 +647,7 @@ where
         reflectance,
         transmittance,
         refraction,
+        emission,
     })
 }
 
@@ -435,13 +663,16 @@ where
     pub struct BaseTexture {
         #[serde(rename = "@name")]
         pub name: String,
+        #[serde(rename = "@color_space")]
+        pub color_space: Option<ColorSpace>,
     }
 
     let t = BaseTexture::deserialize(deserializer)?;
     let mut path = PathBuf::new();
     path.push(r"../scenes");
     path.push(&t.name);
-    let texture = load_texture(&path)
+    let color_space = t.color_space.unwrap_or(ColorSpace::Srgb);
+    let texture = load_texture(&path, color_space)
         .map_err(|e| de::Error::custom(e.to_string()))?;
 
     Ok(texture)
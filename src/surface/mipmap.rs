@@ -0,0 +1,213 @@
+use crate::math::{Color, Vec3};
+use crate::ray::{Ray, HitRecord};
+use super::materials::ColorLookup;
+
+// Footprints longer than this many times their width get sampled with
+// a coarser level instead of more samples
+const MAX_ANISOTROPY: f32 = 8.;
+
+/// One level of a mip pyramid, each level has half the size of the one
+/// above it
+pub struct MipLevel {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>,
+}
+
+impl MipLevel {
+    /// Bilinear lookup, wrapping around at the borders
+    pub fn bilinear(&self, tex_u: f32, tex_v: f32) -> Color {
+        bilinear(self.width, self.height, &self.pixels, tex_u, tex_v)
+    }
+}
+
+/// Averages the pixels of an image that fall into each pixel of the next
+/// smaller level, odd sizes get rounded down
+fn downsample(width: u32, height: u32, pixels: &[Color]) -> MipLevel {
+    let (src_width, src_height) = (width, height);
+    let width = (src_width / 2).max(1);
+    let height = (src_height / 2).max(1);
+
+    let mut level = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        let y0 = y * src_height / height;
+        let y1 = ((y + 1) * src_height / height).max(y0 + 1);
+        for x in 0..width {
+            let x0 = x * src_width / width;
+            let x1 = ((x + 1) * src_width / width).max(x0 + 1);
+
+            let mut sum = Color::new();
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    sum += &pixels[(sy * src_width + sx) as usize];
+                }
+            }
+            level.push(sum / ((y1 - y0) * (x1 - x0)) as f32);
+        }
+    }
+
+    MipLevel {
+        width,
+        height,
+        pixels: level,
+    }
+}
+
+/// Bilinear lookup of an image at texture coords, wrapping around at
+/// the borders
+pub fn bilinear(
+    width: u32,
+    height: u32,
+    pixels: &[Color],
+    tex_u: f32,
+    tex_v: f32,
+) -> Color {
+    let w_int = width as isize;
+    let h_int = height as isize;
+
+    // Subtracting half a pixel from the coordinates, so that the
+    // textures line up with the output produced by nearest
+    // neighbour, otherwise it's one pixel off when compared to
+    // nearest
+    let u = tex_u * w_int as f32 - 0.5;
+    let v = tex_v * h_int as f32 - 0.5;
+    let s = u.fract();
+    let t = v.fract();
+
+    let c0_u = (u.floor() as isize).rem_euclid(w_int);
+    let c0_v = (v.floor() as isize).rem_euclid(h_int);
+    let c0 = &pixels[(c0_v * w_int + c0_u) as usize];
+
+    let c1_u = (u.ceil() as isize).rem_euclid(w_int);
+    let c1_v = (v.floor() as isize).rem_euclid(h_int);
+    let c1 = &pixels[(c1_v * w_int + c1_u) as usize];
+
+    let c2_u = (u.floor() as isize).rem_euclid(w_int);
+    let c2_v = (v.ceil() as isize).rem_euclid(h_int);
+    let c2 = &pixels[(c2_v * w_int + c2_u) as usize];
+
+    let c3_u = (u.ceil() as isize).rem_euclid(w_int);
+    let c3_v = (v.ceil() as isize).rem_euclid(h_int);
+    let c3 = &pixels[(c3_v * w_int + c3_u) as usize];
+
+    let i_0 = (1. - s) * c0 + s * c1;
+    let i_1 = (1. - s) * c2 + s * c3;
+
+    (1. - t) * i_0 + t * i_1
+}
+
+/// Like bilinear, for images that don't repeat: the coords stay half a
+/// pixel away from the edges, so the lookup doesn't wrap around to the
+/// other side
+pub fn bilinear_clamped(
+    width: u32,
+    height: u32,
+    pixels: &[Color],
+    tex_u: f32,
+    tex_v: f32,
+) -> Color {
+    let half_u = 0.5 / width as f32;
+    let half_v = 0.5 / height as f32;
+    let u = tex_u.clamp(half_u, 1. - half_u);
+    let v = tex_v.clamp(half_v, 1. - half_v);
+    bilinear(width, height, pixels, u, v)
+}
+
+/// The levels below the full image, down to a single pixel, empty for
+/// images that are a single pixel already
+pub fn build_pyramid(
+    width: u32,
+    height: u32,
+    pixels: &[Color],
+) -> Vec<MipLevel> {
+    if width <= 1 && height <= 1 {
+        return vec![];
+    }
+
+    let mut levels = vec![downsample(width, height, pixels)];
+    loop {
+        let last = levels.last().unwrap();
+        if last.width == 1 && last.height == 1 {
+            break;
+        }
+        let next = downsample(last.width, last.height, &last.pixels);
+        levels.push(next);
+    }
+
+    levels
+}
+
+/// A texture filtered with the footprint of the pixel in texture space.
+/// Trilinear filtering blends the two levels closest to the size of
+/// the footprint, anisotropic filtering takes several of those samples
+/// along the longer axis of the footprint, so surfaces seen at grazing
+/// angles don't get blurry. Hits without a footprint read the full
+/// image, like linear interpolation.
+pub struct TextureMipmapped {
+    // The full image is the first level
+    pub levels: Vec<MipLevel>,
+    pub anisotropic: bool,
+}
+
+impl TextureMipmapped {
+    /// Blends the two levels around the one where a pixel has the size
+    /// of the footprint, `width` is given in pixels of the full image
+    fn trilinear(&self, tex_u: f32, tex_v: f32, width: f32) -> Color {
+        let max_level = (self.levels.len() - 1) as f32;
+        let level = width.max(1.).log2().min(max_level);
+        let lower = level.floor() as usize;
+        let fraction = level - lower as f32;
+
+        let color = self.levels[lower].bilinear(tex_u, tex_v);
+        if fraction <= 0. {
+            return color;
+        }
+        let coarser = self.levels[lower + 1].bilinear(tex_u, tex_v);
+        (1. - fraction) * color + fraction * coarser
+    }
+}
+
+impl ColorLookup for TextureMipmapped {
+    fn color(&self, _ray: &Ray, hit: &HitRecord) -> Color {
+        let tex_coords = &hit.tex_coords;
+        let (dx, dy) = match &hit.tex_derivatives {
+            Some(derivatives) => derivatives,
+            None => {
+                return self.levels[0].bilinear(tex_coords.x, tex_coords.y)
+            }
+        };
+
+        // Footprint axes in pixels of the full image
+        let full = &self.levels[0];
+        let (w, h) = (full.width as f32, full.height as f32);
+        let axis_x = Vec3::from_values(dx.x * w, dx.y * h, 0.);
+        let axis_y = Vec3::from_values(dy.x * w, dy.y * h, 0.);
+        let (len_x, len_y) = (axis_x.length(), axis_y.length());
+
+        if !self.anisotropic {
+            return self.trilinear(tex_coords.x, tex_coords.y, len_x.max(len_y));
+        }
+
+        let (major, major_len, minor_len) = if len_x >= len_y {
+            (dx, len_x, len_y)
+        } else {
+            (dy, len_y, len_x)
+        };
+        let minor_len = minor_len.max(major_len / MAX_ANISOTROPY);
+        let samples = (major_len / minor_len.max(f32::EPSILON))
+            .ceil()
+            .clamp(1., MAX_ANISOTROPY) as usize;
+
+        // Spread the samples evenly over the major axis, centered on
+        // the hit
+        let mut color = Color::new();
+        for i in 0..samples {
+            let offset = (i as f32 + 0.5) / samples as f32 - 0.5;
+            let u = tex_coords.x + offset * major.x;
+            let v = tex_coords.y + offset * major.y;
+            color += &self.trilinear(u, v, minor_len);
+        }
+
+        color / samples as f32
+    }
+}
@@ -1,7 +1,12 @@
 pub mod surfaces;
 pub mod materials;
+pub mod mipmap;
+pub mod procedural;
 pub mod transforms;
 pub mod julia;
+pub mod sdf;
+pub mod primitives;
+pub mod csg;
 
 pub use materials::{ColorLookup, Material, Phong, Texture};
 pub use transforms::Transform;
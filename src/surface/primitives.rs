@@ -0,0 +1,994 @@
+use serde::{Deserialize, Deserializer};
+use std::sync::Arc;
+use std::f32::consts::TAU;
+use crate::{
+    utils::helpers::parse_vec3,
+    math::{Point3, Vec3},
+    ray::{Hittable, HitRecord, Ray},
+    kdtree::AABB,
+};
+use super::{
+    materials::{parse_material, Material},
+    transforms::Transform,
+};
+
+const EPSILON: f32 = 1e-7;
+
+/// Analytic shape in its own object space. `Primitive` takes care of
+/// the transform and the material.
+pub trait Shape: Send + Sync {
+    /// Returns t, the outward normal and the texture coordinates of the
+    /// closest intersection in range
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3, Vec3)>;
+
+    /// How the point `p` with the outward `normal` moves with the
+    /// texture coords, its derivatives by u and v
+    fn tex_frame(&self, p: &Point3, normal: &Vec3) -> (Vec3, Vec3);
+
+    fn bound(&self) -> AABB;
+}
+
+pub struct Primitive<S: Shape> {
+    shape: S,
+    material: Arc<dyn Material>,
+    transform: Option<Transform>,
+}
+
+impl<S: Shape> Primitive<S> {
+    pub fn from_values(
+        shape: S,
+        material: Arc<dyn Material>,
+        transform: Option<Transform>,
+    ) -> Self {
+        Self {
+            shape,
+            material,
+            transform,
+        }
+    }
+
+    fn object_ray(&self, r: &Ray) -> Ray {
+        // Transform ray if we have transforms
+        self.transform.as_ref().map_or(r.clone(), |t| {
+            let origin = &t.world_to_object * &r.orig;
+            let direction = t.world_to_object.mul_dir(&r.dir);
+            Ray::from_values(&origin, &direction)
+        })
+    }
+}
+
+impl<S: Shape> Hittable for Primitive<S> {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord> {
+        let tr = self.object_ray(r);
+        let (t, object_normal, tex_coords) =
+            self.shape.intersect(&tr, t_min, t_max)?;
+
+        let outward_normal = self
+            .transform
+            .as_ref()
+            .map_or(object_normal.clone(), |t| {
+                t.normal_matrix.mul_dir(&object_normal)
+            });
+
+        let mut hit = HitRecord::from_values(
+            r,
+            r.at(t),
+            &outward_normal.unit_vector(),
+            t,
+            tex_coords,
+            self.material.clone(),
+        );
+        if self.transform.is_some() {
+            hit.object_p = Some(tr.at(t));
+        }
+        if r.differentials.is_some() {
+            let (dp_du, dp_dv) =
+                self.shape.tex_frame(&tr.at(t), &object_normal);
+            let (dp_du, dp_dv) = match &self.transform {
+                Some(t) => (
+                    t.object_to_world.mul_dir(&dp_du),
+                    t.object_to_world.mul_dir(&dp_dv),
+                ),
+                None => (dp_du, dp_dv),
+            };
+            hit.set_tex_derivatives(r, &dp_du, &dp_dv);
+        }
+        Some(hit)
+    }
+
+    fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        let tr = self.object_ray(r);
+        self.shape.intersect(&tr, t_min, t_max).is_some()
+    }
+
+    fn bound(&self) -> AABB {
+        let bound = self.shape.bound();
+        match self.transform {
+            Some(ref t) if bound.is_finite() => transformed_aabb(&bound, t),
+            _ => bound,
+        }
+    }
+}
+
+/// Bounds of the transformed corners of `aabb`
+pub fn transformed_aabb(aabb: &AABB, transform: &Transform) -> AABB {
+    let mut result = AABB::empty();
+    for i in 0..8 {
+        let corner = Point3::from_values(
+            if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+            if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+            if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+        );
+        let v = &transform.object_to_world * &corner;
+        result.min.assign_min(&v);
+        result.max.assign_max(&v);
+    }
+    result
+}
+
+/// Two axes spanning the plane with the given (unit) normal, used for
+/// the texture coordinates of flat shapes
+fn tangent_frame(normal: &Vec3) -> (Vec3, Vec3) {
+    let helper = if normal.x.abs() > 0.9 {
+        Vec3::from_values(0., 1., 0.)
+    } else {
+        Vec3::from_values(1., 0., 0.)
+    };
+    let tangent = helper.cross(normal).unit_vector();
+    let bitangent = normal.cross(&tangent);
+    (tangent, bitangent)
+}
+
+/// Intersection of a ray with the plane through `position`, None if
+/// the ray runs parallel to it
+fn plane_t(position: &Point3, normal: &Vec3, r: &Ray) -> Option<f32> {
+    let ndir = normal.dot(&r.dir);
+    if ndir.abs() < EPSILON {
+        return None;
+    }
+    Some(normal.dot(&(position - &r.orig)) / ndir)
+}
+
+pub struct InfinitePlane {
+    position: Point3,
+    normal: Vec3,
+    tangent: Vec3,
+    bitangent: Vec3,
+    tile: f32,
+}
+
+impl Shape for InfinitePlane {
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3, Vec3)> {
+        let t = plane_t(&self.position, &self.normal, r)?;
+        if t < t_min || t_max < t {
+            return None;
+        }
+
+        // Texture repeats every `tile` units
+        let local = r.at(t) - &self.position;
+        let u = local.dot(&self.tangent) / self.tile;
+        let v = local.dot(&self.bitangent) / self.tile;
+
+        Some((t, self.normal.clone(), Vec3::from_values(u, v, 1.)))
+    }
+
+    fn tex_frame(&self, _p: &Point3, _normal: &Vec3) -> (Vec3, Vec3) {
+        (self.tile * &self.tangent, self.tile * &self.bitangent)
+    }
+
+    fn bound(&self) -> AABB {
+        AABB::infinite()
+    }
+}
+
+pub struct Disk {
+    position: Point3,
+    normal: Vec3,
+    tangent: Vec3,
+    bitangent: Vec3,
+    radius: f32,
+}
+
+impl Shape for Disk {
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3, Vec3)> {
+        let t = plane_t(&self.position, &self.normal, r)?;
+        if t < t_min || t_max < t {
+            return None;
+        }
+
+        let local = r.at(t) - &self.position;
+        if local.length_squared() > self.radius * self.radius {
+            return None;
+        }
+
+        // The texture covers the square around the disk
+        let u = 0.5 + local.dot(&self.tangent) / (2. * self.radius);
+        let v = 0.5 + local.dot(&self.bitangent) / (2. * self.radius);
+
+        Some((t, self.normal.clone(), Vec3::from_values(u, v, 1.)))
+    }
+
+    fn tex_frame(&self, _p: &Point3, _normal: &Vec3) -> (Vec3, Vec3) {
+        let size = 2. * self.radius;
+        (size * &self.tangent, size * &self.bitangent)
+    }
+
+    fn bound(&self) -> AABB {
+        // The extent along an axis shrinks the more the disk faces it
+        let n = &self.normal;
+        let offset = Vec3::from_values(
+            self.radius * (1. - n.x * n.x).max(0.).sqrt(),
+            self.radius * (1. - n.y * n.y).max(0.).sqrt(),
+            self.radius * (1. - n.z * n.z).max(0.).sqrt(),
+        );
+
+        AABB {
+            min: &self.position - &offset,
+            max: &self.position + &offset,
+        }
+    }
+}
+
+pub struct Cuboid {
+    min: Point3,
+    max: Point3,
+}
+
+impl Shape for Cuboid {
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3, Vec3)> {
+        let min = [self.min.x, self.min.y, self.min.z];
+        let max = [self.max.x, self.max.y, self.max.z];
+        let orig = [r.orig.x, r.orig.y, r.orig.z];
+        let dir = [r.dir.x, r.dir.y, r.dir.z];
+
+        // Slab test, remembering which axis we enter and leave through
+        let mut t_near = f32::NEG_INFINITY;
+        let mut t_far = f32::INFINITY;
+        let mut near_axis = 0;
+        let mut far_axis = 0;
+        for axis in 0..3 {
+            let inv_dir = 1. / dir[axis];
+            let t0 = (min[axis] - orig[axis]) * inv_dir;
+            let t1 = (max[axis] - orig[axis]) * inv_dir;
+            let (t0, t1) = (t0.min(t1), t0.max(t1));
+            if t0 > t_near {
+                t_near = t0;
+                near_axis = axis;
+            }
+            if t1 < t_far {
+                t_far = t1;
+                far_axis = axis;
+            }
+        }
+        if t_near > t_far {
+            return None;
+        }
+
+        // Leaving through the far side if we start inside the box
+        let (t, axis) = if t_min <= t_near && t_near <= t_max {
+            (t_near, near_axis)
+        } else if t_min <= t_far && t_far <= t_max {
+            (t_far, far_axis)
+        } else {
+            return None;
+        };
+
+        let p = r.at(t);
+        let p = [p.x, p.y, p.z];
+        let center = 0.5 * (min[axis] + max[axis]);
+        let mut normal = [0.; 3];
+        normal[axis] = if p[axis] > center { 1. } else { -1. };
+
+        // Every face gets the whole texture, spanned by the two other
+        // axes
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let u = (p[a] - min[a]) / (max[a] - min[a]);
+        let v = (p[b] - min[b]) / (max[b] - min[b]);
+
+        Some((
+            t,
+            Vec3::from_values(normal[0], normal[1], normal[2]),
+            Vec3::from_values(u, v, 1.),
+        ))
+    }
+
+    fn tex_frame(&self, _p: &Point3, normal: &Vec3) -> (Vec3, Vec3) {
+        let axis = if normal.x != 0. {
+            0
+        } else if normal.y != 0. {
+            1
+        } else {
+            2
+        };
+        let size = &self.max - &self.min;
+        let size = [size.x, size.y, size.z];
+        let edge = |i: usize| {
+            let mut e = [0.; 3];
+            e[i] = size[i];
+            Vec3::from_values(e[0], e[1], e[2])
+        };
+        (edge((axis + 1) % 3), edge((axis + 2) % 3))
+    }
+
+    fn bound(&self) -> AABB {
+        AABB::new(self.min.clone(), self.max.clone())
+    }
+}
+
+pub struct Cylinder {
+    position: Point3,
+    radius: f32,
+    height: f32,
+    capped: bool,
+}
+
+impl Shape for Cylinder {
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3, Vec3)> {
+        // Centered at the position, with the axis along y
+        let o = &r.orig - &self.position;
+        let d = &r.dir;
+        let half = 0.5 * self.height;
+        let mut closest = t_max;
+        let mut result = None;
+
+        let a = d.x * d.x + d.z * d.z;
+        let b = 2. * (o.x * d.x + o.z * d.z);
+        let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            for t in [t0, t1] {
+                let p = &o + t * d;
+                if t < t_min || closest < t || p.y.abs() > half {
+                    continue;
+                }
+
+                let normal = Vec3::from_values(p.x, 0., p.z);
+                let u = 0.5 + p.x.atan2(p.z) / TAU;
+                let v = (p.y + half) / self.height;
+                closest = t;
+                result = Some((t, normal, Vec3::from_values(u, v, 1.)));
+                break;
+            }
+        }
+
+        if self.capped {
+            for y in [-half, half] {
+                if let Some(hit) =
+                    cap_hit(&o, d, y, self.radius, t_min, closest)
+                {
+                    closest = hit.0;
+                    result = Some(hit);
+                }
+            }
+        }
+
+        result
+    }
+
+    fn tex_frame(&self, p: &Point3, normal: &Vec3) -> (Vec3, Vec3) {
+        if is_cap(normal) {
+            return cap_frame(self.radius);
+        }
+        let p = p - &self.position;
+        (around_y(&p), Vec3::from_values(0., self.height, 0.))
+    }
+
+    fn bound(&self) -> AABB {
+        let offset = Vec3::from_values(
+            self.radius,
+            0.5 * self.height,
+            self.radius,
+        );
+
+        AABB {
+            min: &self.position - &offset,
+            max: &self.position + &offset,
+        }
+    }
+}
+
+pub struct Cone {
+    position: Point3,
+    radius: f32,
+    height: f32,
+    capped: bool,
+}
+
+impl Shape for Cone {
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3, Vec3)> {
+        // Base centered at the position, apex `height` above it
+        let o = &r.orig - &self.position;
+        let d = &r.dir;
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let w = self.height - o.y;
+        let mut closest = t_max;
+        let mut result = None;
+
+        // x^2 + z^2 = k^2 (height - y)^2
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = 2. * (o.x * d.x + o.z * d.z + k2 * w * d.y);
+        let c = o.x * o.x + o.z * o.z - k2 * w * w;
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            for t in [t0, t1] {
+                let p = &o + t * d;
+                if t < t_min
+                    || closest < t
+                    || p.y < 0.
+                    || p.y > self.height
+                {
+                    continue;
+                }
+
+                // Gradient of the implicit surface, undefined at the
+                // apex
+                let normal =
+                    Vec3::from_values(p.x, k2 * (self.height - p.y), p.z);
+                let normal = if normal.length_squared() > 0. {
+                    normal
+                } else {
+                    Vec3::from_values(0., 1., 0.)
+                };
+                let u = 0.5 + p.x.atan2(p.z) / TAU;
+                let v = p.y / self.height;
+                closest = t;
+                result = Some((t, normal, Vec3::from_values(u, v, 1.)));
+                break;
+            }
+        }
+
+        if self.capped {
+            if let Some(hit) = cap_hit(&o, d, 0., self.radius, t_min, closest)
+            {
+                result = Some(hit);
+            }
+        }
+
+        result
+    }
+
+    fn tex_frame(&self, p: &Point3, normal: &Vec3) -> (Vec3, Vec3) {
+        if is_cap(normal) {
+            return cap_frame(self.radius);
+        }
+
+        // Up the side towards the apex, the radius shrinks with y
+        let p = p - &self.position;
+        let rho = (p.x * p.x + p.z * p.z).sqrt();
+        let k = self.radius / self.height;
+        let dp_dy = if rho > 0. {
+            Vec3::from_values(-k * p.x / rho, 1., -k * p.z / rho)
+        } else {
+            Vec3::from_values(0., 1., 0.)
+        };
+        (around_y(&p), self.height * dp_dy)
+    }
+
+    fn bound(&self) -> AABB {
+        let offset = Vec3::from_values(self.radius, 0., self.radius);
+        let top = Vec3::from_values(0., self.height, 0.);
+
+        AABB {
+            min: &self.position - &offset,
+            max: &(&self.position + &offset) + &top,
+        }
+    }
+}
+
+pub struct Torus {
+    position: Point3,
+    major_radius: f32,
+    minor_radius: f32,
+}
+
+impl Shape for Torus {
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3, Vec3)> {
+        // Start the ray at the bounding box, the quartic loses a lot of
+        // precision for far away origins
+        let (t_start, _) = r.aabb_interval(&self.bound(), t_min, t_max)?;
+        let len = r.dir.length();
+        let d = &r.dir / len;
+        let o = r.at(t_start) - &self.position;
+
+        // Ring in the xz plane around the position:
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2)
+        let r2 = (self.major_radius * self.major_radius) as f64;
+        let e = o.dot(&o) as f64 + r2
+            - (self.minor_radius * self.minor_radius) as f64;
+        let n = o.dot(&d) as f64;
+        let dxz = (d.x * d.x + d.z * d.z) as f64;
+        let oxz = (o.x * d.x + o.z * d.z) as f64;
+        let oo = (o.x * o.x + o.z * o.z) as f64;
+        let coefficients = [
+            4. * n,
+            4. * n * n + 2. * e - 4. * r2 * dxz,
+            4. * n * e - 8. * r2 * oxz,
+            e * e - 4. * r2 * oo,
+        ];
+
+        let t = solve_quartic(coefficients)
+            .into_iter()
+            .filter(|&s| s >= 0.)
+            .map(|s| t_start + s as f32 / len)
+            .filter(|&t| t_min <= t && t <= t_max)
+            .min_by(|a, b| a.total_cmp(b))?;
+
+        let p = r.at(t) - &self.position;
+        let k = p.length_squared()
+            - self.major_radius * self.major_radius
+            - self.minor_radius * self.minor_radius;
+        let normal = Vec3::from_values(
+            p.x * k,
+            p.y * (k + 2. * self.major_radius * self.major_radius),
+            p.z * k,
+        );
+
+        // Around the ring and around the tube
+        let ring = (p.x * p.x + p.z * p.z).sqrt() - self.major_radius;
+        let u = 0.5 + p.x.atan2(p.z) / TAU;
+        let v = 0.5 + p.y.atan2(ring) / TAU;
+
+        Some((t, normal, Vec3::from_values(u, v, 1.)))
+    }
+
+    fn tex_frame(&self, p: &Point3, _normal: &Vec3) -> (Vec3, Vec3) {
+        // Around the tube, the derivative of the angle in the plane of
+        // the ring and y
+        let p = p - &self.position;
+        let rho = (p.x * p.x + p.z * p.z).sqrt().max(EPSILON);
+        let ring = rho - self.major_radius;
+        let dp_dv = Vec3::from_values(-p.y * p.x / rho, ring, -p.y * p.z / rho);
+        (around_y(&p), TAU * dp_dv)
+    }
+
+    fn bound(&self) -> AABB {
+        let extent = self.major_radius + self.minor_radius;
+        let offset =
+            Vec3::from_values(extent, self.minor_radius, extent);
+
+        AABB {
+            min: &self.position - &offset,
+            max: &self.position + &offset,
+        }
+    }
+}
+
+/// Disk of the given radius at height `y`, closing cylinders and cones
+fn cap_hit(
+    o: &Vec3,
+    d: &Vec3,
+    y: f32,
+    radius: f32,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, Vec3, Vec3)> {
+    if d.y.abs() < EPSILON {
+        return None;
+    }
+
+    let t = (y - o.y) / d.y;
+    let p = o + t * d;
+    if t < t_min || t_max < t || p.x * p.x + p.z * p.z > radius * radius {
+        return None;
+    }
+
+    // Pointing away from the body, down for the bottom cap and up for
+    // the top one
+    let normal_y = if y > 0. { 1. } else { -1. };
+    let u = 0.5 + p.x / (2. * radius);
+    let v = 0.5 + p.z / (2. * radius);
+
+    Some((
+        t,
+        Vec3::from_values(0., normal_y, 0.),
+        Vec3::from_values(u, v, 1.),
+    ))
+}
+
+/// Caps are the only flat parts of cylinders and cones, facing up or
+/// down
+fn is_cap(normal: &Vec3) -> bool {
+    normal.x == 0. && normal.z == 0.
+}
+
+/// Texture frame of the caps, see cap_hit
+fn cap_frame(radius: f32) -> (Vec3, Vec3) {
+    (
+        Vec3::from_values(2. * radius, 0., 0.),
+        Vec3::from_values(0., 0., 2. * radius),
+    )
+}
+
+/// Derivative by u of the point `p` (relative to the axis) for texture
+/// coords going once around the y axis
+fn around_y(p: &Vec3) -> Vec3 {
+    TAU * Vec3::from_values(p.z, 0., -p.x)
+}
+
+/// Real roots of a t^2 + b t + c in ascending order. Degenerates to the
+/// linear equation for a close to zero.
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    if a.abs() < EPSILON {
+        if b.abs() < EPSILON {
+            return None;
+        }
+        let t = -c / b;
+        return Some((t, t));
+    }
+
+    let discriminant = b * b - 4. * a * c;
+    if discriminant < 0. {
+        return None;
+    }
+
+    // Avoids the cancellation of the textbook formula
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let t0 = q / a;
+    let t1 = if q != 0. { c / q } else { t0 };
+
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+/// Real roots of x^3 + a x^2 + b x + c, using Cardano's formula
+fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // Substitute x = y - a/3 to eliminate the quadratic term:
+    // y^3 + 3p y + 2q = 0
+    let sq_a = a * a;
+    let p = (-sq_a / 3. + b) / 3.;
+    let q = (2. / 27. * a * sq_a - a * b / 3. + c) / 2.;
+    let cb_p = p * p * p;
+    let discriminant = q * q + cb_p;
+
+    let roots = if discriminant.abs() < 1e-12 {
+        if q.abs() < 1e-12 {
+            vec![0.]
+        } else {
+            let u = (-q).cbrt();
+            vec![2. * u, -u]
+        }
+    } else if discriminant < 0. {
+        // Three real roots
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1., 1.).acos() / 3.;
+        let t = 2. * (-p).sqrt();
+        let third = std::f64::consts::PI / 3.;
+        vec![t * phi.cos(), -t * (phi + third).cos(), -t * (phi - third).cos()]
+    } else {
+        let sqrt_d = discriminant.sqrt();
+        vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+    };
+
+    roots.into_iter().map(|y| y - a / 3.).collect()
+}
+
+/// Real roots of x^4 + a x^3 + b x^2 + c x + d, using Ferrari's method.
+/// Every root is polished with a few Newton steps, as the closed form
+/// is numerically rather unstable.
+fn solve_quartic([a, b, c, d]: [f64; 4]) -> Vec<f64> {
+    // Substitute x = y - a/4 to eliminate the cubic term:
+    // y^4 + p y^2 + q y + r = 0
+    let sq_a = a * a;
+    let p = -3. / 8. * sq_a + b;
+    let q = sq_a * a / 8. - a * b / 2. + c;
+    let r = -3. / 256. * sq_a * sq_a + sq_a * b / 16. - a * c / 4. + d;
+
+    let mut roots = if r.abs() < 1e-12 {
+        // No absolute term: y (y^3 + p y + q) = 0
+        let mut roots = solve_cubic(0., p, q);
+        roots.push(0.);
+        roots
+    } else {
+        // Take one root of the resolvent cubic to split the quartic
+        // into two quadratics
+        let z = solve_cubic(-p / 2., -r, r * p / 2. - q * q / 8.)[0];
+        let u = z * z - r;
+        let v = 2. * z - p;
+        if u < -1e-12 || v < -1e-12 {
+            return vec![];
+        }
+        let u = u.max(0.).sqrt();
+        let v = if q < 0. { -v.max(0.).sqrt() } else { v.max(0.).sqrt() };
+
+        let mut roots = vec![];
+        for (b, c) in [(v, z - u), (-v, z + u)] {
+            let discriminant = b * b - 4. * c;
+            if discriminant >= 0. {
+                let sqrt_d = discriminant.sqrt();
+                roots.push((-b - sqrt_d) / 2.);
+                roots.push((-b + sqrt_d) / 2.);
+            }
+        }
+        roots
+    };
+
+    for x in roots.iter_mut() {
+        *x -= a / 4.;
+        for _ in 0..2 {
+            let f = (((*x + a) * *x + b) * *x + c) * *x + d;
+            let df = ((4. * *x + 3. * a) * *x + 2. * b) * *x + c;
+            if df.abs() > 1e-12 {
+                *x -= f / df;
+            }
+        }
+    }
+
+    roots
+}
+
+pub fn parse_plane<'de, D>(
+    deserializer: D,
+) -> Result<Primitive<InfinitePlane>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BasePlane {
+        #[serde(rename = "@tile")]
+        #[serde(default)]
+        pub tile: Option<f32>,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub normal: Vec3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BasePlane {
+        tile,
+        position,
+        normal,
+        material,
+        transform,
+    } = BasePlane::deserialize(deserializer)?;
+
+    let normal = normal.unit_vector();
+    let (tangent, bitangent) = tangent_frame(&normal);
+    let plane = InfinitePlane {
+        position,
+        normal,
+        tangent,
+        bitangent,
+        tile: tile.unwrap_or(1.),
+    };
+
+    Ok(Primitive::from_values(plane, material, transform))
+}
+
+pub fn parse_disk<'de, D>(
+    deserializer: D,
+) -> Result<Primitive<Disk>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseDisk {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub normal: Vec3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseDisk {
+        radius,
+        position,
+        normal,
+        material,
+        transform,
+    } = BaseDisk::deserialize(deserializer)?;
+
+    let normal = normal.unit_vector();
+    let (tangent, bitangent) = tangent_frame(&normal);
+    let disk = Disk {
+        position,
+        normal,
+        tangent,
+        bitangent,
+        radius,
+    };
+
+    Ok(Primitive::from_values(disk, material, transform))
+}
+
+pub fn parse_box<'de, D>(
+    deserializer: D,
+) -> Result<Primitive<Cuboid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseBox {
+        #[serde(deserialize_with = "parse_vec3")]
+        pub min: Point3,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub max: Point3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseBox {
+        min,
+        max,
+        material,
+        transform,
+    } = BaseBox::deserialize(deserializer)?;
+
+    // Allow the corners in any order
+    let mut cuboid = Cuboid {
+        min: min.clone(),
+        max: min,
+    };
+    cuboid.min.assign_min(&max);
+    cuboid.max.assign_max(&max);
+
+    Ok(Primitive::from_values(cuboid, material, transform))
+}
+
+pub fn parse_cylinder<'de, D>(
+    deserializer: D,
+) -> Result<Primitive<Cylinder>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseCylinder {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(rename = "@height")]
+        pub height: f32,
+        #[serde(rename = "@capped")]
+        #[serde(default)]
+        pub capped: Option<bool>,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseCylinder {
+        radius,
+        height,
+        capped,
+        position,
+        material,
+        transform,
+    } = BaseCylinder::deserialize(deserializer)?;
+
+    let cylinder = Cylinder {
+        position,
+        radius,
+        height,
+        capped: capped.unwrap_or(true),
+    };
+
+    Ok(Primitive::from_values(cylinder, material, transform))
+}
+
+pub fn parse_cone<'de, D>(
+    deserializer: D,
+) -> Result<Primitive<Cone>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseCone {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(rename = "@height")]
+        pub height: f32,
+        #[serde(rename = "@capped")]
+        #[serde(default)]
+        pub capped: Option<bool>,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseCone {
+        radius,
+        height,
+        capped,
+        position,
+        material,
+        transform,
+    } = BaseCone::deserialize(deserializer)?;
+
+    let cone = Cone {
+        position,
+        radius,
+        height,
+        capped: capped.unwrap_or(true),
+    };
+
+    Ok(Primitive::from_values(cone, material, transform))
+}
+
+pub fn parse_torus<'de, D>(
+    deserializer: D,
+) -> Result<Primitive<Torus>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseTorus {
+        #[serde(rename = "@major_radius")]
+        pub major_radius: f32,
+        #[serde(rename = "@minor_radius")]
+        pub minor_radius: f32,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseTorus {
+        major_radius,
+        minor_radius,
+        position,
+        material,
+        transform,
+    } = BaseTorus::deserialize(deserializer)?;
+
+    let torus = Torus {
+        position,
+        major_radius,
+        minor_radius,
+    };
+
+    Ok(Primitive::from_values(torus, material, transform))
+}
@@ -0,0 +1,422 @@
+use serde::Deserialize;
+use crate::{
+    utils::helpers::{parse_color, parse_vec3},
+    math::{Color, Point3, Vec3},
+    ray::{HitRecord, Ray},
+};
+use super::materials::ColorLookup;
+
+// Ken Perlin's permutation of 0..256 for the gradient noise
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233,
+    7, 225, 140, 36, 103, 30, 69, 142, 8, 99, 37, 240, 21, 10,
+    23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252,
+    219, 203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87,
+    174, 20, 125, 136, 171, 168, 68, 175, 74, 165, 71, 134, 139, 48,
+    27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230,
+    220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25,
+    63, 161, 1, 216, 80, 73, 209, 76, 132, 187, 208, 89, 18, 169,
+    200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173, 186,
+    3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126,
+    255, 82, 85, 212, 207, 206, 59, 227, 47, 16, 58, 17, 182, 189,
+    28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163, 70,
+    221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98,
+    108, 110, 79, 113, 224, 232, 178, 185, 112, 104, 218, 246, 97, 228,
+    251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162, 241, 81, 51,
+    145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157,
+    184, 84, 204, 176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236,
+    205, 93, 222, 114, 67, 29, 24, 72, 243, 141, 128, 195, 78, 66,
+    215, 61, 156, 180,
+];
+
+/// Where a procedural texture is evaluated
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureSpace {
+    // The texture coords, w is zero
+    Uv,
+    World,
+    // Before the transform of the object, the same as world space for
+    // objects without one
+    Object,
+}
+
+/// The point a procedural texture sees for a hit
+pub struct Placement {
+    space: TextureSpace,
+    scale: f32,
+}
+
+impl Placement {
+    fn point(&self, hit: &HitRecord) -> Point3 {
+        use TextureSpace::*;
+
+        let p = match self.space {
+            Uv => Vec3::from_values(hit.tex_coords.x, hit.tex_coords.y, 0.),
+            World => hit.p.clone(),
+            Object => hit.object_p.as_ref().unwrap_or(&hit.p).clone(),
+        };
+        p * self.scale
+    }
+}
+
+/// Fractal sum of Perlin noise, every octave multiplies the frequency
+/// by the lacunarity and the amplitude by the gain
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Fbm {
+    #[serde(rename = "@octaves")]
+    octaves: usize,
+    #[serde(rename = "@lacunarity")]
+    lacunarity: f32,
+    #[serde(rename = "@gain")]
+    gain: f32,
+}
+
+impl Default for Fbm {
+    fn default() -> Self {
+        Fbm {
+            octaves: 4,
+            lacunarity: 2.,
+            gain: 0.5,
+        }
+    }
+}
+
+impl Fbm {
+    /// Sums the octaves of `noise`, scaled back to its range
+    fn sum(&self, p: &Point3, noise: impl Fn(&Point3) -> f32) -> f32 {
+        let mut sum = 0.;
+        let mut total_amplitude = 0.;
+        let mut amplitude = 1.;
+        let mut frequency = 1.;
+        for _ in 0..self.octaves.max(1) {
+            sum += amplitude * noise(&(p * frequency));
+            total_amplitude += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        sum / total_amplitude
+    }
+
+    /// Between minus one and one
+    fn value(&self, p: &Point3) -> f32 {
+        self.sum(p, perlin)
+    }
+
+    /// Sum of the absolute noise, between zero and one
+    fn turbulence(&self, p: &Point3) -> f32 {
+        self.sum(p, |p| perlin(p).abs())
+    }
+}
+
+/// Improved Perlin noise, roughly between minus one and one and zero
+/// at every integer point
+pub fn perlin(p: &Point3) -> f32 {
+    let hash = |i: usize| PERMUTATION[i & 255] as usize;
+    let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (x, y, z) = (p.x - x0, p.y - y0, p.z - z0);
+    let xi = (x0 as i32 & 255) as usize;
+    let yi = (y0 as i32 & 255) as usize;
+    let zi = (z0 as i32 & 255) as usize;
+
+    // Hashes of the eight corners of the cell
+    let a = hash(xi) + yi;
+    let aa = hash(a) + zi;
+    let ab = hash(a + 1) + zi;
+    let b = hash(xi + 1) + yi;
+    let ba = hash(b) + zi;
+    let bb = hash(b + 1) + zi;
+
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, grad(hash(aa), x, y, z), grad(hash(ba), x - 1., y, z)),
+            lerp(
+                u,
+                grad(hash(ab), x, y - 1., z),
+                grad(hash(bb), x - 1., y - 1., z),
+            ),
+        ),
+        lerp(
+            v,
+            lerp(
+                u,
+                grad(hash(aa + 1), x, y, z - 1.),
+                grad(hash(ba + 1), x - 1., y, z - 1.),
+            ),
+            lerp(
+                u,
+                grad(hash(ab + 1), x, y - 1., z - 1.),
+                grad(hash(bb + 1), x - 1., y - 1., z - 1.),
+            ),
+        ),
+    )
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+/// Dot product with one of twelve gradients picked by the hash
+fn grad(hash: usize, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = match h {
+        0..=3 => y,
+        12 | 14 => x,
+        _ => z,
+    };
+    let u = if h & 1 == 0 { u } else { -u };
+    let v = if h & 2 == 0 { v } else { -v };
+    u + v
+}
+
+fn mix(a: &Color, b: &Color, t: f32) -> Color {
+    let t = t.clamp(0., 1.);
+    (1. - t) * a + t * b
+}
+
+pub struct Constant {
+    color: Color,
+}
+
+impl ColorLookup for Constant {
+    fn color(&self, _ray: &Ray, _hit: &HitRecord) -> Color {
+        self.color.clone()
+    }
+}
+
+/// Alternating cells of unit size, which can be textures again
+pub struct Checker {
+    placement: Placement,
+    even: Box<dyn ColorLookup>,
+    odd: Box<dyn ColorLookup>,
+}
+
+impl ColorLookup for Checker {
+    fn color(&self, ray: &Ray, hit: &HitRecord) -> Color {
+        let p = self.placement.point(hit);
+        let cell = p.x.floor() as i64 + p.y.floor() as i64
+            + p.z.floor() as i64;
+        if cell.rem_euclid(2) == 0 {
+            self.even.color(ray, hit)
+        } else {
+            self.odd.color(ray, hit)
+        }
+    }
+}
+
+/// Fractal noise from the first to the second color
+pub struct Noise {
+    placement: Placement,
+    fbm: Fbm,
+    colors: (Color, Color),
+}
+
+impl ColorLookup for Noise {
+    fn color(&self, _ray: &Ray, hit: &HitRecord) -> Color {
+        let p = self.placement.point(hit);
+        let t = 0.5 + 0.5 * self.fbm.value(&p);
+        mix(&self.colors.0, &self.colors.1, t)
+    }
+}
+
+/// Veins along x, a sine wave bent by turbulence
+pub struct Marble {
+    placement: Placement,
+    fbm: Fbm,
+    turbulence: f32,
+    colors: (Color, Color),
+}
+
+impl ColorLookup for Marble {
+    fn color(&self, _ray: &Ray, hit: &HitRecord) -> Color {
+        let p = self.placement.point(hit);
+        let phase = p.x + self.turbulence * self.fbm.turbulence(&p);
+        let t = 0.5 + 0.5 * phase.sin();
+        mix(&self.colors.0, &self.colors.1, t)
+    }
+}
+
+/// Rings around the y axis, one per unit, made irregular by noise
+pub struct Wood {
+    placement: Placement,
+    fbm: Fbm,
+    turbulence: f32,
+    colors: (Color, Color),
+}
+
+impl ColorLookup for Wood {
+    fn color(&self, _ray: &Ray, hit: &HitRecord) -> Color {
+        let p = self.placement.point(hit);
+        let r = (p.x * p.x + p.z * p.z).sqrt()
+            + self.turbulence * self.fbm.value(&p);
+        mix(&self.colors.0, &self.colors.1, r - r.floor())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GradientKind {
+    // Along the line from start to end
+    Linear,
+    // Around start, reaching the second color at the distance of end
+    Radial,
+}
+
+/// Blends from the first color at start to the second one at end
+pub struct Gradient {
+    placement: Placement,
+    kind: GradientKind,
+    start: Point3,
+    end: Point3,
+    colors: (Color, Color),
+}
+
+impl ColorLookup for Gradient {
+    fn color(&self, _ray: &Ray, hit: &HitRecord) -> Color {
+        let p = self.placement.point(hit);
+        let offset = &p - &self.start;
+        let axis = &self.end - &self.start;
+        let t = match self.kind {
+            GradientKind::Linear => offset.dot(&axis) / axis.length_squared(),
+            GradientKind::Radial => offset.length() / axis.length(),
+        };
+        mix(&self.colors.0, &self.colors.1, t)
+    }
+}
+
+/// The procedural textures, which `material_textured` takes instead of
+/// an image and checker cells take as well as plain colors
+#[derive(Deserialize)]
+pub enum Pattern {
+    #[serde(rename = "color")]
+    #[serde(deserialize_with = "parse_color")]
+    Color(Color),
+    #[serde(rename = "checker")]
+    Checker(BaseChecker),
+    #[serde(rename = "noise")]
+    Noise(BaseNoise),
+    #[serde(rename = "marble")]
+    Marble(BaseNoise),
+    #[serde(rename = "wood")]
+    Wood(BaseNoise),
+    #[serde(rename = "gradient")]
+    Gradient(BaseGradient),
+}
+
+// `space` is "uv", "world" or "object", `scale` multiplies the
+// coordinates
+#[derive(Deserialize)]
+pub struct BaseChecker {
+    #[serde(rename = "@space")]
+    space: Option<TextureSpace>,
+    #[serde(rename = "@scale")]
+    scale: Option<f32>,
+    #[serde(rename = "$value")]
+    cells: Vec<Pattern>,
+}
+
+// Noise, marble and wood, `turbulence` is how much the noise bends the
+// veins or rings
+#[derive(Deserialize)]
+pub struct BaseNoise {
+    #[serde(rename = "@space")]
+    space: Option<TextureSpace>,
+    #[serde(rename = "@scale")]
+    scale: Option<f32>,
+    #[serde(rename = "@turbulence")]
+    turbulence: Option<f32>,
+    #[serde(default)]
+    fbm: Fbm,
+    #[serde(deserialize_with = "parse_color")]
+    color1: Color,
+    #[serde(deserialize_with = "parse_color")]
+    color2: Color,
+}
+
+#[derive(Deserialize)]
+pub struct BaseGradient {
+    #[serde(rename = "@space")]
+    space: Option<TextureSpace>,
+    #[serde(rename = "@scale")]
+    scale: Option<f32>,
+    #[serde(rename = "@kind")]
+    kind: Option<GradientKind>,
+    #[serde(deserialize_with = "parse_vec3")]
+    start: Point3,
+    #[serde(deserialize_with = "parse_vec3")]
+    end: Point3,
+    #[serde(deserialize_with = "parse_color")]
+    color1: Color,
+    #[serde(deserialize_with = "parse_color")]
+    color2: Color,
+}
+
+impl Pattern {
+    /// Checkers and gradients default to the texture coords, the noise
+    /// based textures to object space
+    pub fn into_lookup(self) -> Result<Box<dyn ColorLookup>, String> {
+        use TextureSpace::*;
+
+        let placement = |space: Option<TextureSpace>, scale, default| {
+            Placement {
+                space: space.unwrap_or(default),
+                scale: Option::unwrap_or(scale, 1.),
+            }
+        };
+
+        Ok(match self {
+            Pattern::Color(color) => Box::new(Constant {
+                color,
+            }),
+            Pattern::Checker(c) => {
+                let [even, odd]: [Pattern; 2] =
+                    c.cells.try_into().map_err(|_| {
+                        "A checker needs two cells".to_string()
+                    })?;
+                Box::new(Checker {
+                    placement: placement(c.space, c.scale, Uv),
+                    even: even.into_lookup()?,
+                    odd: odd.into_lookup()?,
+                })
+            }
+            Pattern::Noise(n) => Box::new(Noise {
+                placement: placement(n.space, n.scale, Object),
+                fbm: n.fbm,
+                colors: (n.color1, n.color2),
+            }),
+            Pattern::Marble(n) => Box::new(Marble {
+                placement: placement(n.space, n.scale, Object),
+                fbm: n.fbm,
+                turbulence: n.turbulence.unwrap_or(5.),
+                colors: (n.color1, n.color2),
+            }),
+            Pattern::Wood(n) => Box::new(Wood {
+                placement: placement(n.space, n.scale, Object),
+                fbm: n.fbm,
+                turbulence: n.turbulence.unwrap_or(0.5),
+                colors: (n.color1, n.color2),
+            }),
+            Pattern::Gradient(g) => {
+                if (&g.end - &g.start).length_squared() == 0. {
+                    return Err("A gradient needs two points".to_string());
+                }
+                Box::new(Gradient {
+                    placement: placement(g.space, g.scale, Uv),
+                    kind: g.kind.unwrap_or(GradientKind::Linear),
+                    start: g.start,
+                    end: g.end,
+                    colors: (g.color1, g.color2),
+                })
+            }
+        })
+    }
+}
@@ -0,0 +1,684 @@
+use serde::{Deserialize, Deserializer, de};
+use std::sync::Arc;
+use crate::{
+    utils::helpers::parse_vec3,
+    math::{Mat4, Point3, Vec3},
+    ray::{Hittable, HitRecord, Ray},
+    kdtree::AABB,
+};
+use super::{
+    materials::{parse_material, Material},
+    surfaces::spherical_transformed_aabb,
+    transforms::Transform,
+};
+
+// Offset for the finite difference normals
+const DEL: f32 = 1e-4;
+
+// Upper bound for the sphere tracing steps, in case a ray creeps along
+// a surface without ever getting closer than epsilon
+const MAX_STEPS: usize = 1024;
+
+// Iterations for shapes that don't require max_iterations to be set
+const DEFAULT_ITERATIONS: usize = 8;
+
+const MANDELBULB_BAILOUT: f32 = 2.;
+const MANDELBULB_RADIUS: f32 = 1.25;
+const MENGER_RADIUS: f32 = 1.7320508; // sqrt(3)
+
+/// A (lower bound of the) signed distance to a surface around the
+/// origin, negative inside
+pub trait DistanceEstimator: Send + Sync {
+    fn distance(&self, p: &Point3, max_iterations: usize) -> f32;
+
+    /// Radius of a sphere around the origin containing the surface
+    fn bounding_radius(&self) -> f32;
+
+    /// Estimates the normal by central differences
+    fn normal(&self, p: &Point3, max_iterations: usize) -> Vec3 {
+        let dx = Vec3::from_values(DEL, 0., 0.);
+        let dy = Vec3::from_values(0., DEL, 0.);
+        let dz = Vec3::from_values(0., 0., DEL);
+        let d = |offset: &Vec3| {
+            self.distance(&(p + offset), max_iterations)
+                - self.distance(&(p - offset), max_iterations)
+        };
+
+        Vec3::from_values(d(&dx), d(&dy), d(&dz))
+    }
+
+    /// Sphere traces the ray `tr` (in the space of the estimator) from
+    /// `t1` to `t2`, returns t and the point of the hit. Distances are
+    /// measured in object space, t is scaled by the length of the
+    /// direction. Rays starting inside the surface march towards the
+    /// exit.
+    fn march(
+        &self,
+        tr: &Ray,
+        t1: f32,
+        t2: f32,
+        epsilon: f32,
+        max_iterations: usize,
+    ) -> Option<(f32, Point3)> {
+        let len = tr.dir.length();
+        let mut t = t1;
+        let mut dist = self.distance(&tr.at(t), max_iterations);
+        let side = if dist < 0. { -1. } else { 1. };
+
+        for _ in 0..MAX_STEPS {
+            // Step this far along the ray
+            t += side * dist / len;
+
+            // Are we close enough to the surface?
+            if side * dist < epsilon {
+                return Some((t, tr.at(t)));
+            }
+
+            // Have we left the bounding sphere?
+            if t > t2 {
+                return None;
+            }
+
+            dist = self.distance(&tr.at(t), max_iterations);
+        }
+
+        None
+    }
+}
+
+/// Sphere traced surface. The transform maps the world to the space of
+/// the estimator, so it also contains position and size of the surface.
+pub struct SdfSurface<E: DistanceEstimator> {
+    estimator: E,
+    epsilon: f32,
+    max_iterations: usize,
+    material: Arc<dyn Material>,
+    transform: Transform,
+}
+
+impl<E: DistanceEstimator> SdfSurface<E> {
+    pub fn from_values(
+        estimator: E,
+        epsilon: f32,
+        max_iterations: usize,
+        material: Arc<dyn Material>,
+        transform: Transform,
+    ) -> Self {
+        Self {
+            estimator,
+            epsilon,
+            max_iterations,
+            material,
+            transform,
+        }
+    }
+
+    fn get_intersect_values(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Point3)> {
+        // ------------------------------------------------------------
+        // BOUNDING SPHERE
+        // ------------------------------------------------------------
+
+        // Transform ray
+        let tr_origin = &self.transform.world_to_object * &r.orig;
+        let tr_direction =
+            self.transform.world_to_object.mul_dir(&r.dir);
+        let tr = Ray::from_values(&tr_origin, &tr_direction);
+
+        // Discriminant of quadratic formula
+        let radius = self.estimator.bounding_radius();
+        let a = tr.dir.length_squared();
+        let half_b = tr.orig.dot(&tr.dir);
+        let c = tr.orig.length_squared() - radius * radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0. {
+            return None;
+        }
+
+        // Check if any of the intersection points are in acceptable
+        // range (at least some overlaps with the range)
+        let sqrtd = discriminant.sqrt();
+        let mut t1 = (-half_b - sqrtd) / a;
+        let mut t2 = (-half_b + sqrtd) / a;
+        if (t_max - t1) * (t2 - t_min) < 0. {
+            return None;
+        }
+
+        // Make sure both our t values are sensible
+        // t1 should always be the smaller one, so that we can check
+        // for t2 if we're leaving the scene
+        t1 = t1.clamp(t_min, t_max);
+        t2 = t2.clamp(t_min, t_max);
+
+        // ------------------------------------------------------------
+        // SPHERE TRACING
+        // ------------------------------------------------------------
+
+        self.estimator
+            .march(&tr, t1, t2, self.epsilon, self.max_iterations)
+    }
+}
+
+impl<E: DistanceEstimator> Hittable for SdfSurface<E> {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord> {
+        let (t, origin) = self.get_intersect_values(r, t_min, t_max)?;
+
+        let normal = self.estimator.normal(&origin, self.max_iterations);
+        let normal = self.transform.normal_matrix.mul_dir(&normal);
+
+        let mut hit = HitRecord::from_values(
+            r,
+            r.at(t),
+            &normal.unit_vector(),
+            t,
+            Vec3::from_values(0., 0., 1.),
+            self.material.clone(),
+        );
+        hit.object_p = Some(origin);
+        Some(hit)
+    }
+
+    fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.get_intersect_values(r, t_min, t_max).is_some()
+    }
+
+    fn bound(&self) -> AABB {
+        spherical_transformed_aabb(
+            &Vec3::new(),
+            self.estimator.bounding_radius(),
+            &self.transform,
+        )
+    }
+}
+
+/// Combines the placement of a surface (a uniform scale, then moving
+/// it to `position`) with the transform from the XML file
+pub fn object_transform(
+    position: &Point3,
+    scale: f32,
+    transform: Option<Transform>,
+) -> Transform {
+    let scale_vec = Vec3::from_values(scale, scale, scale);
+    let scale_mat = Mat4::scale(&scale_vec);
+    let scale_inv = Mat4::scale(&(1. / scale_vec));
+    let translate = Mat4::translate(position);
+    let translate_inv = Mat4::translate(&-position);
+
+    // Combine matrices
+    let mut world_to_object = &scale_inv * &translate_inv;
+    let mut object_to_world = &translate * &scale_mat;
+    if let Some(t) = transform {
+        world_to_object = &world_to_object * &t.world_to_object;
+        object_to_world = &t.object_to_world * &object_to_world;
+    }
+    let normal_matrix = world_to_object.transpose();
+
+    Transform {
+        world_to_object,
+        normal_matrix,
+        object_to_world,
+    }
+}
+
+fn abs(v: &Vec3) -> Vec3 {
+    Vec3::from_values(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+fn max_zero(v: &Vec3) -> Vec3 {
+    Vec3::from_values(v.x.max(0.), v.y.max(0.), v.z.max(0.))
+}
+
+fn max_component(v: &Vec3) -> f32 {
+    v.x.max(v.y).max(v.z)
+}
+
+/// Exact distance to a box centered at the origin
+fn box_distance(p: &Point3, half_size: &Vec3) -> f32 {
+    let q = abs(p) - half_size;
+    max_zero(&q).length() + max_component(&q).min(0.)
+}
+
+pub struct Mandelbulb {
+    power: f32,
+}
+
+impl DistanceEstimator for Mandelbulb {
+    fn distance(&self, p: &Point3, max_iterations: usize) -> f32 {
+        let mut z = p.clone();
+        let mut dr = 1.;
+        let mut r = z.length();
+
+        for _ in 0..max_iterations {
+            if r > MANDELBULB_BAILOUT {
+                break;
+            }
+
+            // Raise z to the power in spherical coordinates
+            let theta = (z.z / r).clamp(-1., 1.).acos() * self.power;
+            let phi = z.y.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.) * self.power * dr + 1.;
+            let zr = r.powf(self.power);
+            z = zr
+                * Vec3::from_values(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                )
+                + p;
+            r = z.length();
+        }
+
+        0.5 * r.ln() * r / dr
+    }
+
+    fn bounding_radius(&self) -> f32 {
+        MANDELBULB_RADIUS
+    }
+}
+
+/// Menger sponge filling the cube from -1 to 1, with one level of holes
+/// per iteration
+pub struct MengerSponge;
+
+impl DistanceEstimator for MengerSponge {
+    fn distance(&self, p: &Point3, max_iterations: usize) -> f32 {
+        let mut d = box_distance(p, &Vec3::from_values(1., 1., 1.));
+        let mut scale = 1.;
+
+        for _ in 0..max_iterations {
+            // Position inside the current level's cell, from -1 to 1
+            let a = Vec3::from_values(
+                (p.x * scale).rem_euclid(2.) - 1.,
+                (p.y * scale).rem_euclid(2.) - 1.,
+                (p.z * scale).rem_euclid(2.) - 1.,
+            );
+            scale *= 3.;
+
+            // Distance to the cross cut out of the cell
+            let r = abs(&(Vec3::from_values(1., 1., 1.) - 3. * abs(&a)));
+            let da = r.x.max(r.y);
+            let db = r.y.max(r.z);
+            let dc = r.z.max(r.x);
+            let c = (da.min(db).min(dc) - 1.) / scale;
+            d = d.max(c);
+        }
+
+        d
+    }
+
+    fn bounding_radius(&self) -> f32 {
+        MENGER_RADIUS
+    }
+}
+
+pub struct RoundedBox {
+    half_size: Vec3,
+    rounding: f32,
+}
+
+impl DistanceEstimator for RoundedBox {
+    fn distance(&self, p: &Point3, _max_iterations: usize) -> f32 {
+        let inner = &self.half_size
+            - Vec3::from_values(self.rounding, self.rounding, self.rounding);
+        box_distance(p, &inner) - self.rounding
+    }
+
+    fn bounding_radius(&self) -> f32 {
+        self.half_size.length()
+    }
+}
+
+pub struct SdfSphere {
+    radius: f32,
+}
+
+impl DistanceEstimator for SdfSphere {
+    fn distance(&self, p: &Point3, _max_iterations: usize) -> f32 {
+        p.length() - self.radius
+    }
+
+    fn bounding_radius(&self) -> f32 {
+        self.radius
+    }
+}
+
+/// Child of a smooth union, moved and uniformly scaled. Distances scale
+/// along, so the result is still a valid distance estimate.
+pub struct Placed {
+    position: Point3,
+    scale: f32,
+    shape: Box<dyn DistanceEstimator>,
+}
+
+impl DistanceEstimator for Placed {
+    fn distance(&self, p: &Point3, max_iterations: usize) -> f32 {
+        let local = (p - &self.position) / self.scale;
+        self.scale * self.shape.distance(&local, max_iterations)
+    }
+
+    fn bounding_radius(&self) -> f32 {
+        self.position.length() + self.scale * self.shape.bounding_radius()
+    }
+}
+
+/// Union that blends the shapes together where they are closer than
+/// `k`, using the polynomial smooth minimum
+pub struct SmoothUnion {
+    k: f32,
+    shapes: Vec<Placed>,
+}
+
+impl DistanceEstimator for SmoothUnion {
+    fn distance(&self, p: &Point3, max_iterations: usize) -> f32 {
+        self.shapes
+            .iter()
+            .map(|shape| shape.distance(p, max_iterations))
+            .reduce(|a, b| {
+                let h = (self.k - (a - b).abs()).max(0.) / self.k;
+                a.min(b) - h * h * self.k * 0.25
+            })
+            .unwrap_or(f32::INFINITY)
+    }
+
+    fn bounding_radius(&self) -> f32 {
+        // The blend only ever adds material between the shapes, within
+        // k/4 of them
+        self.shapes
+            .iter()
+            .map(|shape| shape.bounding_radius())
+            .fold(0., f32::max)
+            + 0.25 * self.k
+    }
+}
+
+pub fn parse_mandelbulb<'de, D>(
+    deserializer: D,
+) -> Result<SdfSurface<Mandelbulb>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseMandelbulb {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(rename = "@power")]
+        #[serde(default)]
+        pub power: Option<f32>,
+        #[serde(rename = "@epsilon")]
+        pub epsilon: f32,
+        #[serde(rename = "@max_iterations")]
+        pub max_iterations: usize,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseMandelbulb {
+        radius,
+        power,
+        epsilon,
+        max_iterations,
+        position,
+        material,
+        transform,
+    } = BaseMandelbulb::deserialize(deserializer)?;
+
+    let mandelbulb = Mandelbulb {
+        power: power.unwrap_or(8.),
+    };
+    let scale = radius / mandelbulb.bounding_radius();
+
+    Ok(SdfSurface::from_values(
+        mandelbulb,
+        epsilon,
+        max_iterations,
+        material,
+        object_transform(&position, scale, transform),
+    ))
+}
+
+pub fn parse_menger_sponge<'de, D>(
+    deserializer: D,
+) -> Result<SdfSurface<MengerSponge>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseMengerSponge {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(rename = "@epsilon")]
+        pub epsilon: f32,
+        #[serde(rename = "@max_iterations")]
+        pub max_iterations: usize,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseMengerSponge {
+        radius,
+        epsilon,
+        max_iterations,
+        position,
+        material,
+        transform,
+    } = BaseMengerSponge::deserialize(deserializer)?;
+
+    let scale = radius / MengerSponge.bounding_radius();
+
+    Ok(SdfSurface::from_values(
+        MengerSponge,
+        epsilon,
+        max_iterations,
+        material,
+        object_transform(&position, scale, transform),
+    ))
+}
+
+pub fn parse_rounded_box<'de, D>(
+    deserializer: D,
+) -> Result<SdfSurface<RoundedBox>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseRoundedBox {
+        #[serde(rename = "@rounding")]
+        pub rounding: f32,
+        #[serde(rename = "@epsilon")]
+        pub epsilon: f32,
+        #[serde(rename = "@max_iterations")]
+        #[serde(default)]
+        pub max_iterations: Option<usize>,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub size: Vec3,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseRoundedBox {
+        rounding,
+        epsilon,
+        max_iterations,
+        position,
+        size,
+        material,
+        transform,
+    } = BaseRoundedBox::deserialize(deserializer)?;
+
+    let rounded_box = RoundedBox {
+        half_size: 0.5 * size,
+        rounding,
+    };
+
+    Ok(SdfSurface::from_values(
+        rounded_box,
+        epsilon,
+        max_iterations.unwrap_or(DEFAULT_ITERATIONS),
+        material,
+        object_transform(&position, 1., transform),
+    ))
+}
+
+pub fn parse_smooth_union<'de, D>(
+    deserializer: D,
+) -> Result<SdfSurface<SmoothUnion>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct BaseSphere {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+    }
+
+    #[derive(Deserialize)]
+    pub struct BaseRoundedBox {
+        #[serde(rename = "@rounding")]
+        pub rounding: f32,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub size: Vec3,
+    }
+
+    #[derive(Deserialize)]
+    pub struct BaseMandelbulb {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(rename = "@power")]
+        #[serde(default)]
+        pub power: Option<f32>,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+    }
+
+    #[derive(Deserialize)]
+    pub struct BaseMengerSponge {
+        #[serde(rename = "@radius")]
+        pub radius: f32,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+    }
+
+    #[derive(Deserialize)]
+    pub enum BaseShape {
+        #[serde(rename = "sphere")]
+        Sphere(BaseSphere),
+        #[serde(rename = "rounded_box")]
+        RoundedBox(BaseRoundedBox),
+        #[serde(rename = "mandelbulb")]
+        Mandelbulb(BaseMandelbulb),
+        #[serde(rename = "menger_sponge")]
+        MengerSponge(BaseMengerSponge),
+    }
+
+    #[derive(Deserialize)]
+    pub struct BaseShapes {
+        #[serde(default)]
+        #[serde(rename = "$value")]
+        pub shapes: Vec<BaseShape>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct BaseSmoothUnion {
+        #[serde(rename = "@k")]
+        pub k: f32,
+        #[serde(rename = "@epsilon")]
+        pub epsilon: f32,
+        #[serde(rename = "@max_iterations")]
+        #[serde(default)]
+        pub max_iterations: Option<usize>,
+        #[serde(deserialize_with = "parse_vec3")]
+        pub position: Point3,
+        pub shapes: BaseShapes,
+        #[serde(rename = "$value")]
+        #[serde(deserialize_with = "parse_material")]
+        pub material: Arc<dyn Material>,
+        pub transform: Option<Transform>,
+    }
+
+    let BaseSmoothUnion {
+        k,
+        epsilon,
+        max_iterations,
+        position,
+        shapes,
+        material,
+        transform,
+    } = BaseSmoothUnion::deserialize(deserializer)?;
+
+    if shapes.shapes.is_empty() {
+        return Err(de::Error::custom("smooth_union needs shapes"));
+    }
+    if k <= 0. {
+        return Err(de::Error::custom("smooth_union needs k > 0"));
+    }
+
+    let shapes = shapes
+        .shapes
+        .into_iter()
+        .map(|shape| match shape {
+            BaseShape::Sphere(s) => Placed {
+                position: s.position,
+                scale: 1.,
+                shape: Box::new(SdfSphere {
+                    radius: s.radius,
+                }),
+            },
+            BaseShape::RoundedBox(b) => Placed {
+                position: b.position,
+                scale: 1.,
+                shape: Box::new(RoundedBox {
+                    half_size: 0.5 * b.size,
+                    rounding: b.rounding,
+                }),
+            },
+            BaseShape::Mandelbulb(m) => Placed {
+                position: m.position,
+                scale: m.radius / MANDELBULB_RADIUS,
+                shape: Box::new(Mandelbulb {
+                    power: m.power.unwrap_or(8.),
+                }),
+            },
+            BaseShape::MengerSponge(m) => Placed {
+                position: m.position,
+                scale: m.radius / MENGER_RADIUS,
+                shape: Box::new(MengerSponge),
+            },
+        })
+        .collect();
+
+    Ok(SdfSurface::from_values(
+        SmoothUnion {
+            k,
+            shapes,
+        },
+        epsilon,
+        max_iterations.unwrap_or(DEFAULT_ITERATIONS),
+        material,
+        object_transform(&position, 1., transform),
+    ))
+}
@@ -4,14 +4,29 @@ use crate::{
     math::{Point3, Vec3},
     ray::{Hittable, HittableList, HitRecord, Ray},
     kdtree::AABB,
-    utils::objparser::parse_obj,
+    light::{EmissiveSphere, Emitters},
+    utils::objparser::{load_mesh, MeshInstance, DEFAULT_CREASE_ANGLE},
     utils::helpers::parse_vec3
 };
 
 use super::{
-    materials::{parse_material, parse_texture_object, Material, ColorLookup},
+    materials::{
+        parse_material, parse_material_option, parse_normal_map,
+        Material, ColorLookup,
+    },
     transforms::Transform,
-    julia::{parse_julia, Julia}
+    julia::{parse_julia, Julia},
+    primitives::{
+        parse_box, parse_cone, parse_cylinder, parse_disk, parse_plane,
+        parse_torus, Cone, Cuboid, Cylinder, Disk, InfinitePlane,
+        Primitive, Torus,
+    },
+    csg::{parse_csg, Csg},
+    sdf::{
+        parse_mandelbulb, parse_menger_sponge, parse_rounded_box,
+        parse_smooth_union, Mandelbulb, MengerSponge, RoundedBox,
+        SdfSurface, SmoothUnion,
+    },
 };
 
 #[derive(Deserialize)]
@@ -60,6 +75,17 @@ impl Sphere {
 
         Some(t)
     }
+
+    /// Derivatives of the point `p` by the texture coords, the poles
+    /// only have one direction
+    fn tex_frame(&self, p: &Point3) -> (Vec3, Vec3) {
+        let d = p - &self.position;
+        let rho = (d.x * d.x + d.z * d.z).sqrt().max(f32::EPSILON);
+        let dp_du = std::f32::consts::TAU * Vec3::from_values(d.z, 0., -d.x);
+        let dp_dv = -std::f32::consts::PI
+            * Vec3::from_values(-d.y * d.x / rho, rho, -d.y * d.z / rho);
+        (dp_du, dp_dv)
+    }
 }
 
 impl Hittable for Sphere {
@@ -97,14 +123,29 @@ impl Hittable for Sphere {
         let u = 0.5 + d.x.atan2(d.z) / std::f32::consts::TAU;
         let v = 0.5 - d.y.asin() / std::f32::consts::PI;
 
-        Some(HitRecord::from_values(
+        let mut hit = HitRecord::from_values(
             r,
             r.at(t),
             &outward_normal.unit_vector(),
             t,
             Vec3::from_values(u, v, 1.),
             self.material.clone(),
-        ))
+        );
+        if self.transform.is_some() {
+            hit.object_p = Some(p.clone());
+        }
+        if r.differentials.is_some() {
+            let (dp_du, dp_dv) = self.tex_frame(&p);
+            let (dp_du, dp_dv) = match &self.transform {
+                Some(t) => (
+                    t.object_to_world.mul_dir(&dp_du),
+                    t.object_to_world.mul_dir(&dp_dv),
+                ),
+                None => (dp_du, dp_dv),
+            };
+            hit.set_tex_derivatives(r, &dp_du, &dp_dv);
+        }
+        Some(hit)
     }
 
     fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
@@ -125,6 +166,18 @@ impl Hittable for Sphere {
             spherical_aabb(&self.position, self.radius)
         }
     }
+
+    fn collect_emitters(&self, emitters: &mut Emitters) {
+        if let Some(emission) = self.material.emission() {
+            let sphere = EmissiveSphere::new(
+                self.position.clone(),
+                self.radius,
+                self.transform.clone(),
+                emission.clone(),
+            );
+            emitters.add_sphere(sphere, &self.material);
+        }
+    }
 }
 
 pub fn spherical_aabb(position: &Vec3, radius: f32) -> AABB {
@@ -159,6 +212,82 @@ pub fn spherical_transformed_aabb(
     }
 }
 
+/// Every element that can appear in <surfaces>, or as a child of <csg>
+#[derive(Deserialize)]
+pub enum Surface {
+    #[serde(rename = "sphere")]
+    SurfSphere(Sphere),
+    #[serde(rename = "julia")]
+    #[serde(deserialize_with = "parse_julia")]
+    SurfJulia(Julia),
+    #[serde(rename = "mesh")]
+    #[serde(deserialize_with = "parse_mesh")]
+    SurfMesh(MeshInstance),
+    #[serde(rename = "plane")]
+    #[serde(deserialize_with = "parse_plane")]
+    SurfPlane(Primitive<InfinitePlane>),
+    #[serde(rename = "disk")]
+    #[serde(deserialize_with = "parse_disk")]
+    SurfDisk(Primitive<Disk>),
+    #[serde(rename = "box")]
+    #[serde(deserialize_with = "parse_box")]
+    SurfBox(Primitive<Cuboid>),
+    #[serde(rename = "cylinder")]
+    #[serde(deserialize_with = "parse_cylinder")]
+    SurfCylinder(Primitive<Cylinder>),
+    #[serde(rename = "cone")]
+    #[serde(deserialize_with = "parse_cone")]
+    SurfCone(Primitive<Cone>),
+    #[serde(rename = "torus")]
+    #[serde(deserialize_with = "parse_torus")]
+    SurfTorus(Primitive<Torus>),
+    #[serde(rename = "csg")]
+    #[serde(deserialize_with = "parse_csg")]
+    SurfCsg(Csg),
+    #[serde(rename = "mandelbulb")]
+    #[serde(deserialize_with = "parse_mandelbulb")]
+    SurfMandelbulb(SdfSurface<Mandelbulb>),
+    #[serde(rename = "menger_sponge")]
+    #[serde(deserialize_with = "parse_menger_sponge")]
+    SurfMengerSponge(SdfSurface<MengerSponge>),
+    #[serde(rename = "rounded_box")]
+    #[serde(deserialize_with = "parse_rounded_box")]
+    SurfRoundedBox(SdfSurface<RoundedBox>),
+    #[serde(rename = "smooth_union")]
+    #[serde(deserialize_with = "parse_smooth_union")]
+    SurfSmoothUnion(SdfSurface<SmoothUnion>),
+}
+
+impl Surface {
+    /// None for surfaces without any geometry, like empty meshes
+    pub fn into_hittable(self) -> Option<Box<dyn Hittable>> {
+        use Surface::*;
+
+        match self {
+            SurfSphere(s) => Some(Box::new(s)),
+            SurfJulia(j) => Some(Box::new(j)),
+            SurfMesh(m) => {
+                if m.is_empty() {
+                    None
+                } else {
+                    Some(Box::new(m))
+                }
+            }
+            SurfPlane(p) => Some(Box::new(p)),
+            SurfDisk(d) => Some(Box::new(d)),
+            SurfBox(b) => Some(Box::new(b)),
+            SurfCylinder(c) => Some(Box::new(c)),
+            SurfCone(c) => Some(Box::new(c)),
+            SurfTorus(t) => Some(Box::new(t)),
+            SurfCsg(c) => Some(Box::new(c)),
+            SurfMandelbulb(m) => Some(Box::new(m)),
+            SurfMengerSponge(m) => Some(Box::new(m)),
+            SurfRoundedBox(b) => Some(Box::new(b)),
+            SurfSmoothUnion(u) => Some(Box::new(u)),
+        }
+    }
+}
+
 pub fn parse_world<'de, D>(
     deserializer: D,
 ) -> Result<HittableList, D::Error>
@@ -172,35 +301,21 @@ where
         pub surfaces: Vec<Surface>,
     }
 
-    #[derive(Deserialize)]
-    pub enum Surface {
-        #[serde(rename = "sphere")]
-        SurfSphere(Sphere),
-        #[serde(rename = "julia")]
-        #[serde(deserialize_with = "parse_julia")]
-        SurfJulia(Julia),
-        #[serde(rename = "mesh")]
-        #[serde(deserialize_with = "parse_mesh")]
-        SurfMesh(Vec<Box<dyn Hittable>>),
-    }
-    use Surface::*;
-
     let Surfaces {
         surfaces,
     } = Surfaces::deserialize(deserializer)?;
     let mut hittable_list = HittableList::new();
-    surfaces.into_iter().for_each(|s| match s {
-        SurfSphere(s) => hittable_list.add(Box::new(s)),
-        SurfJulia(j) => hittable_list.add(Box::new(j)),
-        SurfMesh(s) => hittable_list.extend(s),
-    });
+    surfaces
+        .into_iter()
+        .filter_map(Surface::into_hittable)
+        .for_each(|h| hittable_list.add(h));
 
     Ok(hittable_list)
 }
 
 pub fn parse_mesh<'de, D>(
     deserializer: D,
-) -> Result<Vec<Box<dyn Hittable>>, D::Error>
+) -> Result<MeshInstance, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -210,29 +325,41 @@ where
     pub struct BaseMesh {
         #[serde(rename = "@name")]
         pub name: String,
-        #[serde(deserialize_with = "parse_texture_object")]
+        #[serde(rename = "@crease_angle")]
+        #[serde(default)]
+        pub crease_angle: Option<f32>,
+        #[serde(deserialize_with = "parse_normal_map")]
         // TODO: Why is this default here needed?
         #[serde(default)]
         pub normal_map: Option<Box<dyn ColorLookup>>,
         #[serde(rename = "$value")]
-        #[serde(deserialize_with = "parse_material")]
-        pub material: Arc<dyn Material>,
+        #[serde(deserialize_with = "parse_material_option")]
+        #[serde(default)]
+        pub material: Option<Arc<dyn Material>>,
         pub transform: Option<Transform>,
     }
 
     let BaseMesh {
         name,
+        crease_angle,
         material,
         normal_map,
         transform,
     } = BaseMesh::deserialize(deserializer)?;
 
-    // Load obj
+    // Load obj, or share it with the instances that already did
     let mut path = PathBuf::new();
     path.push(r"../scenes");
     path.push(&name);
-    let data = parse_obj(&path, material, normal_map, transform)
+    let crease_angle = crease_angle.unwrap_or(DEFAULT_CREASE_ANGLE);
+    let mesh = load_mesh(&path, crease_angle)
         .map_err(|e| de::Error::custom(e.to_string()))?;
+    if material.is_none() && mesh.has_faces_without_material() {
+        return Err(de::Error::custom(format!(
+            "Mesh {} needs a material, not all faces got one from a mtl file",
+            name
+        )));
+    }
 
-    Ok(data)
+    Ok(MeshInstance::new(mesh, material, normal_map, transform))
 }
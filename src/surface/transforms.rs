@@ -9,6 +9,18 @@ pub struct Transform {
     pub object_to_world: Mat4,
 }
 
+impl Transform {
+    pub fn from_matrices(object_to_world: Mat4, world_to_object: Mat4) -> Self {
+        let normal_matrix = world_to_object.transpose();
+
+        Self {
+            world_to_object,
+            normal_matrix,
+            object_to_world,
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for Transform {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -70,12 +82,6 @@ impl<'de> Deserialize<'de> for Transform {
             })
             .fold(Mat4::identity(), |acc, e| &acc * &e);
 
-        let normal_matrix = world_to_object.transpose();
-
-        Ok(Transform {
-            world_to_object,
-            object_to_world,
-            normal_matrix,
-        })
+        Ok(Transform::from_matrices(object_to_world, world_to_object))
     }
 }
@@ -1,12 +1,30 @@
 use crate::math::Color;
 
+/// The sRGB transfer function, encoding a linear value for display
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Converts `color` to bytes, sRGB encoded if `srgb` is set
 pub fn get_int_color(
     out: &mut [u8;3],
-    color: &Color
+    color: &Color,
+    srgb: bool,
 ) {
-    let r = color.x;
-    let g = color.y;
-    let b = color.z;
+    let encode = |value: f32| {
+        if srgb {
+            linear_to_srgb(value)
+        } else {
+            value
+        }
+    };
+    let r = encode(color.x);
+    let g = encode(color.y);
+    let b = encode(color.z);
 
     out[0] = (r.clamp(0., 0.999) * 256.) as u8;
     out[1] = (g.clamp(0., 0.999) * 256.) as u8;
@@ -21,6 +21,24 @@ pub struct Config {
     pub anim: Option<Animation>,
     #[serde(default)]
     pub texture_interpolation: TextureInterpolation,
+    #[serde(default)]
+    pub integrator: Integrator,
+    pub accel: Option<Accelerator>,
+    pub kdtree_cache: Option<String>,
+    // Encode the image to sRGB instead of writing the linear colors
+    #[serde(default)]
+    pub srgb_output: bool,
+}
+
+impl Config {
+    /// `accel` takes precedence over the older `kdtree` switch
+    pub fn accelerator(&self) -> Accelerator {
+        match self.accel {
+            Some(accel) => accel,
+            None if self.kdtree => Accelerator::KDTree,
+            None => Accelerator::List,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -37,6 +55,26 @@ pub enum TextureInterpolation {
     #[default]
     Nearest,
     Linear,
+    // Blend mip levels by the footprint of the pixel on the surface
+    Trilinear,
+    // Like trilinear, with several samples along stretched footprints
+    Anisotropic,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Integrator {
+    #[default]
+    Whitted,
+    Path,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Accelerator {
+    List,
+    KDTree,
+    Bvh,
 }
 
 #[derive(Deserialize, Debug)]
@@ -1,5 +1,8 @@
 mod colors;
 pub mod objparser;
+pub mod mtlparser;
+pub mod plyparser;
+pub mod stlparser;
 pub mod config;
 pub mod helpers;
 
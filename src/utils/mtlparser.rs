@@ -0,0 +1,240 @@
+use std::{
+    path::Path,
+    error::Error,
+    fs::File,
+    io::{BufReader, BufRead},
+    sync::Arc,
+};
+use crate::{
+    math::Color,
+    io::{load_texture, ColorSpace},
+    surface::{
+        materials::{
+            interpolated_texture, Phong, Reflectance, Refraction, Solid,
+            Textured, Transmittance,
+        },
+        ColorLookup, Material,
+    },
+};
+
+/// A material of a mtl file, the normal map comes from its bump map
+pub struct MeshMaterial {
+    pub material: Arc<dyn Material>,
+    pub normal_map: Option<Box<dyn ColorLookup>>,
+}
+
+/// The statements of one newmtl block we know how to map to our
+/// materials, with the defaults of the mtl spec
+struct MtlEntry {
+    name: String,
+    ambient: f32,
+    diffuse: Color,
+    emission: Color,
+    specular: f32,
+    exponent: f32,
+    transmittance: f32,
+    refraction: f32,
+    illum: u32,
+    diffuse_map: Option<String>,
+    bump_map: Option<String>,
+}
+
+impl MtlEntry {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            ambient: 0.,
+            diffuse: Color::from_values(0.8, 0.8, 0.8),
+            emission: Color::new(),
+            specular: 0.,
+            exponent: 1.,
+            transmittance: 0.,
+            refraction: 1.,
+            illum: 2,
+            diffuse_map: None,
+            bump_map: None,
+        }
+    }
+
+    fn into_mesh_material(self, dir: &Path) -> MeshMaterial {
+        // The colors of our phong model are scalar, so the diffuse
+        // color becomes the material color and the others get averaged
+        let phong = Phong {
+            ka: self.ambient,
+            kd: 1.,
+            ks: self.specular,
+            exponent: self.exponent,
+        };
+
+        // Illumination models 3 to 7 are the ones with ray traced
+        // reflections
+        let reflectance = Reflectance {
+            r: if (3..=7).contains(&self.illum) {
+                self.specular
+            } else {
+                0.
+            },
+        };
+        let transmittance = Transmittance {
+            t: self.transmittance,
+        };
+        let refraction = Refraction {
+            iof: self.refraction,
+        };
+
+        // Black is what most exporters write for materials that don't
+        // glow
+        let emission = Some(self.emission)
+            .filter(|e| e.x > 0. || e.y > 0. || e.z > 0.);
+
+        let diffuse_map = self.diffuse_map.and_then(|name| {
+            load_map(&self.name, dir, &name, ColorSpace::Srgb)
+        });
+        let material: Arc<dyn Material> = match diffuse_map {
+            Some(texture) => Arc::new(Textured {
+                texture,
+                phong,
+                reflectance,
+                transmittance,
+                refraction,
+                emission,
+            }),
+            None => Arc::new(Solid {
+                color: self.diffuse,
+                phong,
+                reflectance,
+                transmittance,
+                refraction,
+                emission,
+            }),
+        };
+
+        let normal_map = self
+            .bump_map
+            .and_then(|name| {
+                load_map(&self.name, dir, &name, ColorSpace::Linear)
+            });
+
+        MeshMaterial {
+            material,
+            normal_map,
+        }
+    }
+}
+
+/// Loads a texture referenced by a material. A missing texture only
+/// costs us the texture, not the whole mesh.
+fn load_map(
+    material: &str,
+    dir: &Path,
+    name: &str,
+    color_space: ColorSpace,
+) -> Option<Box<dyn ColorLookup>> {
+    match load_texture(&dir.join(name), color_space) {
+        Ok(texture) => Some(interpolated_texture(texture)),
+        Err(e) => {
+            println!(
+                "Ignoring texture {} of material {}: {}",
+                name, material, e
+            );
+            None
+        }
+    }
+}
+
+/// Averages the r g b values of a color statement, a single value is
+/// used for all three channels
+fn parse_scalar<'a, I>(
+    iter: I,
+) -> Result<f32, Box<dyn Error + Send + Sync>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let values = iter
+        .take(3)
+        .map(|s| s.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.is_empty() {
+        return Err("Missing value in mtl file".into());
+    }
+    Ok(values.iter().sum::<f32>() / values.len() as f32)
+}
+
+/// The r g b values of a color statement, missing ones repeat the last
+fn parse_rgb<'a, I>(
+    mut iter: I,
+) -> Result<Color, Box<dyn Error + Send + Sync>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let r = iter.next().unwrap_or("0").parse()?;
+    let g = iter.next().map_or(Ok(r), |s| s.parse())?;
+    let b = iter.next().map_or(Ok(g), |s| s.parse())?;
+    Ok(Color::from_values(r, g, b))
+}
+
+/// Texture statements may have options in front of the file name, like
+/// `map_Bump -bm 0.5 bump.png`, so the name is the last argument
+fn parse_map<'a, I>(
+    iter: I,
+) -> Result<String, Box<dyn Error + Send + Sync>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    iter.last()
+        .map(|s| s.to_owned())
+        .ok_or_else(|| "Missing texture name in mtl file".into())
+}
+
+/// Parses all materials of a mtl file, in the order they are defined.
+/// Texture paths are relative to the file.
+pub fn parse_mtl(
+    filepath: &Path,
+) -> Result<Vec<(String, MeshMaterial)>, Box<dyn Error + Send + Sync>> {
+    let dir = filepath.parent().unwrap_or(Path::new(""));
+    let mut entries: Vec<MtlEntry> = vec![];
+
+    let file = File::open(filepath)?;
+    let reader = BufReader::new(file);
+    for line in reader.lines() {
+        let line = line?;
+        let mut iter = line.split_whitespace();
+        let keyword = match iter.next() {
+            Some(s) => s,
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            let name = iter.collect::<Vec<_>>().join(" ");
+            entries.push(MtlEntry::new(name));
+            continue;
+        }
+
+        // Everything else belongs to the last newmtl
+        let entry = match entries.last_mut() {
+            Some(entry) => entry,
+            None => continue,
+        };
+        match keyword {
+            "Ka" => entry.ambient = parse_scalar(iter)?,
+            "Kd" => entry.diffuse = parse_rgb(iter)?,
+            "Ke" => entry.emission = parse_rgb(iter)?,
+            "Ks" => entry.specular = parse_scalar(iter)?,
+            "Ns" => entry.exponent = parse_scalar(iter)?,
+            "Ni" => entry.refraction = parse_scalar(iter)?,
+            "d" => entry.transmittance = 1. - parse_scalar(iter)?,
+            "Tr" => entry.transmittance = parse_scalar(iter)?,
+            "illum" => entry.illum = iter.next().unwrap_or("2").parse()?,
+            "map_Kd" => entry.diffuse_map = Some(parse_map(iter)?),
+            "map_Bump" | "map_bump" | "bump" | "norm" => {
+                entry.bump_map = Some(parse_map(iter)?)
+            }
+            _ => {}
+        }
+    }
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| (entry.name.clone(), entry.into_mesh_material(dir)))
+        .collect())
+}
@@ -1,35 +1,84 @@
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     error::Error,
-    fs::File,
+    fs::{self, File},
     io::{BufReader, BufRead},
     collections::HashMap,
-    sync::Arc, vec
+    sync::{Arc, Mutex, OnceLock, Weak},
 };
 use crate::{
-    math::{Vec3, Point3, Mat4},
+    math::{Color, Vec3, Point3, Mat4},
     ray::{Hittable, HitRecord, Ray},
     kdtree::AABB,
+    bvh::BvhTree,
+    light::Emitters,
     surface::{Material, Transform, ColorLookup},
+    utils::{
+        mtlparser::{parse_mtl, MeshMaterial},
+        plyparser::parse_ply,
+        stlparser::parse_stl,
+    },
 };
 
 const EPSILON: f32 = 0.0000001;
 
+/// Crease angle in degrees for meshes that don't specify one
+pub const DEFAULT_CREASE_ANGLE: f32 = 60.;
+
+// Hits with a lower alpha get cut out, the search for the next one
+// starts this far behind them
+const ALPHA_CUTOFF: f32 = 0.5;
+const CUTOUT_STEP: f32 = 1e-4;
+
+// Meshes loaded so far, so that a mesh file referenced by several
+// <mesh> elements only gets parsed and stored once. Keyed by the path
+// and crease angle.
+static LOADED_MESHES: Mutex<Vec<(PathBuf, f32, Weak<Mesh>)>> =
+    Mutex::new(Vec::new());
+
 pub type Index = u32;
 
+// t, the vertex indices and the barycentric coords of a triangle hit
+type Intersection = (f32, (usize, usize, usize), (f32, f32));
+
+/// The geometry of a mesh file in object space together with its own
+/// bvh. Shared between all instances of the file.
 pub struct Mesh {
     pub vertices: Vec<Point3>,
     pub normals: Vec<Vec3>,
-    pub tangents: Vec<Vec3>,
-    pub bitangents: Vec<Vec3>,
     pub texcoords: Vec<Vec3>,
-    pub material: Arc<dyn Material>,
-    pub normal_map: Option<Box<dyn ColorLookup>>,
-    pub transform: Option<Transform>,
+    // Empty if the file has no second set of texture coords
+    pub second_texcoords: Vec<Vec3>,
+    // Empty if the file has no vertex colors
+    pub colors: Vec<Color>,
+    pub materials: Vec<MeshMaterial>,
+    triangles: Vec<Triangle>,
+    bvh: BvhTree,
+    // Only needed for normal mapping, so only computed once an
+    // instance with a normal map asks for them. One per uv set.
+    tangents: [OnceLock<Tangents>; 2],
+}
+
+struct Tangents {
+    tangents: Vec<Vec3>,
+    bitangents: Vec<Vec3>,
+}
+
+/// One placement of a mesh in the scene, with its own transform. The
+/// material overrides the ones from the mtl files of the mesh.
+pub struct MeshInstance {
+    mesh: Arc<Mesh>,
+    material: Option<Arc<dyn Material>>,
+    normal_map: Option<Box<dyn ColorLookup>>,
+    transform: Option<Transform>,
+    bounds: AABB,
+    // Whether any of the materials has an alpha channel
+    has_cutouts: bool,
 }
 
-pub struct Triangle {
+struct Triangle {
     vertices: [Index; 3],
+    material: Option<Index>,
     hit_normal: Vec3,
     hit_d: f32,
     hit_edge1: Vec3,
@@ -39,26 +88,20 @@ pub struct Triangle {
 impl Triangle {
     fn get_intersection(
         &self,
-        mesh: &Mesh,
-        r: &Ray,
+        mesh_vertices: &[Point3],
+        tr: &Ray,
         t_min: f32,
         t_max: f32,
-    ) -> Option<(f32, (usize, usize, usize), (f32, f32))> {
+    ) -> Option<Intersection> {
         let Triangle {
             vertices,
             hit_normal,
             hit_d,
             hit_edge1,
             hit_edge2,
+            ..
         } = &self;
 
-        // Transform ray if we have transforms
-        let tr = mesh.transform.as_ref().map_or(r.clone(), |t| {
-            let origin = &t.world_to_object * &r.orig;
-            let direction = t.world_to_object.mul_dir(&r.dir);
-            Ray::from_values(&origin, &direction)
-        });
-
         // Check if ray is parallel to triangle
         let ndir = hit_normal.dot(&tr.dir);
         if ndir.abs() < EPSILON {
@@ -80,7 +123,7 @@ impl Triangle {
         let vi2 = vertices[2] as usize;
 
         // Do inside/outside test for triangle
-        let v0 = &mesh.vertices[vi0];
+        let v0 = &mesh_vertices[vi0];
         let h = tr.dir.cross(&hit_edge2);
         let a = hit_edge1.dot(&h);
         let f = 1. / a;
@@ -101,161 +144,186 @@ impl Triangle {
 
         Some((t, (vi0, vi1, vi2), (u, v)))
     }
+
+    /// The barycentric coords where the ray meets the plane of the
+    /// triangle, which may be outside of the triangle
+    fn plane_barycentrics(
+        &self,
+        mesh_vertices: &[Point3],
+        tr: &Ray,
+    ) -> Option<(f32, f32)> {
+        let ndir = self.hit_normal.dot(&tr.dir);
+        if ndir.abs() < EPSILON {
+            return None;
+        }
+        let t = -(self.hit_normal.dot(&tr.orig) + self.hit_d) / ndir;
+        let w = tr.at(t) - &mesh_vertices[self.vertices[0] as usize];
+
+        // Solve w = u * edge1 + v * edge2
+        let e1 = &self.hit_edge1;
+        let e2 = &self.hit_edge2;
+        let (d11, d12, d22) = (e1.dot(e1), e1.dot(e2), e2.dot(e2));
+        let (dw1, dw2) = (w.dot(e1), w.dot(e2));
+        let denom = d11 * d22 - d12 * d12;
+        let u = (d22 * dw1 - d12 * dw2) / denom;
+        let v = (d11 * dw2 - d12 * dw1) / denom;
+
+        Some((u, v))
+    }
+
+    fn bound(&self, mesh_vertices: &[Point3]) -> AABB {
+        let mut aabb = AABB::empty();
+        for vi in self.vertices {
+            let v = &mesh_vertices[vi as usize];
+            aabb.min.assign_min(v);
+            aabb.max.assign_max(v);
+        }
+        aabb
+    }
 }
 
-pub struct MeshTriangle {
-    triangle: Triangle,
-    mesh: Arc<Mesh>,
+impl Mesh {
+    /// Whether some faces didn't get a material from a mtl file
+    pub fn has_faces_without_material(&self) -> bool {
+        self.triangles.iter().any(|t| t.material.is_none())
+    }
+
+    fn tangents(&self, uv_set: usize) -> &Tangents {
+        // Meshes without a second set use the first one for both
+        let uv_set = if self.second_texcoords.is_empty() {
+            0
+        } else {
+            uv_set.min(1)
+        };
+        let texcoords = [&self.texcoords, &self.second_texcoords][uv_set];
+        self.tangents[uv_set]
+            .get_or_init(|| compute_tangents(texcoords, &self.triangles))
+    }
 }
 
-impl Hittable for MeshTriangle {
-    fn hit(
-        &self,
-        r: &Ray,
-        t_min: f32,
-        t_max: f32,
-    ) -> Option<HitRecord> {
-        let isect = match self
-            .triangle
-            .get_intersection(&self.mesh, r, t_min, t_max)
-        {
-            None => return None,
-            Some(v) => v,
+impl MeshInstance {
+    pub fn new(
+        mesh: Arc<Mesh>,
+        material: Option<Arc<dyn Material>>,
+        normal_map: Option<Box<dyn ColorLookup>>,
+        transform: Option<Transform>,
+    ) -> Self {
+        // Compute the tangents before rendering starts, instead of
+        // blocking all threads on the first hit
+        let mtl_normal_maps = mesh
+            .materials
+            .iter()
+            .filter(|_| material.is_none())
+            .filter_map(|m| m.normal_map.as_ref());
+        for map in normal_map.iter().chain(mtl_normal_maps) {
+            mesh.tangents(map.uv_set());
+        }
+
+        // Transforming the vertices gives a tighter box than
+        // transforming the corners of the mesh bounds
+        let mut bounds = AABB::empty();
+        for vertex in &mesh.vertices {
+            let v = transform
+                .as_ref()
+                .map_or(vertex.clone(), |t| &t.object_to_world * vertex);
+            bounds.min.assign_min(&v);
+            bounds.max.assign_max(&v);
+        }
+
+        let has_cutouts = match &material {
+            Some(material) => material.has_alpha(),
+            None => mesh.materials.iter().any(|m| m.material.has_alpha()),
         };
-        let (t, (vi0, vi1, vi2), (u, v)) = isect;
 
-        // Interpolate the normal using barycentric coords
-        let n0 = &self.mesh.normals[vi0];
-        let n1 = &self.mesh.normals[vi1];
-        let n2 = &self.mesh.normals[vi2];
-        let outward_normal = u * n1 + v * n2 + (1. - u - v) * n0;
-        let transformed_normal = self
-            .mesh
-            .transform
-            .as_ref()
-            .map_or(outward_normal.clone(), |t| {
-                t.normal_matrix.mul_dir(&outward_normal)
-            });
+        Self {
+            mesh,
+            material,
+            normal_map,
+            transform,
+            bounds,
+            has_cutouts,
+        }
+    }
 
-        // Interpolate the texture coords using barycentric coords
-        let t0 = &self.mesh.texcoords[vi0];
-        let t1 = &self.mesh.texcoords[vi1];
-        let t2 = &self.mesh.texcoords[vi2];
-        let tex_coords = u * t1 + v * t2 + (1. - u - v) * t0;
+    pub fn is_empty(&self) -> bool {
+        self.mesh.triangles.is_empty()
+    }
 
-        // Update hitrecord
-        let p = r.at(t);
-        Some(HitRecord::from_values(
-            r,
-            p,
-            &transformed_normal.unit_vector(),
-            t,
-            tex_coords,
-            self.mesh.material.clone(),
-        ))
+    fn object_ray(&self, r: &Ray) -> Ray {
+        // Transform ray if we have transforms
+        self.transform.as_ref().map_or(r.clone(), |t| {
+            let origin = &t.world_to_object * &r.orig;
+            let direction = t.world_to_object.mul_dir(&r.dir);
+            Ray::from_values(&origin, &direction)
+        })
     }
 
-    fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
-        self.triangle
-            .get_intersection(&self.mesh, r, t_min, t_max)
-            .is_some()
+    fn to_world_dir(&self, dir: &Vec3) -> Vec3 {
+        self.transform
+            .as_ref()
+            .map_or(dir.clone(), |t| t.normal_matrix.mul_dir(dir))
+            .unit_vector()
     }
 
-    fn bound(&self) -> AABB {
-        let mut aabb: AABB = Default::default();
-
-        if let Some(t) = self.mesh.transform.as_ref() {
-            for i in 0..3 {
-                let vi = self.triangle.vertices[i] as usize;
-                let v = &t.object_to_world * &self.mesh.vertices[vi];
-                aabb.min.assign_min(&v);
-                aabb.max.assign_max(&v);
-            }
-        } else {
-            for i in 0..3 {
-                let vi = self.triangle.vertices[i] as usize;
-                let v = &self.mesh.vertices[vi];
-                aabb.min.assign_min(&v);
-                aabb.max.assign_max(&v);
+    /// The closest triangle the ray in object space hits
+    fn closest_triangle(
+        &self,
+        tr: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(Intersection, &Triangle)> {
+        let mesh = &self.mesh;
+
+        let mut isect = None;
+        mesh.bvh.traverse(tr, t_min, t_max, |range, mut closest| {
+            for triangle in &mesh.triangles[range] {
+                let hit = triangle.get_intersection(
+                    &mesh.vertices,
+                    tr,
+                    t_min,
+                    closest,
+                );
+                if let Some(hit) = hit {
+                    closest = hit.0;
+                    isect = Some((hit, triangle));
+                }
             }
-        }
+            closest
+        });
 
-        aabb
+        isect
     }
-}
 
-pub struct MeshTangentTriangle {
-    triangle: Triangle,
-    mesh: Arc<Mesh>,
-}
-
-impl Hittable for MeshTangentTriangle {
-    fn hit(
+    /// The hit record of a triangle hit, in world space
+    fn hit_record(
         &self,
         r: &Ray,
-        t_min: f32,
-        t_max: f32,
-    ) -> Option<HitRecord> {
-        let isect = match self
-            .triangle
-            .get_intersection(&self.mesh, r, t_min, t_max)
-        {
-            None => return None,
-            Some(v) => v,
+        (t, indices, uv): Intersection,
+        triangle: &Triangle,
+    ) -> HitRecord {
+        let mesh = &self.mesh;
+        let face_material = triangle.material;
+
+        // Faces without a mtl material only exist if the instance has
+        // a material of its own, see parse_mesh
+        let (material, normal_map) = match &self.material {
+            Some(material) => (material, &self.normal_map),
+            None => {
+                let mtl = &mesh.materials[face_material.unwrap() as usize];
+                let normal_map = match self.normal_map {
+                    Some(_) => &self.normal_map,
+                    None => &mtl.normal_map,
+                };
+                (&mtl.material, normal_map)
+            }
         };
-        let (t, (vi0, vi1, vi2), (u, v)) = isect;
 
         // Interpolate the normal using barycentric coords
-        let n0 = &self.mesh.normals[vi0];
-        let n1 = &self.mesh.normals[vi1];
-        let n2 = &self.mesh.normals[vi2];
-        let outward_normal = u * n1 + v * n2 + (1. - u - v) * n0;
-        let transformed_normal = self
-            .mesh
-            .transform
-            .as_ref()
-            .map_or(outward_normal.clone(), |t| {
-                t.normal_matrix.mul_dir(&outward_normal).unit_vector()
-            });
-
-        // Interpolate the tangent using barycentric coords
-        let t0 = &self.mesh.tangents[vi0];
-        let t1 = &self.mesh.tangents[vi1];
-        let t2 = &self.mesh.tangents[vi2];
-        let tangent = u * t1 + v * t2 + (1. - u - v) * t0;
-        let transformed_tangent = self
-            .mesh
-            .transform
-            .as_ref()
-            .map_or(tangent.clone(), |t| {
-                t.normal_matrix.mul_dir(&tangent).unit_vector()
-            });
-
-        // Interpolate the bitangent using barycentric coords
-        let b0 = &self.mesh.bitangents[vi0];
-        let b1 = &self.mesh.bitangents[vi1];
-        let b2 = &self.mesh.bitangents[vi2];
-        let bitangent = u * b1 + v * b2 + (1. - u - v) * b0;
-        let transformed_bitangent = self
-            .mesh
-            .transform
-            .as_ref()
-            .map_or(bitangent.clone(), |t| {
-                t.normal_matrix.mul_dir(&bitangent).unit_vector()
-            });
-
-        // Create TBN matrix
-        let tbn = Mat4::tbn(
-            &transformed_tangent,
-            &transformed_bitangent,
-            &transformed_normal,
-        );
+        let outward_normal = interpolate(&mesh.normals, indices, uv);
+        let transformed_normal = self.to_world_dir(&outward_normal);
 
         // Interpolate the texture coords using barycentric coords
-        let t0 = &self.mesh.texcoords[vi0];
-        let t1 = &self.mesh.texcoords[vi1];
-        let t2 = &self.mesh.texcoords[vi2];
-        let tex_coords = u * t1 + v * t2 + (1. - u - v) * t0;
+        let tex_coords = interpolate(&mesh.texcoords, indices, uv);
 
         // Create the hitrecord
         let p = r.at(t);
@@ -265,11 +333,59 @@ impl Hittable for MeshTangentTriangle {
             &transformed_normal,
             t,
             tex_coords,
-            self.mesh.material.clone(),
+            material.clone(),
         );
+        if !mesh.second_texcoords.is_empty() {
+            hit.second_tex_coords =
+                Some(interpolate(&mesh.second_texcoords, indices, uv));
+        }
+
+        // The texture coords where the rays of the neighbouring pixels
+        // meet the triangle's plane give the footprint of the pixel
+        if let Some(d) = &r.differentials {
+            let rx = self.object_ray(&Ray::from_values(&d.rx_orig, &d.rx_dir));
+            let ry = self.object_ray(&Ray::from_values(&d.ry_orig, &d.ry_dir));
+            let uv_x = triangle.plane_barycentrics(&mesh.vertices, &rx);
+            let uv_y = triangle.plane_barycentrics(&mesh.vertices, &ry);
+            if let (Some(uv_x), Some(uv_y)) = (uv_x, uv_y) {
+                let derivatives = |values: &[Vec3], at: &Vec3| {
+                    (
+                        interpolate(values, indices, uv_x) - at,
+                        interpolate(values, indices, uv_y) - at,
+                    )
+                };
+                hit.tex_derivatives =
+                    Some(derivatives(&mesh.texcoords, &hit.tex_coords));
+                hit.second_tex_derivatives = hit
+                    .second_tex_coords
+                    .as_ref()
+                    .map(|at| derivatives(&mesh.second_texcoords, at));
+            }
+        }
+        if self.transform.is_some() {
+            hit.object_p = Some(self.object_ray(r).at(t));
+        }
+        if !mesh.colors.is_empty() {
+            hit.vertex_color = Some(interpolate(&mesh.colors, indices, uv));
+        }
 
         // Read the normal from the normal map (if there is one)
-        if let Some(ref normal_map) = self.mesh.normal_map {
+        if let Some(normal_map) = normal_map {
+            let Tangents {
+                tangents,
+                bitangents,
+            } = mesh.tangents(normal_map.uv_set());
+
+            // Interpolate the tangent and bitangent using barycentric
+            // coords and create the TBN matrix
+            let tangent = interpolate(tangents, indices, uv);
+            let bitangent = interpolate(bitangents, indices, uv);
+            let tbn = Mat4::tbn(
+                &self.to_world_dir(&tangent),
+                &self.to_world_dir(&bitangent),
+                &transformed_normal,
+            );
+
             let nt = normal_map.color(r, &hit) * 2.
                 - Vec3::from_values(1., 1., 1.);
 
@@ -279,65 +395,305 @@ impl Hittable for MeshTangentTriangle {
             hit.normal = tbn.mul_dir(&nt).unit_vector();
         }
 
-        Some(hit)
+        hit
+    }
+}
+
+impl Hittable for MeshInstance {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord> {
+        let tr = self.object_ray(r);
+
+        // The transform is affine, so t is the same in both spaces.
+        // Hits the material cuts out are skipped.
+        let mut t_start = t_min;
+        loop {
+            let (isect, triangle) =
+                self.closest_triangle(&tr, t_start, t_max)?;
+            let hit = self.hit_record(r, isect, triangle);
+            if !hit.material.has_alpha()
+                || hit.material.alpha(r, &hit) >= ALPHA_CUTOFF
+            {
+                return Some(hit);
+            }
+            t_start = hit.t + CUTOUT_STEP;
+        }
     }
 
     fn shadow_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
-        self.triangle
-            .get_intersection(&self.mesh, r, t_min, t_max)
-            .is_some()
+        // Cutouts need the texture coords of the hits
+        if self.has_cutouts {
+            return self.hit(r, t_min, t_max).is_some();
+        }
+
+        let mesh = &self.mesh;
+        let tr = self.object_ray(r);
+
+        mesh.bvh.any(&tr, t_min, t_max, |range| {
+            mesh.triangles[range].iter().any(|triangle| {
+                triangle
+                    .get_intersection(&mesh.vertices, &tr, t_min, t_max)
+                    .is_some()
+            })
+        })
     }
 
     fn bound(&self) -> AABB {
-        let mut aabb: AABB = Default::default();
-
-        if let Some(t) = self.mesh.transform.as_ref() {
-            for i in 0..3 {
-                let vi = self.triangle.vertices[i] as usize;
-                let v = &t.object_to_world * &self.mesh.vertices[vi];
-                aabb.min.assign_min(&v);
-                aabb.max.assign_max(&v);
-            }
-        } else {
-            for i in 0..3 {
-                let vi = self.triangle.vertices[i] as usize;
-                let v = &self.mesh.vertices[vi];
-                aabb.min.assign_min(&v);
-                aabb.max.assign_max(&v);
+        self.bounds.clone()
+    }
+
+    fn collect_emitters(&self, emitters: &mut Emitters) {
+        let mesh = &self.mesh;
+        for triangle in &mesh.triangles {
+            let material = match &self.material {
+                Some(material) => material,
+                None => {
+                    let index = triangle.material.unwrap() as usize;
+                    &mesh.materials[index].material
+                }
+            };
+            let emission = match material.emission() {
+                Some(emission) => emission,
+                None => continue,
+            };
+
+            let vertices = triangle.vertices.map(|i| {
+                let v = &mesh.vertices[i as usize];
+                self.transform
+                    .as_ref()
+                    .map_or(v.clone(), |t| &t.object_to_world * v)
+            });
+            emitters.add_triangle(vertices, emission, material);
+        }
+    }
+}
+
+fn interpolate(
+    values: &[Vec3],
+    (vi0, vi1, vi2): (usize, usize, usize),
+    (u, v): (f32, f32),
+) -> Vec3 {
+    u * &values[vi1] + v * &values[vi2] + (1. - u - v) * &values[vi0]
+}
+
+/// Returns the mesh stored in `filepath`, which is only parsed if no
+/// other instance loaded it already (with the same crease angle)
+pub fn load_mesh(
+    filepath: &Path,
+    crease_angle: f32,
+) -> Result<Arc<Mesh>, Box<dyn Error + Send + Sync>> {
+    let filepath = fs::canonicalize(filepath)?;
+    let mut loaded = LOADED_MESHES.lock().unwrap();
+    loaded.retain(|(_, _, mesh)| mesh.strong_count() > 0);
+
+    let cached = loaded
+        .iter()
+        .find(|(path, angle, _)| {
+            *path == filepath && *angle == crease_angle
+        })
+        .and_then(|(_, _, mesh)| mesh.upgrade());
+    if let Some(mesh) = cached {
+        return Ok(mesh);
+    }
+
+    // Dispatch on the file extension
+    let extension = filepath
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    let mesh = match extension.as_deref() {
+        Some("obj") => parse_obj(&filepath)?,
+        Some("ply") => parse_ply(&filepath)?,
+        Some("stl") => parse_stl(&filepath)?,
+        _ => {
+            return Err(format!(
+                "Unsupported mesh format: {}",
+                filepath.display()
+            )
+            .into())
+        }
+    };
+    let mesh = Arc::new(mesh.into_mesh(crease_angle));
+    loaded.push((filepath, crease_angle, Arc::downgrade(&mesh)));
+    Ok(mesh)
+}
+
+/// One corner of a face, as indices into the vertices, texture coords
+/// and normals of the mesh file
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Corner {
+    pub vertex: usize,
+    pub texcoord: Option<usize>,
+    pub second_texcoord: Option<usize>,
+    pub normal: Option<usize>,
+}
+
+/// The contents of a mesh file, with faces indexing the attributes
+/// separately like in obj files. Colors belong to the vertices, so
+/// they are either missing or there is one per vertex.
+#[derive(Default)]
+pub struct MeshData {
+    pub vertices: Vec<Point3>,
+    pub normals: Vec<Vec3>,
+    pub texcoords: Vec<Vec3>,
+    pub second_texcoords: Vec<Vec3>,
+    pub colors: Vec<Color>,
+    pub faces: Vec<[Corner; 3]>,
+    pub face_materials: Vec<Option<Index>>,
+    pub materials: Vec<MeshMaterial>,
+}
+
+impl MeshData {
+    /// Adds a polygon, triangulating it if it has more than three
+    /// corners
+    pub fn add_polygon(
+        &mut self,
+        corners: &[Corner],
+        material: Option<Index>,
+    ) {
+        if corners.len() < 3 {
+            return;
+        }
+
+        let polygon: Vec<_> = corners
+            .iter()
+            .map(|corner| self.vertices[corner.vertex].clone())
+            .collect();
+        for [a, b, c] in triangulate(&polygon) {
+            self.faces.push([corners[a], corners[b], corners[c]]);
+            self.face_materials.push(material);
+        }
+    }
+
+    /// Creates the triangles and their bvh. Corners without a normal
+    /// get a smoothed one, averaged over the faces around the vertex
+    /// that meet at less than `crease_angle` degrees.
+    pub fn into_mesh(self, crease_angle: f32) -> Mesh {
+        let MeshData {
+            vertices: base_vertices,
+            normals: mut base_normals,
+            texcoords: base_texcoords,
+            second_texcoords: base_second_texcoords,
+            colors: base_colors,
+            mut faces,
+            face_materials,
+            materials,
+        } = self;
+
+        smooth_normals(
+            &base_vertices,
+            &mut base_normals,
+            &mut faces,
+            crease_angle,
+        );
+
+        // Create a vertex for every distinct corner
+        let mut index_mapping = HashMap::new();
+        let mut vertices = vec![];
+        let mut normals = vec![];
+        let mut texcoords = vec![];
+        let mut second_texcoords = vec![];
+        let mut colors = vec![];
+        let mut triangles: Vec<Triangle> = vec![];
+        for (face, material) in faces.iter().zip(face_materials) {
+            let mut triangle = Triangle {
+                vertices: [0; 3],
+                material,
+                hit_normal: Vec3::new(),
+                hit_d: 0.,
+                hit_edge1: Vec3::new(),
+                hit_edge2: Vec3::new(),
+            };
+
+            for (i, corner) in face.iter().enumerate() {
+                // Check if we already know the corner
+                if let Some(index) = index_mapping.get(corner) {
+                    triangle.vertices[i] = *index;
+                    continue;
+                }
+
+                // Only add texcoords if we have them
+                let texcoord = corner
+                    .texcoord
+                    .map_or(Vec3::new(), |t| base_texcoords[t].clone());
+                let normal = corner.normal.map_or(Vec3::new(), |n| {
+                    base_normals[n].clone()
+                });
+
+                let index = vertices.len() as Index;
+                vertices.push(base_vertices[corner.vertex].clone());
+                normals.push(normal);
+                texcoords.push(texcoord);
+                if !base_colors.is_empty() {
+                    colors.push(base_colors[corner.vertex].clone());
+                }
+                if !base_second_texcoords.is_empty() {
+                    second_texcoords.push(corner.second_texcoord.map_or(
+                        Vec3::new(),
+                        |t| base_second_texcoords[t].clone(),
+                    ));
+                }
+                index_mapping.insert(*corner, index);
+                triangle.vertices[i] = index;
             }
+
+            // Pre-compute plane normal for hition test
+            let v0 = &vertices[triangle.vertices[0] as usize];
+            let v1 = &vertices[triangle.vertices[1] as usize];
+            let v2 = &vertices[triangle.vertices[2] as usize];
+            let v0v1 = v1 - v0;
+            let v0v2 = v2 - v0;
+            let n = v0v1.cross(&v0v2);
+            triangle.hit_d = -n.dot(v0);
+            triangle.hit_normal = n;
+            triangle.hit_edge1 = v0v1;
+            triangle.hit_edge2 = v0v2;
+
+            triangles.push(triangle);
         }
 
-        aabb
+        // Store the triangles in bvh order, so every leaf covers a
+        // contiguous range. The bvh is built whatever `accel` says and
+        // isn't cached, unlike the tree over the instances.
+        let bounds: Vec<AABB> =
+            triangles.iter().map(|t| t.bound(&vertices)).collect();
+        let (bvh, order) = BvhTree::build(&bounds);
+        let mut triangles: Vec<_> =
+            triangles.into_iter().map(Some).collect();
+        let triangles = order
+            .into_iter()
+            .map(|i| triangles[i].take().unwrap())
+            .collect();
+
+        Mesh {
+            vertices,
+            normals,
+            texcoords,
+            second_texcoords,
+            colors,
+            materials,
+            triangles,
+            bvh,
+            tangents: [OnceLock::new(), OnceLock::new()],
+        }
     }
 }
 
+/// Parses an obj file, faces with more than three corners get
+/// triangulated
 pub fn parse_obj(
     filepath: &Path,
-    material: Arc<dyn Material>,
-    normal_map: Option<Box<dyn ColorLookup>>,
-    transform: Option<Transform>,
-) -> Result<Vec<Box<dyn Hittable>>, Box<dyn Error + Send + Sync>> {
-    let has_normal_map = normal_map.is_some();
-    let mut index_mapping = HashMap::new();
-    let mut base_vertices = vec![];
-    let mut base_normals = vec![];
-    let mut base_texcoords = vec![];
-    let mut mesh = Mesh {
-        vertices: vec![],
-        normals: vec![],
-        texcoords: vec![],
-        tangents: vec![],
-        bitangents: vec![],
-        normal_map,
-        material,
-        transform,
-    };
-    let mut triangles: Vec<Triangle> = vec![];
-    let mut next_index = 0;
+) -> Result<MeshData, Box<dyn Error + Send + Sync>> {
+    let mut data = MeshData::default();
+    let mut material_names = HashMap::new();
+    let mut current_material = None;
 
     let file = File::open(filepath)?;
     let reader = BufReader::new(file);
-    'lines: for line in reader.lines() {
+    for line in reader.lines() {
         let line = line?;
         let mut iter = line.split_whitespace();
         let keyword = match iter.next() {
@@ -345,7 +701,7 @@ pub fn parse_obj(
             None => continue,
         };
 
-        // Vertices and normals
+        // Vertices, optionally with a color, and normals
         if keyword == "v" || keyword == "vn" {
             let x = match iter.next() {
                 Some(s) => s.parse()?,
@@ -362,14 +718,15 @@ pub fn parse_obj(
 
             if keyword == "v" {
                 let v = Point3::from_values(x, y, z);
-                base_vertices.push(v);
+                data.vertices.push(v);
+                add_vertex_color(&mut data, iter)?;
             } else {
-                base_normals.push(Vec3::from_values(x, y, z));
+                data.normals.push(Vec3::from_values(x, y, z));
             }
             continue;
         }
 
-        // Texture coordinates
+        // Texture coordinates, v is optional
         if keyword == "vt" {
             let u = match iter.next() {
                 Some(s) => s.parse()?,
@@ -377,178 +734,358 @@ pub fn parse_obj(
             };
             let v = match iter.next() {
                 Some(s) => s.parse()?,
-                None => continue,
+                None => 0.,
             };
 
-            base_texcoords.push(Vec3::from_values(u, v, 0.));
+            data.texcoords.push(Vec3::from_values(u, v, 0.));
             continue;
         }
 
-        // Faces
-        if keyword == "f" {
-            let mut triangle = Triangle {
-                vertices: [0; 3],
-                hit_normal: Vec3::new(),
-                hit_d: 0.,
-                hit_edge1: Vec3::new(),
-                hit_edge2: Vec3::new(),
-            };
-
-            for i in 0..3 {
-                let indices = match iter.next() {
-                    Some(s) => s,
-                    None => continue 'lines,
+        // Material libraries, relative to the obj file. Meshes with an
+        // XML material don't need them, so they aren't fatal.
+        if keyword == "mtllib" {
+            let dir = filepath.parent().unwrap_or(Path::new(""));
+            for name in iter {
+                let library = match parse_mtl(&dir.join(name)) {
+                    Ok(library) => library,
+                    Err(e) => {
+                        println!("Ignoring material library {}: {}", name, e);
+                        continue;
+                    }
                 };
-
-                // Check if we already know the index_group
-                if let Some(index) = index_mapping.get(indices) {
-                    triangle.vertices[i] = *index;
-                    continue;
+                for (name, material) in library {
+                    let index = data.materials.len() as Index;
+                    material_names.insert(name, index);
+                    data.materials.push(material);
                 }
+            }
+            continue;
+        }
 
-                // Otherwise, parse everything
-                let mut vertex_iter = indices.split('/');
-                let coords = match vertex_iter.next() {
-                    Some(s) => s.parse::<usize>()? - 1,
-                    None => continue 'lines,
-                };
-                let tex = match vertex_iter.next() {
-                    Some(s) => s.parse::<usize>().unwrap_or(1) - 1,
-                    None => continue 'lines,
+        // Material of the following faces
+        if keyword == "usemtl" {
+            let name = iter.collect::<Vec<_>>().join(" ");
+            current_material = material_names.get(&name).copied();
+            continue;
+        }
+
+        // Faces, with index groups of the form v, v/vt, v//vn or
+        // v/vt/vn
+        if keyword == "f" {
+            let mut corners = vec![];
+            for group in iter {
+                let mut index_iter = group.split('/');
+                let vertex = match index_iter.next() {
+                    Some(s) => parse_index(s, data.vertices.len())?,
+                    None => continue,
                 };
-                let normal = match vertex_iter.next() {
-                    Some(s) => s.parse::<usize>()? - 1,
-                    None => continue 'lines,
+                let texcoord = match index_iter.next() {
+                    Some(s) if !s.is_empty() => {
+                        Some(parse_index(s, data.texcoords.len())?)
+                    }
+                    _ => None,
                 };
-
-                // Only add texcoords if we have them
-                let texcoord = if base_texcoords.len() > 0 {
-                    base_texcoords[tex].clone()
-                } else {
-                    Vec3::new()
+                let normal = match index_iter.next() {
+                    Some(s) if !s.is_empty() => {
+                        Some(parse_index(s, data.normals.len())?)
+                    }
+                    _ => None,
                 };
 
-                mesh.vertices.push(base_vertices[coords].clone());
-                mesh.normals.push(base_normals[normal].clone());
-                mesh.texcoords.push(texcoord);
-                index_mapping.insert(indices.to_owned(), next_index);
-                triangle.vertices[i] = next_index;
-                next_index += 1;
+                corners.push(Corner {
+                    vertex,
+                    texcoord,
+                    second_texcoord: None,
+                    normal,
+                });
             }
 
-            // Pre-compute plane normal for hition test
-            let v0 = &mesh.vertices[triangle.vertices[0] as usize];
-            let v1 = &mesh.vertices[triangle.vertices[1] as usize];
-            let v2 = &mesh.vertices[triangle.vertices[2] as usize];
-            let v0v1 = v1 - v0;
-            let v0v2 = v2 - v0;
-            let n = v0v1.cross(&v0v2);
-            triangle.hit_d = -n.dot(v0);
-            triangle.hit_normal = n;
-            triangle.hit_edge1 = v0v1;
-            triangle.hit_edge2 = v0v2;
-
-            triangles.push(triangle);
+            data.add_polygon(&corners, current_material);
             continue;
         }
     }
 
-    if has_normal_map {
-        let num_vertices = mesh.vertices.len();
-        let mut num_tangents = vec![0; num_vertices];
-        let mut tangents = vec![Vec3::new(); num_vertices];
-        let mut bitangents = vec![Vec3::new(); num_vertices];
-
-        for triangle in &triangles {
-            let v0i = triangle.vertices[0] as usize;
-            let v1i = triangle.vertices[1] as usize;
-            let v2i = triangle.vertices[2] as usize;
-
-            let uv0 = &mesh.texcoords[v0i];
-            let uv1 = &mesh.texcoords[v1i];
-            let uv2 = &mesh.texcoords[v2i];
-
-            let delta_pos1 = &triangle.hit_edge1;
-            let delta_pos2 = &triangle.hit_edge2;
-
-            let delta_uv1 = uv1 - uv0;
-            let delta_uv2 = uv2 - uv0;
-
-            let r = 1.
-                / (delta_uv1.x * delta_uv2.y
-                    - delta_uv1.y * delta_uv2.x);
-            let tangent = r
-                * (delta_pos1 * delta_uv2.y
-                    - delta_pos2 * delta_uv1.y);
-            let bitangent = r
-                * (delta_pos2 * delta_uv1.x
-                    - delta_pos1 * delta_uv2.x);
-
-            // TODO: this might be REALLY sussy
-            //let n0 = &mesh.normals[v0i];
-            //let n1 = &mesh.normals[v1i];
-            //let n2 = &mesh.normals[v2i];
-
-            //tangents[v0i] += &tangent - n0 * n0.dot(&tangent);
-            //tangents[v1i] += &tangent - n1 * n1.dot(&tangent);
-            //tangents[v2i] += &tangent - n2 * n2.dot(&tangent);
-
-            //bitangents[v0i] += &bitangent - n0 * n0.dot(&bitangent);
-            //bitangents[v1i] += &bitangent - n1 * n1.dot(&bitangent);
-            //bitangents[v2i] += &bitangent - n2 * n2.dot(&bitangent);
-
-            tangents[v0i] += &tangent;
-            tangents[v1i] += &tangent;
-            tangents[v2i] += &tangent;
-
-            bitangents[v0i] += &bitangent;
-            bitangents[v1i] += &bitangent;
-            bitangents[v2i] += &bitangent;
-
-            num_tangents[v0i] += 1;
-            num_tangents[v1i] += 1;
-            num_tangents[v2i] += 1;
-        }
-
-        // Average the tangents for all vertices
-        let it = num_tangents
-            .into_iter()
-            .zip(tangents.iter_mut().zip(bitangents.iter_mut()));
-        for (n, (t, b)) in it {
-            if n == 0 {
-                continue;
-            }
+    Ok(data)
+}
 
-            let factor = 1. / n as f32;
-            *t *= factor;
-            *b *= factor;
+/// Reads the optional r g b values after the position of a vertex.
+/// Once a vertex has a color, the ones without get white.
+fn add_vertex_color<'a, I>(
+    data: &mut MeshData,
+    iter: I,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let values = iter
+        .take(3)
+        .map(|s| s.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let white = Color::from_values(1., 1., 1.);
+
+    match values[..] {
+        [r, g, b] => {
+            data.colors.resize(data.vertices.len() - 1, white);
+            data.colors.push(Color::from_values(r, g, b));
+        }
+        // A single value is the optional w coordinate
+        [] | [_] => {
+            if !data.colors.is_empty() {
+                data.colors.push(white);
+            }
         }
+        _ => return Err("Vertex color needs r g b values".into()),
+    }
 
-        mesh.tangents = tangents;
-        mesh.bitangents = bitangents;
+    Ok(())
+}
 
-        let mesh_pointer = Arc::new(mesh);
-        let hittables = triangles
-            .into_iter()
-            .map(|triangle| {
-                Box::new(MeshTangentTriangle {
-                    triangle,
-                    mesh: mesh_pointer.clone(),
-                }) as Box<dyn Hittable>
+/// Turns a 1-based obj index into a 0-based one. Negative indices count
+/// back from the last of the `count` elements read so far.
+fn parse_index(
+    s: &str,
+    count: usize,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let index: isize = s.parse()?;
+    let resolved = if index < 0 {
+        count as isize + index
+    } else {
+        index - 1
+    };
+
+    if resolved < 0 || resolved >= count as isize {
+        return Err(format!("Obj index {} out of range", s).into());
+    }
+    Ok(resolved as usize)
+}
+
+/// Splits a polygon into triangles by ear clipping, so concave polygons
+/// work as well. Returns indices into `polygon`.
+fn triangulate(polygon: &[Point3]) -> Vec<[usize; 3]> {
+    let n = polygon.len();
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    // Project onto the plane the polygon is facing the most, the newell
+    // normal also tells us the winding in that plane
+    let mut normal = Vec3::new();
+    for i in 0..n {
+        let a = &polygon[i];
+        let b = &polygon[(i + 1) % n];
+        normal += Vec3::from_values(
+            (a.y - b.y) * (a.z + b.z),
+            (a.z - b.z) * (a.x + b.x),
+            (a.x - b.x) * (a.y + b.y),
+        );
+    }
+    let (nx, ny, nz) = (normal.x.abs(), normal.y.abs(), normal.z.abs());
+    let (points, winding): (Vec<(f32, f32)>, f32) = if nx >= ny && nx >= nz
+    {
+        (polygon.iter().map(|p| (p.y, p.z)).collect(), normal.x)
+    } else if ny >= nz {
+        (polygon.iter().map(|p| (p.z, p.x)).collect(), normal.y)
+    } else {
+        (polygon.iter().map(|p| (p.x, p.y)).collect(), normal.z)
+    };
+    let winding = if winding < 0. { -1. } else { 1. };
+
+    let cross = |a: usize, b: usize, c: usize| {
+        let (ax, ay) = points[a];
+        let (bx, by) = points[b];
+        let (cx, cy) = points[c];
+        winding * ((bx - ax) * (cy - ay) - (by - ay) * (cx - ax))
+    };
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = vec![];
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let ear = (0..m).find(|&i| {
+            let a = remaining[(i + m - 1) % m];
+            let b = remaining[i];
+            let c = remaining[(i + 1) % m];
+            if cross(a, b, c) <= 0. {
+                return false;
+            }
+
+            // No other corner may lie inside the ear
+            remaining.iter().all(|&p| {
+                p == a
+                    || p == b
+                    || p == c
+                    || cross(a, b, p) < 0.
+                    || cross(b, c, p) < 0.
+                    || cross(c, a, p) < 0.
             })
-            .collect();
-        return Ok(hittables);
+        });
+
+        // Degenerate or self intersecting polygons may not have any
+        // ears left, fall back to a fan for the rest
+        let i = match ear {
+            Some(i) => i,
+            None => break,
+        };
+        triangles.push([
+            remaining[(i + m - 1) % m],
+            remaining[i],
+            remaining[(i + 1) % m],
+        ]);
+        remaining.remove(i);
     }
 
-    let mesh_pointer = Arc::new(mesh);
-    let hittables = triangles
-        .into_iter()
-        .map(|triangle| {
-            Box::new(MeshTriangle {
-                triangle,
-                mesh: mesh_pointer.clone(),
-            }) as Box<dyn Hittable>
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+
+    triangles
+}
+
+/// Generates a normal for every corner that doesn't have one, by
+/// averaging the (area weighted) normals of the faces sharing the
+/// vertex that differ by less than `crease_angle` degrees from the
+/// corner's face. The normals get appended to `normals`.
+fn smooth_normals(
+    vertices: &[Point3],
+    normals: &mut Vec<Vec3>,
+    faces: &mut [[Corner; 3]],
+    crease_angle: f32,
+) {
+    let face_normals: Vec<Vec3> = faces
+        .iter()
+        .map(|face| {
+            let v0 = &vertices[face[0].vertex];
+            let v1 = &vertices[face[1].vertex];
+            let v2 = &vertices[face[2].vertex];
+            (v1 - v0).cross(&(v2 - v0))
         })
         .collect();
-    Ok(hittables)
+
+    // Faces around every vertex that needs a normal
+    let mut adjacent: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (f, face) in faces.iter().enumerate() {
+        for corner in face.iter().filter(|c| c.normal.is_none()) {
+            let faces = adjacent.entry(corner.vertex).or_default();
+            if faces.last() != Some(&f) {
+                faces.push(f);
+            }
+        }
+    }
+
+    // Corners averaging over the same faces get the same normal, so
+    // they can still share a vertex
+    let cos_crease = crease_angle.to_radians().cos();
+    let mut generated = HashMap::new();
+    for f in 0..faces.len() {
+        let face_normal = &face_normals[f];
+        for corner in faces[f].iter_mut() {
+            if corner.normal.is_some() {
+                continue;
+            }
+
+            let smooth: Vec<usize> = adjacent[&corner.vertex]
+                .iter()
+                .copied()
+                .filter(|&g| {
+                    let other = &face_normals[g];
+                    let len = face_normal.length() * other.length();
+                    g == f
+                        || len > 0.
+                            && face_normal.dot(other) >= cos_crease * len
+                })
+                .collect();
+
+            let index = *generated
+                .entry((corner.vertex, smooth))
+                .or_insert_with_key(|(_, smooth)| {
+                    let mut normal = Vec3::new();
+                    for &g in smooth {
+                        normal += &face_normals[g];
+                    }
+                    normals.push(normal.unit_vector());
+                    normals.len() - 1
+                });
+            corner.normal = Some(index);
+        }
+    }
+}
+
+fn compute_tangents(
+    texcoords: &[Vec3],
+    triangles: &[Triangle],
+) -> Tangents {
+    let num_vertices = texcoords.len();
+    let mut num_tangents = vec![0; num_vertices];
+    let mut tangents = vec![Vec3::new(); num_vertices];
+    let mut bitangents = vec![Vec3::new(); num_vertices];
+
+    for triangle in triangles {
+        let v0i = triangle.vertices[0] as usize;
+        let v1i = triangle.vertices[1] as usize;
+        let v2i = triangle.vertices[2] as usize;
+
+        let uv0 = &texcoords[v0i];
+        let uv1 = &texcoords[v1i];
+        let uv2 = &texcoords[v2i];
+
+        let delta_pos1 = &triangle.hit_edge1;
+        let delta_pos2 = &triangle.hit_edge2;
+
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+
+        let r = 1.
+            / (delta_uv1.x * delta_uv2.y
+                - delta_uv1.y * delta_uv2.x);
+        let tangent = r
+            * (delta_pos1 * delta_uv2.y
+                - delta_pos2 * delta_uv1.y);
+        let bitangent = r
+            * (delta_pos2 * delta_uv1.x
+                - delta_pos1 * delta_uv2.x);
+
+        // TODO: this might be REALLY sussy
+        //let n0 = &mesh.normals[v0i];
+        //let n1 = &mesh.normals[v1i];
+        //let n2 = &mesh.normals[v2i];
+
+        //tangents[v0i] += &tangent - n0 * n0.dot(&tangent);
+        //tangents[v1i] += &tangent - n1 * n1.dot(&tangent);
+        //tangents[v2i] += &tangent - n2 * n2.dot(&tangent);
+
+        //bitangents[v0i] += &bitangent - n0 * n0.dot(&bitangent);
+        //bitangents[v1i] += &bitangent - n1 * n1.dot(&bitangent);
+        //bitangents[v2i] += &bitangent - n2 * n2.dot(&bitangent);
+
+        tangents[v0i] += &tangent;
+        tangents[v1i] += &tangent;
+        tangents[v2i] += &tangent;
+
+        bitangents[v0i] += &bitangent;
+        bitangents[v1i] += &bitangent;
+        bitangents[v2i] += &bitangent;
+
+        num_tangents[v0i] += 1;
+        num_tangents[v1i] += 1;
+        num_tangents[v2i] += 1;
+    }
+
+    // Average the tangents for all vertices
+    let it = num_tangents
+        .into_iter()
+        .zip(tangents.iter_mut().zip(bitangents.iter_mut()));
+    for (n, (t, b)) in it {
+        if n == 0 {
+            continue;
+        }
+
+        let factor = 1. / n as f32;
+        *t *= factor;
+        *b *= factor;
+    }
+
+    Tangents {
+        tangents,
+        bitangents,
+    }
 }
@@ -0,0 +1,361 @@
+use std::{
+    path::Path,
+    error::Error,
+    fs::File,
+    io::Read,
+    str::SplitWhitespace,
+};
+use crate::math::{Color, Point3, Vec3};
+use super::objparser::{Corner, MeshData};
+
+#[derive(Clone, Copy)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum PropertyType {
+    Scalar(Scalar),
+    List {
+        count: Scalar,
+        item: Scalar,
+    },
+}
+
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+struct Header<'a> {
+    format: Format,
+    elements: Vec<Element>,
+    body: &'a [u8],
+}
+
+impl Scalar {
+    fn parse(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        use Scalar::*;
+
+        Ok(match s {
+            "char" | "int8" => I8,
+            "uchar" | "uint8" => U8,
+            "short" | "int16" => I16,
+            "ushort" | "uint16" => U16,
+            "int" | "int32" => I32,
+            "uint" | "uint32" => U32,
+            "float" | "float32" => F32,
+            "double" | "float64" => F64,
+            _ => return Err(format!("Unknown ply type {}", s).into()),
+        })
+    }
+
+    fn size(&self) -> usize {
+        use Scalar::*;
+
+        match self {
+            I8 | U8 => 1,
+            I16 | U16 => 2,
+            I32 | U32 | F32 => 4,
+            F64 => 8,
+        }
+    }
+
+    /// The value of full intensity for colors of this type
+    fn color_scale(&self) -> f64 {
+        use Scalar::*;
+
+        match self {
+            U8 | I8 => 255.,
+            U16 | I16 => 65535.,
+            _ => 1.,
+        }
+    }
+}
+
+/// Reads the values of the body one by one, whatever the format
+struct Body<'a> {
+    format: Format,
+    data: &'a [u8],
+    tokens: SplitWhitespace<'a>,
+}
+
+impl<'a> Body<'a> {
+    fn new(
+        format: Format,
+        data: &'a [u8],
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let text = match format {
+            Format::Ascii => std::str::from_utf8(data)?,
+            _ => "",
+        };
+
+        Ok(Self {
+            format,
+            data,
+            tokens: text.split_whitespace(),
+        })
+    }
+
+    fn read(
+        &mut self,
+        ty: Scalar,
+    ) -> Result<f64, Box<dyn Error + Send + Sync>> {
+        use Scalar::*;
+
+        if let Format::Ascii = self.format {
+            let token = self.tokens.next().ok_or("Truncated ply file")?;
+            return Ok(token.parse()?);
+        }
+
+        let size = ty.size();
+        if self.data.len() < size {
+            return Err("Truncated ply file".into());
+        }
+        let (bytes, rest) = self.data.split_at(size);
+        self.data = rest;
+
+        // Bring the bytes into little endian order
+        let mut buf = [0u8; 8];
+        buf[..size].copy_from_slice(bytes);
+        if let Format::BinaryBigEndian = self.format {
+            buf[..size].reverse();
+        }
+
+        Ok(match ty {
+            I8 => buf[0] as i8 as f64,
+            U8 => buf[0] as f64,
+            I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            I32 => i32::from_le_bytes(buf[..4].try_into()?) as f64,
+            U32 => u32::from_le_bytes(buf[..4].try_into()?) as f64,
+            F32 => f32::from_le_bytes(buf[..4].try_into()?) as f64,
+            F64 => f64::from_le_bytes(buf),
+        })
+    }
+}
+
+/// Splits the file into the elements of the header and the body
+fn parse_header(
+    data: &[u8],
+) -> Result<Header<'_>, Box<dyn Error + Send + Sync>> {
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    let mut rest = data;
+
+    loop {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or("Ply header has no end")?;
+        let line = std::str::from_utf8(&rest[..end])?.trim();
+        rest = &rest[end + 1..];
+
+        let mut iter = line.split_whitespace();
+        match iter.next() {
+            Some("ply") | Some("comment") | Some("obj_info") | None => {}
+            Some("format") => {
+                format = Some(match iter.next() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => {
+                        Format::BinaryLittleEndian
+                    }
+                    Some("binary_big_endian") => Format::BinaryBigEndian,
+                    _ => return Err("Unknown ply format".into()),
+                });
+            }
+            Some("element") => {
+                let name = iter.next().ok_or("Ply element without name")?;
+                let count = iter.next().ok_or("Ply element without count")?;
+                elements.push(Element {
+                    name: name.to_owned(),
+                    count: count.parse()?,
+                    properties: vec![],
+                });
+            }
+            Some("property") => {
+                let element = elements
+                    .last_mut()
+                    .ok_or("Ply property outside of an element")?;
+                let ty = iter.next().ok_or("Ply property without type")?;
+                let ty = if ty == "list" {
+                    let count = iter.next().ok_or("Ply list without type")?;
+                    let item = iter.next().ok_or("Ply list without type")?;
+                    PropertyType::List {
+                        count: Scalar::parse(count)?,
+                        item: Scalar::parse(item)?,
+                    }
+                } else {
+                    PropertyType::Scalar(Scalar::parse(ty)?)
+                };
+                let name = iter.next().ok_or("Ply property without name")?;
+                element.properties.push(Property {
+                    name: name.to_owned(),
+                    ty,
+                });
+            }
+            Some("end_header") => break,
+            Some(keyword) => {
+                return Err(format!("Unknown ply keyword {}", keyword).into())
+            }
+        }
+    }
+
+    let format = format.ok_or("Ply file without format")?;
+    Ok(Header {
+        format,
+        elements,
+        body: rest,
+    })
+}
+
+/// Parses an ascii or binary ply file. Vertices may have normals
+/// (nx ny nz), texture coords (u v, s t or texture_u texture_v), a
+/// second set of them (the same names followed by 1) and colors (red
+/// green blue), faces get triangulated.
+pub fn parse_ply(
+    filepath: &Path,
+) -> Result<MeshData, Box<dyn Error + Send + Sync>> {
+    let mut data = vec![];
+    File::open(filepath)?.read_to_end(&mut data)?;
+    if !data.starts_with(b"ply") {
+        return Err("Not a ply file".into());
+    }
+
+    let header = parse_header(&data)?;
+    let mut body = Body::new(header.format, header.body)?;
+    let mut mesh = MeshData::default();
+
+    for element in &header.elements {
+        let is_vertex = element.name == "vertex";
+        let is_face = element.name == "face";
+        let has = |names: &[&str]| {
+            element.properties.iter().any(|p| names.contains(&&*p.name))
+        };
+        let has_normals = has(&["nx"]);
+        let has_texcoords = has(&["u", "s", "texture_u"]);
+        let has_second_texcoords = has(&["u1", "s1", "texture_u1"]);
+        let has_colors = has(&["red", "diffuse_red"]);
+
+        // Colors stored as integers range over the whole type
+        let color_scale = element
+            .properties
+            .iter()
+            .find(|p| p.name == "red" || p.name == "diffuse_red")
+            .map_or(1., |p| match p.ty {
+                PropertyType::Scalar(ty) => ty.color_scale(),
+                PropertyType::List {
+                    ..
+                } => 1.,
+            }) as f32;
+
+        for _ in 0..element.count {
+            let mut position = Point3::new();
+            let mut normal = Vec3::new();
+            let mut texcoord = Vec3::new();
+            let mut second_texcoord = Vec3::new();
+            let mut color = Color::new();
+            let mut indices = vec![];
+
+            // Everything is read, even the properties we don't need,
+            // to get to the next element in binary files
+            for property in &element.properties {
+                let value = match property.ty {
+                    PropertyType::Scalar(ty) => body.read(ty)?,
+                    PropertyType::List {
+                        count,
+                        item,
+                    } => {
+                        let count = body.read(count)? as usize;
+                        let is_indices = property.name == "vertex_indices"
+                            || property.name == "vertex_index";
+                        for _ in 0..count {
+                            let value = body.read(item)?;
+                            if is_indices {
+                                indices.push(value);
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                let value = value as f32;
+                match property.name.as_str() {
+                    "x" => position.x = value,
+                    "y" => position.y = value,
+                    "z" => position.z = value,
+                    "nx" => normal.x = value,
+                    "ny" => normal.y = value,
+                    "nz" => normal.z = value,
+                    "u" | "s" | "texture_u" => texcoord.x = value,
+                    "v" | "t" | "texture_v" => texcoord.y = value,
+                    "u1" | "s1" | "texture_u1" => second_texcoord.x = value,
+                    "v1" | "t1" | "texture_v1" => second_texcoord.y = value,
+                    "red" | "diffuse_red" => color.x = value,
+                    "green" | "diffuse_green" => color.y = value,
+                    "blue" | "diffuse_blue" => color.z = value,
+                    _ => {}
+                }
+            }
+
+            if is_vertex {
+                mesh.vertices.push(position);
+                if has_normals {
+                    mesh.normals.push(normal);
+                }
+                if has_texcoords {
+                    mesh.texcoords.push(texcoord);
+                }
+                if has_second_texcoords {
+                    mesh.second_texcoords.push(second_texcoord);
+                }
+                if has_colors {
+                    mesh.colors.push(color / color_scale);
+                }
+            } else if is_face {
+                // Normals and texture coords belong to the vertices
+                let has_normals = !mesh.normals.is_empty();
+                let has_texcoords = !mesh.texcoords.is_empty();
+                let has_second_texcoords = !mesh.second_texcoords.is_empty();
+                let corners = indices
+                    .into_iter()
+                    .map(|i| {
+                        let i = i as usize;
+                        if i >= mesh.vertices.len() {
+                            return Err("Ply vertex index out of range");
+                        }
+                        Ok(Corner {
+                            vertex: i,
+                            texcoord: has_texcoords.then_some(i),
+                            second_texcoord: has_second_texcoords
+                                .then_some(i),
+                            normal: has_normals.then_some(i),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                mesh.add_polygon(&corners, None);
+            }
+        }
+    }
+
+    Ok(mesh)
+}
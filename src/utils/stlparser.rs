@@ -0,0 +1,118 @@
+use std::{
+    path::Path,
+    error::Error,
+    fs::File,
+    io::Read,
+    collections::HashMap,
+};
+use crate::math::Point3;
+use super::objparser::{Corner, MeshData};
+
+// Header, triangle count and 50 bytes per triangle
+const BINARY_HEADER_SIZE: usize = 84;
+const BINARY_TRIANGLE_SIZE: usize = 50;
+
+/// Parses an ascii or binary stl file. Stl stores every triangle with
+/// its own copy of the vertices, so equal positions get merged to be
+/// able to smooth the normals across triangles. The facet normals are
+/// ignored, they are often missing or wrong.
+pub fn parse_stl(
+    filepath: &Path,
+) -> Result<MeshData, Box<dyn Error + Send + Sync>> {
+    let mut data = vec![];
+    File::open(filepath)?.read_to_end(&mut data)?;
+
+    // Binary files may start with "solid" as well, but their size
+    // always matches the triangle count
+    let is_binary = data.len() >= BINARY_HEADER_SIZE && {
+        let count = u32::from_le_bytes(data[80..84].try_into()?) as usize;
+        data.len() == BINARY_HEADER_SIZE + count * BINARY_TRIANGLE_SIZE
+    };
+
+    let positions = if is_binary {
+        parse_binary(&data)?
+    } else if data.starts_with(b"solid") {
+        parse_ascii(std::str::from_utf8(&data)?)?
+    } else {
+        return Err("Not a stl file".into());
+    };
+
+    let mut mesh = MeshData::default();
+    let mut indices = HashMap::new();
+    let mut corners = vec![];
+    for position in positions {
+        let key = [
+            position.x.to_bits(),
+            position.y.to_bits(),
+            position.z.to_bits(),
+        ];
+        let vertex = *indices.entry(key).or_insert_with(|| {
+            mesh.vertices.push(position);
+            mesh.vertices.len() - 1
+        });
+
+        corners.push(Corner {
+            vertex,
+            texcoord: None,
+            second_texcoord: None,
+            normal: None,
+        });
+        if corners.len() == 3 {
+            mesh.add_polygon(&corners, None);
+            corners.clear();
+        }
+    }
+
+    Ok(mesh)
+}
+
+/// Returns the corners of all triangles, three in a row per triangle
+fn parse_binary(
+    data: &[u8],
+) -> Result<Vec<Point3>, Box<dyn Error + Send + Sync>> {
+    let mut positions = vec![];
+    for triangle in data[BINARY_HEADER_SIZE..].chunks(BINARY_TRIANGLE_SIZE) {
+        // Skip the normal, the corners follow
+        for corner in triangle[12..48].chunks(12) {
+            let x = f32::from_le_bytes(corner[0..4].try_into()?);
+            let y = f32::from_le_bytes(corner[4..8].try_into()?);
+            let z = f32::from_le_bytes(corner[8..12].try_into()?);
+            positions.push(Point3::from_values(x, y, z));
+        }
+    }
+
+    Ok(positions)
+}
+
+/// Returns the corners of all triangles, three in a row per triangle
+fn parse_ascii(
+    text: &str,
+) -> Result<Vec<Point3>, Box<dyn Error + Send + Sync>> {
+    let mut positions = vec![];
+    let mut corners = 0;
+    for line in text.lines() {
+        let mut iter = line.split_whitespace();
+        match iter.next() {
+            Some("vertex") => {
+                let coords = iter
+                    .map(|s| s.parse())
+                    .collect::<Result<Vec<f32>, _>>()?;
+                if coords.len() != 3 {
+                    return Err("Stl vertex without three coords".into());
+                }
+                let (x, y, z) = (coords[0], coords[1], coords[2]);
+                positions.push(Point3::from_values(x, y, z));
+                corners += 1;
+            }
+            Some("endfacet") => {
+                if corners != 3 {
+                    return Err("Stl facet without three vertices".into());
+                }
+                corners = 0;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(positions)
+}